@@ -0,0 +1,200 @@
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use remitslib::commands::{
+    Command, IngestionMode, IteratorAdd, IteratorDelete, IteratorKind, IteratorNext, LogAdd,
+    LogOptions, MessageAdd,
+};
+use remitslib::db::iters::{CompiledItr, Itr, LuaPool};
+use remitslib::db::DB;
+use remitslib::errors::Error;
+use remitslib::protocol::Response;
+use serde_cbor::Value as CborValue;
+use std::collections::BTreeMap;
+use storage::{MemoryStorage, Metadata, StorageEngine};
+
+const FUNC: &str = "return msg.n * 2";
+const MSGS: usize = 1000;
+const PAGE: usize = 100;
+
+/// A CBOR encoded map, the way clients send messages.
+fn msg(n: usize) -> CborValue {
+    let mut map = BTreeMap::new();
+    map.insert(CborValue::Text("n".into()), CborValue::Integer(n as i128));
+    map.insert(
+        CborValue::Text("name".into()),
+        CborValue::Text(format!("msg-{}", n)),
+    );
+    CborValue::Bytes(serde_cbor::to_vec(&CborValue::Map(map)).unwrap())
+}
+
+fn add_itr(db: &DB) {
+    db.exec(Command::IteratorAdd(IteratorAdd {
+        log_name: "bench".into(),
+        iterator_name: "itr".into(),
+        iterator_kind: IteratorKind::Map,
+        iterator_func: FUNC.into(),
+    }));
+}
+
+fn del_itr(db: &DB) {
+    db.exec(Command::IteratorDelete(IteratorDelete {
+        log_name: "bench".into(),
+        iterator_name: "itr".into(),
+    }));
+}
+
+fn next_page(db: &DB) {
+    let resp = db.exec(Command::IteratorNext(IteratorNext {
+        iterator_name: "itr".into(),
+        message_id: 0,
+        count: PAGE,
+        start_time: None,
+        end_time: None,
+        with_next_offset: false,
+    }));
+    match resp {
+        Response::Data(msgs) => assert_eq!(msgs.len(), PAGE),
+        _ => panic!("expected itr_next to return data"),
+    }
+}
+
+/// A DB with a Log of MSGS messages and a map Iterator over it.
+fn setup(dir: &tempfile::TempDir) -> DB {
+    let db = DB::new(dir.path().to_string_lossy().into_owned());
+    db.exec(Command::LogAdd(LogAdd {
+        log_name: "bench".into(),
+        options: LogOptions::default(),
+    }));
+    for n in 0..MSGS {
+        let resp = db.exec(Command::MessageAdd(MessageAdd {
+            log_name: "bench".into(),
+            message: msg(n),
+            key: None,
+            headers: BTreeMap::new(),
+            producer_id: None,
+            sequence: None,
+        }));
+        assert!(
+            matches!(resp, Response::Info(_)),
+            "could not add msg: {:?}",
+            resp
+        );
+    }
+    add_itr(&db);
+    db
+}
+
+fn bench_itr_next(c: &mut Criterion) {
+    let dir = tempfile::tempdir().unwrap();
+    let db = setup(&dir);
+
+    let mut group = c.benchmark_group("itr_next");
+    // Every call after the first reuses the Iterator's compiled function.
+    group.bench_function("cached", |b| b.iter(|| next_page(&db)));
+    // Replacing the Iterator drops its Lua states, so every call compiles it again.
+    group.bench_function("recompiled", |b| {
+        b.iter_batched(
+            || {
+                del_itr(&db);
+                add_itr(&db);
+            },
+            |_| next_page(&db),
+            BatchSize::PerIteration,
+        )
+    });
+    group.finish();
+}
+
+/// A Map Iterator over a Log of MSGS messages held in memory, so that only running the function is
+/// measured.
+fn setup_storage() -> (MemoryStorage, Itr) {
+    let mut storage = MemoryStorage::open("").unwrap();
+    storage.create_log("bench").unwrap();
+    for n in 0..MSGS {
+        let body = match msg(n) {
+            CborValue::Bytes(body) => body,
+            _ => unreachable!(),
+        };
+        storage.append("bench", body, Metadata::default()).unwrap();
+    }
+
+    let itr = Itr {
+        log: "bench".into(),
+        name: "itr".into(),
+        func: FUNC.into(),
+        kind: IteratorKind::Map,
+    };
+    (storage, itr)
+}
+
+fn next_page_with(itr: &Itr, compiled: &CompiledItr, storage: &MemoryStorage) -> Result<(), Error> {
+    let (msgs, _) = itr.next(compiled, storage, IngestionMode::Cbor, 0, None, PAGE)?;
+    assert_eq!(msgs.len(), PAGE);
+    Ok(())
+}
+
+/// Compares taking a state from a warm LuaPool for each request against compiling the function
+/// into a new state for each one.
+fn bench_lua_pool(c: &mut Criterion) {
+    let (storage, itr) = setup_storage();
+
+    let mut group = c.benchmark_group("lua_pool");
+    let pool = LuaPool::default();
+    group.bench_function("warm_pool", |b| {
+        b.iter(|| {
+            pool.with(&itr, |compiled| next_page_with(&itr, compiled, &storage))
+                .unwrap()
+        })
+    });
+    group.bench_function("compiled_per_request", |b| {
+        b.iter(|| {
+            let compiled = CompiledItr::new(&itr.func).unwrap();
+            next_page_with(&itr, &compiled, &storage).unwrap()
+        })
+    });
+    group.finish();
+}
+
+/// Compares running a function over a page of messages the way Iterators used to, with a new Lua
+/// state and the source loaded again for every message, against calling a compiled function.
+fn bench_lua(c: &mut Criterion) {
+    let msgs: Vec<i64> = (0..PAGE as i64).collect();
+
+    let mut group = c.benchmark_group("lua");
+    group.bench_function("reload_per_message", |b| {
+        b.iter(|| {
+            let lua = rlua::Lua::new();
+            lua.context(|ctx| {
+                let globals = ctx.globals();
+                for n in msgs.iter() {
+                    let msg = ctx.create_table_from(vec![("n", *n)]).unwrap();
+                    globals.set("msg", msg).unwrap();
+                    ctx.load(FUNC).eval::<i64>().unwrap();
+                }
+            })
+        })
+    });
+
+    let lua = rlua::Lua::new();
+    let func = lua.context(|ctx| {
+        let func = ctx
+            .load(&format!("return function(msg) {} end", FUNC))
+            .eval::<rlua::Function>()
+            .unwrap();
+        ctx.create_registry_value(func).unwrap()
+    });
+    group.bench_function("compiled", |b| {
+        b.iter(|| {
+            lua.context(|ctx| {
+                let func: rlua::Function = ctx.registry_value(&func).unwrap();
+                for n in msgs.iter() {
+                    let msg = ctx.create_table_from(vec![("n", *n)]).unwrap();
+                    func.call::<_, i64>(msg).unwrap();
+                }
+            })
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_itr_next, bench_lua_pool, bench_lua);
+criterion_main!(benches);
@@ -40,5 +40,5 @@ impl Default for Config {
 #[derive(Clone, Debug)]
 pub enum Error {
     //NoConfigFoundAtPath,
-//MalformedConfig,
+    //MalformedConfig,
 }
@@ -8,8 +8,10 @@ use config::Config;
 use protocol::Body;
 
 use env_logger::{Builder, Target};
+use remitslib::commands::{IngestionMode, LogOptions};
+use remitslib::db::SegmentEngine;
 use serde_json::Deserializer;
-use storage::Storage;
+use storage::{Metadata, StorageEngine};
 
 use std::net::{TcpListener, TcpStream};
 use std::sync::{Arc, Mutex};
@@ -31,13 +33,19 @@ fn main() {
         Err(e) => panic!("could not load config: {:?}", e),
     };
 
-    setup_logger(&*cfg.log_level);
+    setup_logger(&cfg.log_level);
 
-    info!("opening storage");
-    let storage = match Storage::open("TODO: Path does not work yet") {
-        Ok(s) => s,
-        Err(e) => return error!("could not open storage: {:?}", e),
+    info!("opening storage at {}", cfg.db_path);
+    // Message bodies are strings rather than CBOR, so Logs take them as raw bytes.
+    let defaults = LogOptions {
+        ingestion: Some(IngestionMode::Raw),
+        ..LogOptions::default()
     };
+    let storage: Box<dyn StorageEngine + Send> =
+        match SegmentEngine::open(cfg.db_path.clone().into(), defaults) {
+            Ok(s) => Box::new(s),
+            Err(e) => return error!("could not open storage: {:?}", e),
+        };
 
     // TODO: We're using a global mutex now for convenience during initial development.
     // There's no way we actually want a global mutex, but finding the correct latch granularity is
@@ -52,10 +60,7 @@ fn main() {
 
     info!("listening");
 
-    let mut connection_counter: u64 = 0;
-    for stream in listener.incoming() {
-        connection_counter += 1;
-        let conn_id = *(&connection_counter); // hack to force copy of id
+    for (conn_id, stream) in (1u64..).zip(listener.incoming()) {
         let storage = storage.clone();
 
         match stream {
@@ -71,13 +76,20 @@ fn main() {
     }
 }
 
-fn handle_stream(conn_id: u64, _storage: Arc<Mutex<Storage>>, conn: TcpStream) {
+fn handle_stream(
+    conn_id: u64,
+    storage: Arc<Mutex<Box<dyn StorageEngine + Send>>>,
+    conn: TcpStream,
+) {
     info!("accepted stream {}", conn_id);
 
     let stream = Deserializer::from_reader(conn).into_iter::<Body>();
     for result in stream {
         match result {
-            Ok(body) => info!("recvd body on conn_id {}: {:?}", conn_id, body),
+            Ok(body) => {
+                info!("recvd body on conn_id {}: {:?}", conn_id, body);
+                handle_body(conn_id, &storage, body);
+            }
             Err(e) => {
                 error!("recvd malformed body on conn_id {}: {:?}", conn_id, e);
                 return;
@@ -87,3 +99,17 @@ fn handle_stream(conn_id: u64, _storage: Arc<Mutex<Storage>>, conn: TcpStream) {
 
     info!("closing stream {}", conn_id);
 }
+
+fn handle_body(conn_id: u64, storage: &Mutex<Box<dyn StorageEngine + Send>>, body: Body) {
+    let mut storage = storage.lock().expect("unwrapped poisoned storage lock");
+    let res = match body {
+        Body::Ping(_) => return,
+        Body::LogAdd(b) => storage.create_log(&b.name),
+        Body::MessageAdd(b) => storage
+            .append(&b.log, b.body.into_bytes(), Metadata::default())
+            .map(|_| ()),
+    };
+    if let Err(e) = res {
+        error!("could not handle body on conn_id {}: {:?}", conn_id, e);
+    }
+}
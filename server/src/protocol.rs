@@ -32,5 +32,7 @@ pub struct LogAddBody {
 #[derive(Serialize, Deserialize, Debug)]
 pub struct MessageAddBody {
     pub id: u64,
+    /// Name of the Log to add the message to.
+    pub log: String,
     pub body: String,
 }
@@ -1,5 +1,7 @@
 use clap::clap_app;
 use serde::{Deserialize, Serialize};
+mod export;
+mod import;
 mod protocol;
 
 fn main() {
@@ -10,6 +12,13 @@ fn main() {
         (@subcommand log_add =>
             (about: "Add log")
             (@arg log_name: -n +required +takes_value "Log name to add")
+            (@arg options: -o +takes_value "Log options as a JSON object")
+        )
+        (@subcommand log_alter =>
+            (about: "Change the options of a log")
+            (@arg log_name: -n +required +takes_value "Log name to change")
+            (@arg options: -o +takes_value "Log options to change as a JSON object")
+            (@arg reset: -r +takes_value +multiple number_of_values(1) "Log option to unset, falling back to the server default")
         )
         (@subcommand log_list =>
             (about: "List logs")
@@ -17,6 +26,11 @@ fn main() {
         (@subcommand log_del =>
             (about: "Delete log")
             (@arg log_name: -n +required +takes_value "Log name to delete")
+            (@arg grace_period: -g +takes_value "seconds the log can be undeleted for")
+        )
+        (@subcommand log_undel =>
+            (about: "Undelete a log that is still in its grace period")
+            (@arg log_name: -n +required +takes_value "Log name to undelete")
         )
         (@subcommand log_show =>
             (about: "Show metadata of log")
@@ -24,7 +38,40 @@ fn main() {
         )
         (@subcommand msg_add =>
             (about: "Add message to log")
+            (@arg log_name: -n +required +takes_value "Log name to add the message to")
             (@arg msg: -m +takes_value "Value of msg to add")
+            (@arg key: -k +takes_value "Key to store with the message")
+            (@arg header: -H +takes_value +multiple number_of_values(1) "Header to store with the message, as name=value")
+            (@arg producer_id: -p +takes_value requires[sequence] "Id of the producer adding the message, so retries are only added once")
+            (@arg sequence: -s +takes_value requires[producer_id] "Sequence number of the message, higher than the producer's last")
+        )
+        (@subcommand msg_read =>
+            (about: "Get up to <count> messages of a log as they were added")
+            (@arg log_name: -n +required +takes_value "Log name to read from")
+            (@arg message_id: -i +required +takes_value "message_id")
+            (@arg count: -c +required +takes_value "count")
+            (@arg start_time: -s +takes_value "start at messages ingested at or after this unix time in ms")
+            (@arg end_time: -e +takes_value "stop before messages ingested at or after this unix time in ms")
+        )
+        (@subcommand import =>
+            (about: "Add every message in a JSON Lines or CBOR sequence file to a log")
+            (@arg log_name: -n +required +takes_value "Log name to import into")
+            (@arg file: -f +required +takes_value "file to import")
+            (@arg format: --format +takes_value "jsonl (default) or cbor")
+            (@arg time_field: -t +takes_value "field holding each message's original unix time in ms")
+            (@arg batch_size: -b +takes_value "messages to send per request")
+        )
+        (@subcommand export =>
+            (about: "Write the messages of a log or the output of an iterator to a file")
+            (@arg log_name: -l +takes_value conflicts_with[iterator_name] "Log to export")
+            (@arg iterator_name: -n +takes_value required_unless[log_name] "Iterator to export the output of")
+            (@arg file: -f +required +takes_value "file to export to")
+            (@arg format: --format +takes_value "jsonl (default), cbor or csv")
+            (@arg start: -s +takes_value "first offset to export")
+            (@arg end: -e +takes_value "offset to stop before")
+            (@arg start_time: --start_time +takes_value "start at messages ingested at or after this unix time in ms")
+            (@arg end_time: --end_time +takes_value "stop before messages ingested at or after this unix time in ms")
+            (@arg page_size: -c +takes_value "messages to ask for per request")
         )
         (@subcommand iterator_add =>
             (about: "Add iterator to log")
@@ -35,20 +82,44 @@ fn main() {
         (@subcommand iterator_list =>
             (about: "List all iterators")
         )
+        (@subcommand backup =>
+            (about: "Back up the db to a directory on the server")
+            (@arg path: -p +required +takes_value "empty directory on the server to back up to")
+        )
         (@subcommand iterator_next =>
             (about: "Get up to <count> messages from an Iterator")
             (@arg iterator_name: -n +required +takes_value "iterator name")
             (@arg message_id: -i +required +takes_value "message_id")
             (@arg count: -c +required +takes_value "count")
+            (@arg start_time: -s +takes_value "start at messages ingested at or after this unix time in ms")
+            (@arg end_time: -e +takes_value "stop before messages ingested at or after this unix time in ms")
         )
     )
     .get_matches();
 
     let request = match matches.subcommand() {
         ("log_list", Some(_)) => protocol::new_log_list_req(),
-        ("log_add", Some(args)) => protocol::new_log_add_req(args.value_of("log_name").unwrap()),
+        ("log_add", Some(args)) => protocol::new_log_add_req(
+            args.value_of("log_name").unwrap(),
+            args.value_of("options").map(parse_options),
+        ),
+        ("log_alter", Some(args)) => protocol::new_log_alter_req(
+            args.value_of("log_name").unwrap(),
+            args.value_of("options").map(parse_options),
+            args.values_of("reset")
+                .into_iter()
+                .flatten()
+                .map(String::from)
+                .collect(),
+        ),
         ("log_show", Some(args)) => protocol::new_log_show_req(args.value_of("log_name").unwrap()),
-        ("log_del", Some(args)) => protocol::new_log_del_req(args.value_of("log_name").unwrap()),
+        ("log_del", Some(args)) => {
+            let grace_period = args.value_of("grace_period").map(|g| g.parse().unwrap());
+            protocol::new_log_del_req(args.value_of("log_name").unwrap(), grace_period)
+        }
+        ("log_undel", Some(args)) => {
+            protocol::new_log_undel_req(args.value_of("log_name").unwrap())
+        }
         ("msg_add", Some(args)) => {
             #[derive(Debug, Eq, PartialEq, Serialize, Deserialize)]
             struct Msg {
@@ -59,7 +130,75 @@ fn main() {
             };
             let cbor = serde_cbor::to_vec(&test_msg).unwrap();
 
-            protocol::new_msg_add_req("test", cbor)
+            let headers = args
+                .values_of("header")
+                .into_iter()
+                .flatten()
+                .map(|h| match h.split_once('=') {
+                    Some((name, value)) => (name.to_string(), value.to_string()),
+                    None => fail(format!("header {:?} is not name=value", h)),
+                })
+                .collect();
+
+            protocol::new_msg_add_req(
+                args.value_of("log_name").unwrap(),
+                cbor,
+                args.value_of("key"),
+                headers,
+                args.value_of("producer_id")
+                    .map(|id| (id, args.value_of("sequence").unwrap().parse().unwrap())),
+            )
+        }
+        ("msg_read", Some(args)) => {
+            let log_name = args.value_of("log_name").unwrap();
+            let message_id = args.value_of("message_id").unwrap().parse().unwrap();
+            let count = args.value_of("count").unwrap().parse().unwrap();
+            let start_time = args.value_of("start_time").map(|t| t.parse().unwrap());
+            let end_time = args.value_of("end_time").map(|t| t.parse().unwrap());
+            protocol::new_msg_read_req(log_name, message_id, count, start_time, end_time)
+        }
+        ("import", Some(args)) => {
+            let format = args
+                .value_of("format")
+                .map_or(import::Format::Jsonl, |f| f.parse().unwrap());
+            let batch_size = args
+                .value_of("batch_size")
+                .map_or(import::DEFAULT_BATCH_SIZE, |b| b.parse().unwrap());
+            import::run(
+                args.value_of("log_name").unwrap(),
+                args.value_of("file").unwrap(),
+                format,
+                args.value_of("time_field"),
+                batch_size.max(1),
+            );
+            return;
+        }
+        ("export", Some(args)) => {
+            let source = match args.value_of("log_name") {
+                Some(log) => export::Source::Log(log.into()),
+                None => export::Source::Iterator(args.value_of("iterator_name").unwrap().into()),
+            };
+            let parse = |name| args.value_of(name).map(|v| v.parse().unwrap());
+            let range = export::Range {
+                start: parse("start"),
+                end: parse("end"),
+                start_time: parse("start_time"),
+                end_time: parse("end_time"),
+            };
+            let format = args
+                .value_of("format")
+                .map_or(export::Format::Jsonl, |f| f.parse().unwrap());
+            let page_size = args
+                .value_of("page_size")
+                .map_or(export::DEFAULT_PAGE_SIZE, |c| c.parse().unwrap());
+            export::run(
+                source,
+                range,
+                args.value_of("file").unwrap(),
+                format,
+                page_size.max(1),
+            );
+            return;
         }
         ("iterator_add", Some(args)) => {
             let log = args.value_of("log").unwrap();
@@ -72,8 +211,18 @@ fn main() {
             let iterator_name = args.value_of("iterator_name").unwrap();
             let message_id = args.value_of("message_id").unwrap().parse().unwrap();
             let count = args.value_of("count").unwrap().parse().unwrap();
-            protocol::new_iterator_next_req(iterator_name, message_id, count)
+            let start_time = args.value_of("start_time").map(|t| t.parse().unwrap());
+            let end_time = args.value_of("end_time").map(|t| t.parse().unwrap());
+            protocol::new_iterator_next_req(
+                iterator_name,
+                message_id,
+                count,
+                start_time,
+                end_time,
+                false,
+            )
         }
+        ("backup", Some(args)) => protocol::new_backup_req(args.value_of("path").unwrap()),
         _ => panic!("{}", "Type help, -h, or --help"),
     };
     println!("request to remits {:?}", request);
@@ -94,3 +243,13 @@ fn main() {
     }
     println!("Response from remits {:?}", output.2);
 }
+
+fn parse_options(json: &str) -> serde_cbor::Value {
+    remitslib::json::from_json(json)
+        .unwrap_or_else(|e| fail(format!("options are not valid JSON: {}", e)))
+}
+
+fn fail(msg: String) -> ! {
+    eprintln!("{}", msg);
+    std::process::exit(1);
+}
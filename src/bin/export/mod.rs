@@ -0,0 +1,361 @@
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Instant;
+
+use remitslib::json::{key_string, to_json};
+use serde::{Deserialize, Serialize};
+use serde_cbor::Value as CborValue;
+
+use crate::{fail, protocol};
+
+/// Number of messages asked for in each Message Read or Iterator Next request.
+pub const DEFAULT_PAGE_SIZE: usize = 1000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Format {
+    /// JSON Lines, one JSON value per line.
+    Jsonl,
+    /// RFC 8742 CBOR sequence, CBOR values one after another.
+    Cbor,
+    /// CSV with a column per field, where nested fields are flattened into dotted names.
+    Csv,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Format, String> {
+        match &*s.to_lowercase() {
+            "jsonl" => Ok(Format::Jsonl),
+            "cbor" => Ok(Format::Cbor),
+            "csv" => Ok(Format::Csv),
+            _ => Err(format!("unexpected format {:?}", s)),
+        }
+    }
+}
+
+/// Where the exported messages come from.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Source {
+    /// Every message of a Log, as it was added.
+    Log(String),
+    /// The output of a named Iterator.
+    Iterator(String),
+}
+
+/// Which messages to export. Offsets are of the source Log, and times are Unix timestamps in
+/// milliseconds compared against when each message was ingested.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Range {
+    pub start: Option<u64>,
+    pub end: Option<u64>,
+    pub start_time: Option<u64>,
+    pub end_time: Option<u64>,
+}
+
+/// Progress of an export, saved next to the output file after every page so that an interrupted
+/// export can pick up where it left off. It is removed once the export finishes.
+#[derive(Debug, Serialize, Deserialize)]
+struct State {
+    source: Source,
+    format: Format,
+    range: Range,
+    /// Offset of the source Log to ask for next.
+    next_offset: u64,
+    /// Length of the output file once the last page was written. Anything after it is from a page
+    /// that was interrupted, and is written again.
+    bytes_written: u64,
+    /// CSV columns, picked from the first page.
+    columns: Vec<String>,
+}
+
+/// Streams the messages of `source` in `range` into `out`, asking for `page_size` at a time. If
+/// an earlier export to `out` of the same messages was interrupted, it is resumed.
+pub fn run(source: Source, range: Range, out: &str, format: Format, page_size: usize) {
+    let out = PathBuf::from(out);
+    let state_path = state_path(&out);
+    let mut stream = protocol::connect_to_remits();
+
+    let mut state = match read_state(&state_path) {
+        Some(state) if state.source == source && state.format == format && state.range == range => {
+            println!("resuming export from offset {}", state.next_offset);
+            state
+        }
+        Some(_) => fail(format!(
+            "{:?} holds an unfinished export of different messages; remove it to start over",
+            state_path
+        )),
+        None if out.exists() => fail(format!("{:?} already exists", out)),
+        None => State {
+            next_offset: first_offset(&mut stream, &source, &range),
+            source,
+            format,
+            range,
+            bytes_written: 0,
+            columns: vec![],
+        },
+    };
+
+    let mut file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(false)
+        .open(&out)
+        .unwrap_or_else(|e| fail(format!("could not open {:?}: {}", out, e)));
+    file.set_len(state.bytes_written)
+        .and_then(|_| file.seek(SeekFrom::End(0)))
+        .unwrap_or_else(|e| fail(format!("could not write to {:?}: {}", out, e)));
+    let mut file = BufWriter::new(file);
+
+    let started = Instant::now();
+    let (mut exported, mut dropped_fields) = (0u64, 0u64);
+    loop {
+        let count = match state.range.end {
+            Some(end) => page_size.min(end.saturating_sub(state.next_offset) as usize),
+            None => page_size,
+        };
+        if count == 0 {
+            break;
+        }
+
+        let (msgs, next_offset) = next_page(&mut stream, &state, count);
+        if state.format == Format::Csv && state.columns.is_empty() && !msgs.is_empty() {
+            state.columns = columns(&msgs);
+            write_csv_row(&mut file, &state.columns);
+        }
+        for msg in msgs.iter() {
+            dropped_fields += write_msg(&mut file, state.format, &state.columns, msg);
+        }
+        exported += msgs.len() as u64;
+
+        // No progress means the end of the range or the Log was reached.
+        if next_offset == state.next_offset {
+            break;
+        }
+
+        // The page has to be on disk before the progress saying so is.
+        file.flush()
+            .and_then(|_| file.get_ref().sync_data())
+            .unwrap_or_else(|e| fail(format!("could not write to {:?}: {}", out, e)));
+        state.next_offset = next_offset;
+        state.bytes_written = file.get_ref().metadata().map(|m| m.len()).unwrap_or(0);
+        write_state(&state_path, &state);
+    }
+
+    file.flush()
+        .and_then(|_| file.get_ref().sync_all())
+        .unwrap_or_else(|e| fail(format!("could not write to {:?}: {}", out, e)));
+    let _ = std::fs::remove_file(&state_path);
+
+    let secs = started.elapsed().as_secs_f64();
+    println!(
+        "exported {} messages to {:?} in {:.2}s ({:.0} msgs/s)",
+        exported,
+        out,
+        secs,
+        exported as f64 / secs.max(0.001)
+    );
+    if dropped_fields > 0 {
+        eprintln!(
+            "dropped {} fields that weren't in the first page of messages, and so have no CSV \
+             column",
+            dropped_fields
+        );
+    }
+}
+
+fn state_path(out: &Path) -> PathBuf {
+    let mut path = out.as_os_str().to_owned();
+    path.push(".export");
+    PathBuf::from(path)
+}
+
+fn read_state(path: &Path) -> Option<State> {
+    let bytes = std::fs::read(path).ok()?;
+    match serde_cbor::from_slice(&bytes) {
+        Ok(state) => Some(state),
+        Err(e) => fail(format!(
+            "could not read export progress in {:?}: {}",
+            path, e
+        )),
+    }
+}
+
+fn write_state(path: &Path, state: &State) {
+    let tmp_path = path.with_extension("export.tmp");
+    let res = File::create(&tmp_path)
+        .and_then(|mut tmp| {
+            tmp.write_all(&serde_cbor::to_vec(state).unwrap())?;
+            tmp.sync_all()
+        })
+        .and_then(|_| std::fs::rename(&tmp_path, path));
+    if let Err(e) = res {
+        fail(format!(
+            "could not save export progress to {:?}: {}",
+            path, e
+        ));
+    }
+}
+
+/// The offset to start exporting from. Messages of a Log that were deleted by retention are
+/// skipped rather than failing the export.
+fn first_offset(stream: &mut TcpStream, source: &Source, range: &Range) -> u64 {
+    let start = range.start.unwrap_or(0);
+    let log = match source {
+        Source::Log(log) => log,
+        Source::Iterator(_) => return start,
+    };
+
+    let (kind, _, payload) = protocol::send_req_on(stream, protocol::new_log_show_req(log));
+    if kind == 0x03 {
+        fail(format!(
+            "could not show log {:?}: {}",
+            log,
+            protocol::error_name(&payload)
+        ));
+    }
+    let info: CborValue = protocol::read_data(&payload)
+        .first()
+        .and_then(|item| serde_cbor::from_slice(item).ok())
+        .unwrap_or_else(|| fail(format!("could not read info of log {:?}", log)));
+    match field(&info, "earliest_offset") {
+        Some(CborValue::Integer(earliest)) => start.max(earliest as u64),
+        _ => start,
+    }
+}
+
+/// Asks the source for the next page, returning its messages and the offset to resume from. The
+/// messages of a Log are read as they were added.
+fn next_page(stream: &mut TcpStream, state: &State, count: usize) -> (Vec<Vec<u8>>, u64) {
+    let (offset, range) = (state.next_offset as usize, &state.range);
+    let req = match &state.source {
+        Source::Log(log) => {
+            protocol::new_msg_read_req(log, offset, count, range.start_time, range.end_time)
+        }
+        Source::Iterator(name) => protocol::new_iterator_next_req(
+            name,
+            offset,
+            count,
+            range.start_time,
+            range.end_time,
+            true,
+        ),
+    };
+    let (kind, _, payload) = protocol::send_req_on(stream, req);
+    if kind == 0x03 {
+        let source = match &state.source {
+            Source::Log(log) => format!("log {:?}", log),
+            Source::Iterator(name) => format!("iterator {:?}", name),
+        };
+        fail(format!(
+            "could not read from {} at offset {}: {}",
+            source,
+            state.next_offset,
+            protocol::error_name(&payload)
+        ));
+    }
+
+    let mut msgs = protocol::read_data(&payload);
+    let next_offset = msgs
+        .pop()
+        .and_then(|offset| serde_cbor::from_slice(&offset).ok())
+        .unwrap_or_else(|| fail("server did not say where to resume from".into()));
+    (msgs, next_offset)
+}
+
+fn decode(msg: &[u8]) -> CborValue {
+    serde_cbor::from_slice(msg).unwrap_or_else(|_| CborValue::Bytes(msg.to_vec()))
+}
+
+/// Writes a message, returning how many of its fields were dropped for having no CSV column.
+fn write_msg(out: &mut impl Write, format: Format, columns: &[String], msg: &[u8]) -> u64 {
+    let res = match format {
+        Format::Cbor => out.write_all(msg),
+        Format::Jsonl => writeln!(out, "{}", to_json(&decode(msg))),
+        Format::Csv => {
+            let mut fields = BTreeMap::new();
+            flatten(&decode(msg), String::new(), &mut fields);
+            let row: Vec<String> = columns
+                .iter()
+                .map(|c| fields.remove(c).unwrap_or_default())
+                .collect();
+            write_csv_row(out, &row);
+            return fields.len() as u64;
+        }
+    };
+    res.unwrap_or_else(|e| fail(format!("could not write message: {}", e)));
+    0
+}
+
+/// The CSV columns for a page of messages: every flattened field found in any of them, in order.
+fn columns(msgs: &[Vec<u8>]) -> Vec<String> {
+    let mut columns = std::collections::BTreeSet::new();
+    for msg in msgs {
+        let mut fields = BTreeMap::new();
+        flatten(&decode(msg), String::new(), &mut fields);
+        columns.extend(fields.into_keys());
+    }
+    columns.into_iter().collect()
+}
+
+/// Flattens nested maps and arrays into one field per value, named by the path to it joined with
+/// dots, e.g. `user.emails.0`. A message that isn't a map or array is a single field, `value`.
+fn flatten(value: &CborValue, prefix: String, fields: &mut BTreeMap<String, String>) {
+    let join = |key: String| match prefix.is_empty() {
+        true => key,
+        false => format!("{}.{}", prefix, key),
+    };
+
+    match value {
+        CborValue::Map(map) => {
+            for (k, v) in map {
+                flatten(v, join(key_string(k)), fields);
+            }
+        }
+        CborValue::Array(values) => {
+            for (i, v) in values.iter().enumerate() {
+                flatten(v, join(i.to_string()), fields);
+            }
+        }
+        CborValue::Tag(_, value) => flatten(value, prefix, fields),
+        value => {
+            let name = if prefix.is_empty() {
+                "value".to_string()
+            } else {
+                prefix
+            };
+            let text = match value {
+                CborValue::Null => String::new(),
+                CborValue::Text(s) => s.clone(),
+                value => to_json(value).trim_matches('"').to_string(),
+            };
+            fields.insert(name, text);
+        }
+    }
+}
+
+fn write_csv_row(out: &mut impl Write, fields: &[String]) {
+    let row: Vec<String> = fields
+        .iter()
+        .map(|f| {
+            if f.contains(&[',', '"', '\n', '\r'][..]) {
+                format!("\"{}\"", f.replace('"', "\"\""))
+            } else {
+                f.clone()
+            }
+        })
+        .collect();
+    writeln!(out, "{}", row.join(","))
+        .unwrap_or_else(|e| fail(format!("could not write message: {}", e)));
+}
+
+fn field(map: &CborValue, name: &str) -> Option<CborValue> {
+    match map {
+        CborValue::Map(map) => map.get(&CborValue::Text(name.into())).cloned(),
+        _ => None,
+    }
+}
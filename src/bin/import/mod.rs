@@ -0,0 +1,202 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::str::FromStr;
+use std::time::Instant;
+
+use remitslib::json::from_json;
+use serde_cbor::Value as CborValue;
+
+use crate::{fail, protocol};
+
+/// Number of messages sent in each Message Import request.
+pub const DEFAULT_BATCH_SIZE: usize = 1000;
+/// Messages are sent in a new request once a batch reaches this many bytes, which keeps requests
+/// well under the largest frame the server accepts.
+const MAX_BATCH_BYTES: usize = 4 * 1024 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    /// JSON Lines, one JSON value per line.
+    Jsonl,
+    /// RFC 8742 CBOR sequence, CBOR values one after another.
+    Cbor,
+}
+
+impl Format {
+    /// What one message in the file is called when reporting where it was.
+    fn unit(self) -> &'static str {
+        match self {
+            Format::Jsonl => "line",
+            Format::Cbor => "item",
+        }
+    }
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Format, String> {
+        match &*s.to_lowercase() {
+            "jsonl" => Ok(Format::Jsonl),
+            "cbor" => Ok(Format::Cbor),
+            _ => Err(format!("unexpected format {:?}", s)),
+        }
+    }
+}
+
+/// A message read from the file, ready to be sent.
+struct Record {
+    /// Line or item number in the file, starting at 1.
+    number: usize,
+    message: Vec<u8>,
+    time: Option<u64>,
+}
+
+/// A message that couldn't be imported.
+struct Rejected {
+    number: usize,
+    reason: String,
+}
+
+/// Reads the messages in `path` and adds them to `log` in batches over one connection, then
+/// reports throughput and any messages that were rejected, either while reading the file or by
+/// the server.
+///
+/// When `time_field` is given, a message that is a map with that key has its value, in
+/// milliseconds since the Unix Epoch, used as its ingest time.
+pub fn run(log: &str, path: &str, format: Format, time_field: Option<&str>, batch_size: usize) {
+    let file = File::open(path).unwrap_or_else(|e| fail(format!("could not open {}: {}", path, e)));
+    let values = read_values(file, format);
+
+    let started = Instant::now();
+    let mut stream = protocol::connect_to_remits();
+    let (mut added, mut rejected) = (0, vec![]);
+    let mut batch: Vec<Record> = vec![];
+    let mut batch_bytes = 0;
+
+    for (number, value) in values {
+        let record = value.and_then(|value| to_record(number, value, time_field));
+        match record {
+            Ok(record) => {
+                batch_bytes += record.message.len();
+                batch.push(record);
+            }
+            Err(reason) => rejected.push(Rejected { number, reason }),
+        }
+
+        if batch.len() >= batch_size || batch_bytes >= MAX_BATCH_BYTES {
+            added += send_batch(&mut stream, log, std::mem::take(&mut batch), &mut rejected);
+            batch_bytes = 0;
+        }
+    }
+    if !batch.is_empty() {
+        added += send_batch(&mut stream, log, batch, &mut rejected);
+    }
+
+    let secs = started.elapsed().as_secs_f64();
+    println!(
+        "imported {} messages in {:.2}s ({:.0} msgs/s), rejected {}",
+        added,
+        secs,
+        added as f64 / secs.max(0.001),
+        rejected.len()
+    );
+    rejected.sort_by_key(|r| r.number);
+    for r in rejected {
+        eprintln!("{} {}: {}", format.unit(), r.number, r.reason);
+    }
+}
+
+/// Reads the file one value at a time, numbering each from 1. A CBOR sequence can't be read past
+/// a value that is malformed, so reading stops there.
+fn read_values(
+    file: File,
+    format: Format,
+) -> Box<dyn Iterator<Item = (usize, Result<CborValue, String>)>> {
+    match format {
+        Format::Jsonl => Box::new(
+            BufReader::new(file)
+                .lines()
+                .enumerate()
+                .map(|(i, line)| (i + 1, line.map_err(|e| e.to_string())))
+                .filter(|(_, line)| !matches!(line, Ok(line) if line.trim().is_empty()))
+                .map(|(number, line)| (number, line.and_then(|line| from_json(&line)))),
+        ),
+        Format::Cbor => {
+            let mut failed = false;
+            Box::new(
+                serde_cbor::Deserializer::from_reader(BufReader::new(file))
+                    .into_iter::<CborValue>()
+                    .map(|value| value.map_err(|e| e.to_string()))
+                    .take_while(move |value| {
+                        let keep = !failed;
+                        failed |= value.is_err();
+                        keep
+                    })
+                    .enumerate()
+                    .map(|(i, value)| (i + 1, value)),
+            )
+        }
+    }
+}
+
+fn to_record(number: usize, value: CborValue, time_field: Option<&str>) -> Result<Record, String> {
+    let time = match (&value, time_field) {
+        (CborValue::Map(map), Some(field)) => match map.get(&CborValue::Text(field.into())) {
+            Some(CborValue::Integer(t)) if *t >= 0 && *t <= u64::MAX as i128 => Some(*t as u64),
+            Some(_) => return Err(format!("{:?} is not a unix time in milliseconds", field)),
+            None => None,
+        },
+        _ => None,
+    };
+
+    Ok(Record {
+        number,
+        message: serde_cbor::to_vec(&value).map_err(|e| e.to_string())?,
+        time,
+    })
+}
+
+/// Sends a batch, adding the messages the server rejected to `rejected`, and returns how many it
+/// added.
+fn send_batch(
+    stream: &mut std::net::TcpStream,
+    log: &str,
+    batch: Vec<Record>,
+    rejected: &mut Vec<Rejected>,
+) -> u64 {
+    let numbers: Vec<usize> = batch.iter().map(|r| r.number).collect();
+    let messages = batch.into_iter().map(|r| (r.message, r.time)).collect();
+
+    let (kind, _, payload) =
+        protocol::send_req_on(stream, protocol::new_msg_import_req(log, messages));
+    if kind == 0x03 {
+        fail(format!("import failed: {}", protocol::error_name(&payload)));
+    }
+
+    let report: CborValue = protocol::read_data(&payload)
+        .first()
+        .and_then(|item| serde_cbor::from_slice(item).ok())
+        .unwrap_or_else(|| fail("could not read import report".into()));
+    let field = |map: &CborValue, name: &str| match map {
+        CborValue::Map(map) => map.get(&CborValue::Text(name.into())).cloned(),
+        _ => None,
+    };
+
+    if let Some(CborValue::Array(rejections)) = field(&report, "rejected") {
+        for r in rejections {
+            if let (Some(CborValue::Integer(index)), Some(error)) =
+                (field(&r, "index"), field(&r, "error"))
+            {
+                rejected.push(Rejected {
+                    number: numbers[index as usize],
+                    reason: remitslib::json::to_json(&error),
+                });
+            }
+        }
+    }
+    match field(&report, "added") {
+        Some(CborValue::Integer(added)) => added as u64,
+        _ => 0,
+    }
+}
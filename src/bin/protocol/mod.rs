@@ -1,3 +1,4 @@
+use std::collections::BTreeMap;
 use std::io::{Read, Write};
 use std::net::TcpStream;
 
@@ -5,15 +6,49 @@ use serde::Serialize;
 
 pub static OK_RESP: &[u8] = &[0x62, 0x6F, 0x6B];
 
-pub fn new_log_add_req(name: &str) -> Vec<u8> {
+/// Builds a Log Add request. `options` is a map of the Log's options, or None for the server's
+/// defaults.
+pub fn new_log_add_req(name: &str, options: Option<serde_cbor::Value>) -> Vec<u8> {
     #[derive(Serialize)]
     struct Body {
         log_name: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        options: Option<serde_cbor::Value>,
     }
 
     let mut body = vec![0x00, 0x01];
     let req = serde_cbor::to_vec(&Body {
         log_name: name.into(),
+        options,
+    })
+    .unwrap();
+    body.extend(req);
+    let mut size = (body.len() as u32).to_be_bytes().to_vec();
+    size.extend(body);
+    size
+}
+
+/// Builds a Log Alter request, changing the options in the `options` map and unsetting those
+/// named in `reset`.
+pub fn new_log_alter_req(
+    name: &str,
+    options: Option<serde_cbor::Value>,
+    reset: Vec<String>,
+) -> Vec<u8> {
+    #[derive(Serialize)]
+    struct Body {
+        log_name: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        options: Option<serde_cbor::Value>,
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        reset: Vec<String>,
+    }
+
+    let mut body = vec![0x00, 0x0C];
+    let req = serde_cbor::to_vec(&Body {
+        log_name: name.into(),
+        options,
+        reset,
     })
     .unwrap();
     body.extend(req);
@@ -39,13 +74,32 @@ pub fn new_log_show_req(name: &str) -> Vec<u8> {
     size
 }
 
-pub fn new_log_del_req(name: &str) -> Vec<u8> {
+pub fn new_log_del_req(name: &str, grace_period: Option<u64>) -> Vec<u8> {
     #[derive(Serialize)]
     struct Body {
         log_name: String,
+        grace_period: Option<u64>,
     }
 
     let mut body = vec![0x00, 0x02];
+    let req = serde_cbor::to_vec(&Body {
+        log_name: name.into(),
+        grace_period,
+    })
+    .unwrap();
+    body.extend(req);
+    let mut size = (body.len() as u32).to_be_bytes().to_vec();
+    size.extend(body);
+    size
+}
+
+pub fn new_log_undel_req(name: &str) -> Vec<u8> {
+    #[derive(Serialize)]
+    struct Body {
+        log_name: String,
+    }
+
+    let mut body = vec![0x00, 0x09];
     let req = serde_cbor::to_vec(&Body {
         log_name: name.into(),
     })
@@ -79,17 +133,69 @@ pub fn new_iterator_add_req(name: &str, iterator_name: &str, typ: &str) -> Vec<u
     size
 }
 
-pub fn new_msg_add_req(name: &str, message: Vec<u8>) -> Vec<u8> {
+/// Builds a Message Add request for a CBOR encoded message, with the key and headers it should be
+/// stored with.
+pub fn new_msg_add_req(
+    name: &str,
+    message: Vec<u8>,
+    key: Option<&str>,
+    headers: BTreeMap<String, String>,
+    producer: Option<(&str, u64)>,
+) -> Vec<u8> {
     #[derive(Serialize)]
-    struct Body {
+    struct Body<'a> {
         log_name: String,
         message: serde_cbor::Value,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        key: Option<&'a str>,
+        #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+        headers: BTreeMap<String, String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        producer_id: Option<&'a str>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        sequence: Option<u64>,
     }
 
     let mut body = vec![0x00, 0x04];
     let req = serde_cbor::to_vec(&Body {
         log_name: name.into(),
         message: serde_cbor::Value::Bytes(message),
+        key,
+        headers,
+        producer_id: producer.map(|(id, _)| id),
+        sequence: producer.map(|(_, sequence)| sequence),
+    })
+    .unwrap();
+    body.extend(req);
+    let mut size = (body.len() as u32).to_be_bytes().to_vec();
+    size.extend(body);
+    size
+}
+
+/// Builds a Message Import request for a batch of CBOR encoded messages, each with its original
+/// ingest time in milliseconds since the Unix Epoch if it has one.
+pub fn new_msg_import_req(name: &str, messages: Vec<(Vec<u8>, Option<u64>)>) -> Vec<u8> {
+    #[derive(Serialize)]
+    struct Message {
+        message: serde_cbor::Value,
+        time: Option<u64>,
+    }
+    #[derive(Serialize)]
+    struct Body {
+        log_name: String,
+        messages: Vec<Message>,
+    }
+
+    let mut body = vec![0x00, 0x0B];
+    let req = serde_cbor::to_vec(&Body {
+        log_name: name.into(),
+        messages: messages
+            .into_iter()
+            .map(|(message, time)| Message {
+                message: serde_cbor::Value::Bytes(message),
+                time,
+            })
+            .collect(),
     })
     .unwrap();
     body.extend(req);
@@ -110,12 +216,22 @@ pub fn new_iterator_list_req() -> Vec<u8> {
     size.extend(body);
     size
 }
-pub fn new_iterator_next_req(name: &str, message_id: usize, count: usize) -> Vec<u8> {
+pub fn new_iterator_next_req(
+    name: &str,
+    message_id: usize,
+    count: usize,
+    start_time: Option<u64>,
+    end_time: Option<u64>,
+    with_next_offset: bool,
+) -> Vec<u8> {
     #[derive(Serialize)]
     struct Body {
         iterator_name: String,
         message_id: usize,
         count: usize,
+        start_time: Option<u64>,
+        end_time: Option<u64>,
+        with_next_offset: bool,
     }
 
     let mut body = vec![0x00, 0x07];
@@ -123,6 +239,9 @@ pub fn new_iterator_next_req(name: &str, message_id: usize, count: usize) -> Vec
         iterator_name: name.into(),
         message_id,
         count,
+        start_time,
+        end_time,
+        with_next_offset,
     })
     .unwrap();
     body.extend(req);
@@ -131,14 +250,64 @@ pub fn new_iterator_next_req(name: &str, message_id: usize, count: usize) -> Vec
     size
 }
 
+pub fn new_msg_read_req(
+    name: &str,
+    message_id: usize,
+    count: usize,
+    start_time: Option<u64>,
+    end_time: Option<u64>,
+) -> Vec<u8> {
+    #[derive(Serialize)]
+    struct Body {
+        log_name: String,
+        message_id: usize,
+        count: usize,
+        start_time: Option<u64>,
+        end_time: Option<u64>,
+    }
+
+    let mut body = vec![0x00, 0x0D];
+    let req = serde_cbor::to_vec(&Body {
+        log_name: name.into(),
+        message_id,
+        count,
+        start_time,
+        end_time,
+    })
+    .unwrap();
+    body.extend(req);
+    let mut size = (body.len() as u32).to_be_bytes().to_vec();
+    size.extend(body);
+    size
+}
+
+pub fn new_backup_req(path: &str) -> Vec<u8> {
+    #[derive(Serialize)]
+    struct Body {
+        path: String,
+    }
+
+    let mut body = vec![0x00, 0x0A];
+    let req = serde_cbor::to_vec(&Body { path: path.into() }).unwrap();
+    body.extend(req);
+    let mut size = (body.len() as u32).to_be_bytes().to_vec();
+    size.extend(body);
+    size
+}
+
 pub fn send_req(bytes: Vec<u8>) -> (u8, u8, Vec<u8>) {
-    let mut stream = connect_to_remits();
+    send_req_on(&mut connect_to_remits(), bytes)
+}
+
+/// Sends a request over an open connection and waits for its response, so that many requests can
+/// share one connection.
+pub fn send_req_on(stream: &mut TcpStream, bytes: Vec<u8>) -> (u8, u8, Vec<u8>) {
     stream.write_all(&bytes).expect("could not send command");
 
     let mut buffer = [0; 4];
     stream.read_exact(&mut buffer).unwrap();
     let size = u32::from_be_bytes(buffer);
-    let mut output_buffer = vec![0 as u8; (size) as usize].as_slice().to_owned();
+    let mut output_buffer = vec![0u8; (size) as usize].as_slice().to_owned();
     stream.read_exact(&mut output_buffer).expect("peek failed");
 
     (
@@ -148,6 +317,26 @@ pub fn send_req(bytes: Vec<u8>) -> (u8, u8, Vec<u8>) {
     )
 }
 
+/// The name of the error in the payload of an Error Response.
+pub fn error_name(payload: &[u8]) -> String {
+    match serde_cbor::from_slice(payload) {
+        Ok(serde_cbor::Value::Text(name)) => name,
+        _ => String::from_utf8_lossy(payload).into_owned(),
+    }
+}
+
+/// Splits the payload of a Data Response into its items.
+pub fn read_data(mut payload: &[u8]) -> Vec<Vec<u8>> {
+    let mut items = vec![];
+    while payload.len() >= 4 {
+        let len = u32::from_be_bytes([payload[0], payload[1], payload[2], payload[3]]) as usize;
+        let end = (4 + len).min(payload.len());
+        items.push(payload[4..end].to_vec());
+        payload = &payload[end..];
+    }
+    items
+}
+
 pub fn connect_to_remits() -> TcpStream {
     if let Ok(stream) = TcpStream::connect("localhost:4242") {
         stream
@@ -0,0 +1,145 @@
+use clap::clap_app;
+use remitslib::db::inspect::DataDir;
+use remitslib::json::to_json;
+use serde::Serialize;
+use serde_cbor::Value as CborValue;
+use std::collections::BTreeMap;
+use std::io::Write;
+
+/// A message as printed by `dump`, one JSON object per line.
+#[derive(Serialize)]
+struct DumpedMessage {
+    offset: usize,
+    time: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    key: Option<String>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    headers: BTreeMap<String, String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    producer: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sequence: Option<u64>,
+    message: CborValue,
+}
+
+fn main() {
+    let matches = clap_app!(remitsadmin =>
+        (version: "0.1")
+        (about: "Inspect and verify a remits db directory while the server isn't running")
+        (@arg db_path: -d +required +takes_value "directory that contains the db")
+        (@subcommand manifest =>
+            (about: "Print the manifest as JSON")
+        )
+        (@subcommand segments =>
+            (about: "List the segments of each log")
+            (@arg log_name: -n +takes_value "only list this log's segments")
+        )
+        (@subcommand verify =>
+            (about: "Check segment headers, indexes and message CRCs")
+            (@arg log_name: -n +takes_value "only verify this log")
+        )
+        (@subcommand dump =>
+            (about: "Print messages as JSON, one per line")
+            (@arg log_name: -n +required +takes_value "log to read from")
+            (@arg start: -s +takes_value "first offset to print")
+            (@arg end: -e +takes_value "offset to stop before")
+        )
+    )
+    .get_matches();
+
+    let db_path = matches.value_of("db_path").unwrap();
+    let dir = match DataDir::open(db_path.into()) {
+        Ok(dir) => dir,
+        Err(e) => fail(format!("could not open db at {:?}: {}", db_path, e)),
+    };
+
+    let logs = |args: &clap::ArgMatches| match args.value_of("log_name") {
+        Some(name) => vec![name.to_string()],
+        None => dir.log_names(),
+    };
+
+    match matches.subcommand() {
+        ("manifest", Some(_)) => println!("{}", to_json(&dir.manifest())),
+        ("segments", Some(args)) => {
+            for log in logs(args) {
+                let segments = dir
+                    .segments(&log)
+                    .unwrap_or_else(|e| fail(format!("could not read log {:?}: {}", log, e)));
+
+                println!("{}", log);
+                for s in segments {
+                    let time_range = match s.time_range {
+                        Some((first, last)) => format!("{}..={}", first, last),
+                        None => "-".to_string(),
+                    };
+                    println!(
+                        "  {:020}  offsets {}..{}  {} msgs  times {}  {} bytes{}",
+                        s.timestamp,
+                        s.first_offset,
+                        s.end_offset,
+                        s.msgs,
+                        time_range,
+                        s.size_bytes,
+                        if s.compressed { "  compressed" } else { "" }
+                    );
+                }
+            }
+        }
+        ("verify", Some(args)) => {
+            let mut found = 0;
+            for log in logs(args) {
+                let problems = dir
+                    .verify(&log)
+                    .unwrap_or_else(|e| fail(format!("could not read log {:?}: {}", log, e)));
+                for p in problems.iter() {
+                    println!("{} {:020}: {}", p.log, p.segment, p.description);
+                }
+                found += problems.len();
+            }
+
+            if found > 0 {
+                fail(format!("found {} problems", found));
+            }
+            println!("ok");
+        }
+        ("dump", Some(args)) => {
+            let log = args.value_of("log_name").unwrap();
+            let offset = |name| {
+                args.value_of(name).map(|v| {
+                    v.parse()
+                        .unwrap_or_else(|_| fail(format!("{} {:?} is not an offset", name, v)))
+                })
+            };
+            let start = offset("start").unwrap_or(0);
+            let end = offset("end").unwrap_or(usize::MAX);
+
+            let stdout = std::io::stdout();
+            let mut out = std::io::BufWriter::new(stdout.lock());
+            dir.messages(log, start, end, |msg| {
+                let producer = msg.meta.producer;
+                let line = DumpedMessage {
+                    offset: msg.offset,
+                    time: msg.time,
+                    key: msg.meta.key,
+                    headers: msg.meta.headers,
+                    producer: producer.as_ref().map(|p| p.id.clone()),
+                    sequence: producer.as_ref().map(|p| p.sequence),
+                    message: serde_cbor::from_slice(&msg.body)
+                        .unwrap_or(CborValue::Bytes(msg.body)),
+                };
+                let line = serde_cbor::value::to_value(&line).expect("could not serialize message");
+                writeln!(out, "{}", to_json(&line))
+                    .unwrap_or_else(|e| fail(format!("could not write message: {}", e)));
+            })
+            .unwrap_or_else(|e| fail(format!("could not read log {:?}: {}", log, e)));
+            out.flush()
+                .unwrap_or_else(|e| fail(format!("could not write message: {}", e)));
+        }
+        _ => panic!("{}", "Type help, -h, or --help"),
+    }
+}
+
+fn fail(msg: String) -> ! {
+    eprintln!("{}", msg);
+    std::process::exit(1);
+}
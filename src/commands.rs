@@ -1,16 +1,25 @@
+use crate::errors::Error;
+use crate::schema::Schema;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::str::FromStr;
 
 #[derive(Debug)]
 pub enum Command {
     LogShow(LogShow),
     LogAdd(LogAdd),
     LogDelete(LogDelete),
+    LogUndelete(LogUndelete),
+    LogAlter(LogAlter),
     LogList,
     MessageAdd(MessageAdd),
+    MessageImport(MessageImport),
+    MessageRead(MessageRead),
     IteratorAdd(IteratorAdd),
     IteratorList(IteratorList),
     IteratorDelete(IteratorDelete),
     IteratorNext(IteratorNext),
+    Backup(Backup),
 }
 
 #[derive(Deserialize, Debug)]
@@ -21,17 +30,81 @@ pub struct LogShow {
 #[derive(Deserialize, Debug)]
 pub struct LogAdd {
     pub log_name: String,
+    #[serde(default)]
+    pub options: LogOptions,
 }
 
 #[derive(Deserialize, Debug)]
 pub struct LogDelete {
     pub log_name: String,
+    /// Number of seconds the Log can be undeleted for. Overrides the Log's
+    /// `delete_grace_period`.
+    #[serde(default)]
+    pub grace_period: Option<u64>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct LogUndelete {
+    pub log_name: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct LogAlter {
+    pub log_name: String,
+    /// Options to change. Options left unset keep their current value.
+    #[serde(default)]
+    pub options: LogOptions,
+    /// Names of options to unset, so that they fall back to the server-wide default.
+    #[serde(default)]
+    pub reset: Vec<String>,
 }
 
 #[derive(Deserialize, Debug)]
 pub struct MessageAdd {
     pub log_name: String,
     pub message: serde_cbor::Value,
+    /// Optional metadata stored alongside the message. Iterator functions see it as `meta`.
+    #[serde(default)]
+    pub key: Option<String>,
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    /// Set by an idempotent producer, along with `sequence`, so that the message is only added
+    /// once however many times it is sent.
+    #[serde(default)]
+    pub producer_id: Option<String>,
+    /// Numbers the producer's messages. Each message must have a higher number than the last.
+    #[serde(default)]
+    pub sequence: Option<u64>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct MessageImport {
+    pub log_name: String,
+    pub messages: Vec<ImportedMessage>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ImportedMessage {
+    pub message: serde_cbor::Value,
+    /// Original ingest time of the message, in milliseconds since the Unix Epoch. The time it is
+    /// imported at is used if unset.
+    #[serde(default)]
+    pub time: Option<u64>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct MessageRead {
+    pub log_name: String,
+    pub message_id: usize,
+    pub count: usize,
+    /// Start at the first message ingested at or after this time, in milliseconds since the Unix
+    /// Epoch, if that is later than `message_id`.
+    #[serde(default)]
+    pub start_time: Option<u64>,
+    /// Stop before the first message ingested at or after this time, in milliseconds since the
+    /// Unix Epoch.
+    #[serde(default)]
+    pub end_time: Option<u64>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -52,6 +125,18 @@ pub struct IteratorNext {
     pub iterator_name: String,
     pub message_id: usize,
     pub count: usize,
+    /// Start at the first message ingested at or after this time, in milliseconds since the Unix
+    /// Epoch, if that is later than `message_id`.
+    #[serde(default)]
+    pub start_time: Option<u64>,
+    /// Stop before the first message ingested at or after this time, in milliseconds since the
+    /// Unix Epoch.
+    #[serde(default)]
+    pub end_time: Option<u64>,
+    /// Append the CBOR encoded offset to resume from to the messages returned, so the next page
+    /// can be requested without knowing where this one started.
+    #[serde(default)]
+    pub with_next_offset: bool,
 }
 
 #[derive(Deserialize, Debug)]
@@ -60,7 +145,13 @@ pub struct IteratorDelete {
     pub iterator_name: String,
 }
 
-#[derive(PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[derive(Deserialize, Debug)]
+pub struct Backup {
+    /// Directory on the server to write the backup to. It must be empty or not exist yet.
+    pub path: String,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
 #[serde(rename_all = "snake_case")]
 pub enum IteratorKind {
     Map,
@@ -78,3 +169,209 @@ impl From<&str> for IteratorKind {
         }
     }
 }
+
+/// Per-Log settings. Any option left unset falls back to the server-wide default.
+#[derive(Clone, Default, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct LogOptions {
+    /// Number of bytes a Segment can hold before a new one is started.
+    #[serde(default)]
+    pub max_segment_size: Option<u64>,
+    /// Number of seconds a Segment is written to before a new one is started.
+    #[serde(default)]
+    pub max_segment_age: Option<u64>,
+    /// How Segments are compressed once they are sealed.
+    #[serde(default)]
+    pub compression: Option<Compression>,
+    /// Number of seconds after which messages are deleted.
+    #[serde(default)]
+    pub retention_max_age: Option<u64>,
+    /// Number of bytes of Segments to keep before the oldest are deleted.
+    #[serde(default)]
+    pub retention_max_size: Option<u64>,
+    /// Number of messages to keep before the oldest are deleted.
+    #[serde(default)]
+    pub retention_max_messages: Option<u64>,
+    /// When messages are fsynced, and so when MessageAdd acknowledges them.
+    #[serde(default)]
+    pub durability: Option<Durability>,
+    /// Number of seconds a deleted Log is kept for before its data is removed. Until then it can
+    /// be undeleted.
+    #[serde(default)]
+    pub delete_grace_period: Option<u64>,
+    /// How old messages are removed from the Log.
+    #[serde(default)]
+    pub cleanup_policy: Option<CleanupPolicy>,
+    /// Number of seconds a tombstone is kept for in a compacted Log, so that readers have a chance
+    /// to see that its key was deleted.
+    #[serde(default)]
+    pub tombstone_grace_period: Option<u64>,
+    /// Number of bytes a message can be at most.
+    #[serde(default)]
+    pub max_message_size: Option<u64>,
+    /// What messages the Log accepts.
+    #[serde(default)]
+    pub ingestion: Option<IngestionMode>,
+    /// Structure CBOR messages must have to be accepted.
+    #[serde(default)]
+    pub schema: Option<Box<Schema>>,
+    /// What the Log is for, for the people who use it.
+    #[serde(default)]
+    pub description: Option<String>,
+    /// Labels such as the team that owns the Log. They aren't used by the server.
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+}
+
+impl LogOptions {
+    /// Fills in any unset options from `defaults`.
+    pub fn or(&self, defaults: &LogOptions) -> LogOptions {
+        LogOptions {
+            max_segment_size: self.max_segment_size.or(defaults.max_segment_size),
+            max_segment_age: self.max_segment_age.or(defaults.max_segment_age),
+            compression: self.compression.or(defaults.compression),
+            retention_max_age: self.retention_max_age.or(defaults.retention_max_age),
+            retention_max_size: self.retention_max_size.or(defaults.retention_max_size),
+            retention_max_messages: self
+                .retention_max_messages
+                .or(defaults.retention_max_messages),
+            durability: self.durability.or(defaults.durability),
+            delete_grace_period: self.delete_grace_period.or(defaults.delete_grace_period),
+            cleanup_policy: self.cleanup_policy.or(defaults.cleanup_policy),
+            tombstone_grace_period: self
+                .tombstone_grace_period
+                .or(defaults.tombstone_grace_period),
+            max_message_size: self.max_message_size.or(defaults.max_message_size),
+            ingestion: self.ingestion.or(defaults.ingestion),
+            schema: self.schema.clone().or_else(|| defaults.schema.clone()),
+            description: self
+                .description
+                .clone()
+                .or_else(|| defaults.description.clone()),
+            labels: if self.labels.is_empty() {
+                defaults.labels.clone()
+            } else {
+                self.labels.clone()
+            },
+        }
+    }
+
+    /// Unsets the options called `names`, which must all be names of options.
+    pub fn reset(&self, names: &[String]) -> Result<LogOptions, Error> {
+        let mut value = serde_cbor::value::to_value(self).expect("could not serialize options");
+        if let serde_cbor::Value::Map(map) = &mut value {
+            for name in names {
+                // An option that is missing is the same as one that is unset.
+                map.remove(&serde_cbor::Value::Text(name.clone()))
+                    .ok_or(Error::LogOptionUnknown)?;
+            }
+        }
+        Ok(serde_cbor::value::from_value(value).expect("could not deserialize options"))
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Compression {
+    None,
+    Lz4,
+}
+
+impl FromStr for Compression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Compression, String> {
+        match &*s.to_lowercase() {
+            "none" => Ok(Compression::None),
+            "lz4" => Ok(Compression::Lz4),
+            _ => Err(format!("unexpected compression {:?}", s)),
+        }
+    }
+}
+
+/// How a Log gets rid of old messages.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CleanupPolicy {
+    /// The oldest Segments are deleted once they are past the Log's retention limits.
+    #[default]
+    Delete,
+    /// Messages are removed once a newer message with the same key is added, so that the Log keeps
+    /// the latest value of each key. A message with a key and a null body is a tombstone, which
+    /// removes its key altogether once the tombstone grace period has passed. Retention limits
+    /// still apply, deleting the oldest Segments whatever keys they hold.
+    Compact,
+}
+
+impl FromStr for CleanupPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<CleanupPolicy, String> {
+        match &*s.to_lowercase() {
+            "delete" => Ok(CleanupPolicy::Delete),
+            "compact" => Ok(CleanupPolicy::Compact),
+            _ => Err(format!("unexpected cleanup policy {:?}", s)),
+        }
+    }
+}
+
+/// What a Log accepts as a message.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IngestionMode {
+    /// Messages must be a single CBOR value, which iterator functions see decoded.
+    #[default]
+    Cbor,
+    /// Messages are opaque bytes, which iterator functions see as a byte string. Schemas don't
+    /// apply.
+    Raw,
+}
+
+impl FromStr for IngestionMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<IngestionMode, String> {
+        match &*s.to_lowercase() {
+            "cbor" => Ok(IngestionMode::Cbor),
+            "raw" => Ok(IngestionMode::Raw),
+            _ => Err(format!("unexpected ingestion mode {:?}", s)),
+        }
+    }
+}
+
+/// How long MessageAdd waits for a message to reach the disk before acknowledging it.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Durability {
+    /// Every message is fsynced before it is acknowledged.
+    Always,
+    /// Messages are fsynced as a group once `messages` are waiting or the oldest has waited
+    /// `millis` milliseconds, whichever comes first. Each is acknowledged once its group is.
+    Batch { messages: u64, millis: u64 },
+    /// Messages are acknowledged once they are handed to the OS, which writes them out whenever
+    /// it sees fit.
+    #[default]
+    Os,
+}
+
+impl FromStr for Durability {
+    type Err = String;
+
+    /// Parses "always", "os", or "batch:<messages>:<millis>".
+    fn from_str(s: &str) -> Result<Durability, String> {
+        let s = s.to_lowercase();
+        let parts: Vec<&str> = s.split(':').collect();
+        match &*parts {
+            ["always"] => Ok(Durability::Always),
+            ["os"] => Ok(Durability::Os),
+            ["batch", messages, millis] => Ok(Durability::Batch {
+                messages: messages
+                    .parse()
+                    .map_err(|_| format!("unexpected batch size {:?}", messages))?,
+                millis: millis
+                    .parse()
+                    .map_err(|_| format!("unexpected batch interval {:?}", millis))?,
+            }),
+            _ => Err(format!("unexpected durability {:?}", s)),
+        }
+    }
+}
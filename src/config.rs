@@ -1,3 +1,4 @@
+use crate::commands::{CleanupPolicy, Compression, Durability, IngestionMode, LogOptions};
 use argh::FromArgs;
 use env_logger::{Builder, Target};
 use serde::{Deserialize, Serialize};
@@ -16,6 +17,66 @@ pub struct RemitsConfig {
     #[argh(option, short = 'd')]
     /// directory that contains the db
     pub db_path: Option<String>,
+
+    #[argh(option)]
+    /// default number of bytes a log segment holds before rolling over
+    pub max_segment_size: Option<u64>,
+
+    #[argh(option)]
+    /// default number of seconds a log segment is written to before rolling over
+    pub max_segment_age: Option<u64>,
+
+    #[argh(option)]
+    /// default compression for sealed log segments: "none" or "lz4"
+    pub compression: Option<Compression>,
+
+    #[argh(option)]
+    /// default for when messages are fsynced: "always", "os" or "batch:<messages>:<millis>"
+    pub durability: Option<Durability>,
+
+    #[argh(option)]
+    /// default number of seconds to keep messages for
+    pub retention_max_age: Option<u64>,
+
+    #[argh(option)]
+    /// default number of bytes of segments to keep per log
+    pub retention_max_size: Option<u64>,
+
+    #[argh(option)]
+    /// default number of messages to keep per log
+    pub retention_max_messages: Option<u64>,
+
+    #[argh(option)]
+    /// default number of seconds a deleted log can be undeleted for before its data is removed
+    pub delete_grace_period: Option<u64>,
+
+    #[argh(option)]
+    /// default for how old messages are removed: "delete" or "compact"
+    pub cleanup_policy: Option<CleanupPolicy>,
+
+    #[argh(option)]
+    /// default number of seconds tombstones are kept for in compacted logs
+    pub tombstone_grace_period: Option<u64>,
+
+    #[argh(option)]
+    /// default number of bytes a message can be at most
+    pub max_message_size: Option<u64>,
+
+    #[argh(option)]
+    /// default for what messages logs accept: "cbor" or "raw"
+    pub ingestion: Option<IngestionMode>,
+
+    #[argh(option)]
+    /// how often in seconds to delete segments past their log's retention, compact logs and
+    /// compress sealed segments, or 0 to never do so
+    // Config files written before this option existed don't have it, and should still get the
+    // default rather than turn maintenance off.
+    #[serde(default = "default_retention_interval")]
+    pub retention_interval: Option<u64>,
+
+    #[argh(option)]
+    /// restore the db from this backup directory before starting, if db_path is empty
+    pub restore_from: Option<String>,
 }
 
 impl RemitsConfig {
@@ -29,11 +90,11 @@ impl RemitsConfig {
         // This one must be first so debug logs work the rest of the way down
         setup_logger(self.log_level.clone(), flags.log_level);
 
-        if flags.port.is_some() {
+        if let Some(port) = &flags.port {
             debug!(
                 "Replacing config option \"port\":{} with flag \"-p/--port\":{}",
                 self.port.as_ref().unwrap(),
-                flags.port.as_ref().unwrap()
+                port
             );
             self.port = flags.port;
         }
@@ -42,12 +103,89 @@ impl RemitsConfig {
             self.db_path = flags.db_path;
         }
 
+        if flags.max_segment_size.is_some() {
+            self.max_segment_size = flags.max_segment_size;
+        }
+
+        if flags.max_segment_age.is_some() {
+            self.max_segment_age = flags.max_segment_age;
+        }
+
+        if flags.compression.is_some() {
+            self.compression = flags.compression;
+        }
+
+        if flags.durability.is_some() {
+            self.durability = flags.durability;
+        }
+
+        if flags.retention_max_age.is_some() {
+            self.retention_max_age = flags.retention_max_age;
+        }
+
+        if flags.retention_max_size.is_some() {
+            self.retention_max_size = flags.retention_max_size;
+        }
+
+        if flags.retention_max_messages.is_some() {
+            self.retention_max_messages = flags.retention_max_messages;
+        }
+
+        if flags.delete_grace_period.is_some() {
+            self.delete_grace_period = flags.delete_grace_period;
+        }
+
+        if flags.cleanup_policy.is_some() {
+            self.cleanup_policy = flags.cleanup_policy;
+        }
+
+        if flags.tombstone_grace_period.is_some() {
+            self.tombstone_grace_period = flags.tombstone_grace_period;
+        }
+
+        if flags.max_message_size.is_some() {
+            self.max_message_size = flags.max_message_size;
+        }
+
+        if flags.ingestion.is_some() {
+            self.ingestion = flags.ingestion;
+        }
+
+        if flags.retention_interval.is_some() {
+            self.retention_interval = flags.retention_interval;
+        }
+
+        if flags.restore_from.is_some() {
+            self.restore_from = flags.restore_from;
+        }
+
         self.clone()
     }
 
     pub fn addr(&self) -> String {
         format!("0.0.0.0:{}", self.clone().port.expect("no port defined"))
     }
+
+    /// Server-wide defaults for Logs that don't set their own options.
+    pub fn log_defaults(&self) -> LogOptions {
+        LogOptions {
+            max_segment_size: self.max_segment_size,
+            max_segment_age: self.max_segment_age,
+            compression: self.compression,
+            retention_max_age: self.retention_max_age,
+            retention_max_size: self.retention_max_size,
+            retention_max_messages: self.retention_max_messages,
+            durability: self.durability,
+            delete_grace_period: self.delete_grace_period,
+            cleanup_policy: self.cleanup_policy,
+            tombstone_grace_period: self.tombstone_grace_period,
+            max_message_size: self.max_message_size,
+            ingestion: self.ingestion,
+            schema: None,
+            description: None,
+            labels: Default::default(),
+        }
+    }
 }
 
 impl ::std::default::Default for RemitsConfig {
@@ -56,10 +194,28 @@ impl ::std::default::Default for RemitsConfig {
             port: Some("4242".into()),
             log_level: Some("info".into()),
             db_path: Some("/var/lib/remits".into()),
+            max_segment_size: None,
+            max_segment_age: None,
+            compression: None,
+            durability: None,
+            retention_max_age: None,
+            retention_max_size: None,
+            retention_max_messages: None,
+            delete_grace_period: None,
+            cleanup_policy: None,
+            tombstone_grace_period: None,
+            max_message_size: None,
+            ingestion: None,
+            retention_interval: default_retention_interval(),
+            restore_from: None,
         }
     }
 }
 
+fn default_retention_interval() -> Option<u64> {
+    Some(60)
+}
+
 fn setup_logger(config_level: Option<String>, flag_level: Option<String>) {
     let log_level = &flag_level.unwrap_or_else(|| {
         config_level
@@ -79,3 +235,16 @@ fn setup_logger(config_level: Option<String>, flag_level: Option<String>) {
 pub fn load() -> RemitsConfig {
     RemitsConfig::new().update_from_flags()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_config_without_retention_interval_gets_default() {
+        let cfg: RemitsConfig =
+            serde_json::from_str(r#"{"port": "4242", "db_path": "/tmp/remits"}"#).unwrap();
+        assert_eq!(cfg.retention_interval, Some(60));
+        assert_eq!(cfg.max_segment_size, None);
+    }
+}
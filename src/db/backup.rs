@@ -0,0 +1,296 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::prelude::*;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use super::logs::crc::Crc32;
+
+/// Name of the file describing a backup. It is written last, so a backup without one is
+/// incomplete.
+pub const INFO_FILE: &str = "backup";
+
+// Layout:
+//
+// A backup has the same layout as a DB directory, so once restored it is opened like any other:
+//   - manifest            The Manifest, checkpointed as of the backup.
+//   - logs/<name>/        The Segments of each Log, up to its high-water mark.
+//   - trash/<dir>/        The data of soft deleted Logs that can still be undeleted.
+//   - backup              The CBOR encoded BackupInfo.
+//
+// Sealed Segments and the data of deleted Logs are never written to again, so they are hard
+// linked into the backup rather than copied when it is on the same filesystem.
+
+/// Describes what a backup holds, so that it can be checked before it is restored.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct BackupInfo {
+    pub version: u32,
+    /// When the backup was taken, in seconds since the Unix Epoch.
+    pub created_at: u64,
+    /// The high-water mark of each Log, which is the offset of its first message that isn't in
+    /// the backup.
+    pub logs: BTreeMap<String, u64>,
+    /// Every file in the backup, other than this one.
+    pub files: Vec<BackupFile>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct BackupFile {
+    /// Path relative to the backup directory.
+    pub path: String,
+    pub len: u64,
+    pub crc: u32,
+}
+
+impl BackupInfo {
+    pub const VERSION: u32 = 1;
+
+    /// Checksums every file under `dir` and writes the BackupInfo describing them, which marks the
+    /// backup as complete.
+    pub fn write(
+        dir: &Path,
+        created_at: u64,
+        logs: BTreeMap<String, u64>,
+    ) -> std::io::Result<Self> {
+        let mut files = vec![];
+        for path in list_files(dir)? {
+            let (len, crc) = checksum_file(&path, None)?;
+            files.push(BackupFile {
+                path: relative(dir, &path),
+                len,
+                crc,
+            });
+        }
+        files.sort_by(|a, b| a.path.cmp(&b.path));
+
+        let info = BackupInfo {
+            version: Self::VERSION,
+            created_at,
+            logs,
+            files,
+        };
+
+        let tmp_path = dir.join(format!("{}.tmp", INFO_FILE));
+        let mut tmp = File::create(&tmp_path)?;
+        tmp.write_all(&serde_cbor::to_vec(&info).expect("could not serialize backup info"))?;
+        tmp.sync_all()?;
+        std::fs::rename(&tmp_path, dir.join(INFO_FILE))?;
+        File::open(dir)?.sync_all()?;
+
+        Ok(info)
+    }
+
+    /// Reads the BackupInfo of the backup in `dir`.
+    pub fn read(dir: &Path) -> std::io::Result<Self> {
+        let bytes = match std::fs::read(dir.join(INFO_FILE)) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Err(invalid_data(format!("{:?} is not a complete backup", dir)))
+            }
+            Err(e) => return Err(e),
+        };
+        let info: BackupInfo = serde_cbor::from_slice(&bytes)
+            .map_err(|e| invalid_data(format!("could not read backup info: {}", e)))?;
+
+        if info.version > Self::VERSION {
+            return Err(invalid_data(format!(
+                "backup version {} is newer than the supported version {}",
+                info.version,
+                Self::VERSION
+            )));
+        }
+        Ok(info)
+    }
+}
+
+/// A file opened while the DB was locked, so that it can be backed up once the lock is released
+/// even if retention or compaction has removed or replaced it since.
+#[derive(Debug)]
+pub struct PinnedFile {
+    path: PathBuf,
+    file: File,
+}
+
+impl PinnedFile {
+    pub fn open(path: PathBuf) -> std::io::Result<Self> {
+        let file = File::open(&path)?;
+        Ok(PinnedFile { path, file })
+    }
+
+    /// Opens every file under `dir`, recursively, along with their paths relative to it.
+    pub fn open_tree(dir: &Path) -> std::io::Result<Vec<(String, Self)>> {
+        list_files(dir)?
+            .into_iter()
+            .map(|path| Ok((relative(dir, &path), Self::open(path)?)))
+            .collect()
+    }
+
+    /// Hard links the file to `dst`, or copies it if that isn't possible, e.g. because they are
+    /// on different filesystems, or if its path no longer holds the file that was opened.
+    pub fn link_or_copy(&self, dst: &Path) -> std::io::Result<()> {
+        if std::fs::hard_link(&self.path, dst).is_ok() {
+            let (linked, pinned) = (std::fs::metadata(dst)?, self.file.metadata()?);
+            if linked.dev() == pinned.dev() && linked.ino() == pinned.ino() {
+                return Ok(());
+            }
+            std::fs::remove_file(dst)?;
+        }
+
+        let mut out = File::create(dst)?;
+        let mut file = &self.file;
+        file.seek(std::io::SeekFrom::Start(0))?;
+        std::io::copy(&mut file, &mut out)?;
+        out.sync_all()
+    }
+}
+
+/// Restores the backup in `backup` to `db_path`, which must not hold a DB already. Every file is
+/// checked against its checksum as it is copied, and the DB only appears at `db_path` once all of
+/// them have been, so a backup that fails to restore never leaves behind a DB that can be opened.
+pub fn restore(backup: &Path, db_path: &Path) -> std::io::Result<BackupInfo> {
+    let info = BackupInfo::read(backup)?;
+
+    if db_path.exists() && std::fs::read_dir(db_path)?.next().is_some() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::AlreadyExists,
+            format!("can not restore over the existing DB in {:?}", db_path),
+        ));
+    }
+
+    let mut tmp_path = db_path.as_os_str().to_owned();
+    tmp_path.push(".restoring");
+    let tmp_path = PathBuf::from(tmp_path);
+    if tmp_path.exists() {
+        std::fs::remove_dir_all(&tmp_path)?;
+    }
+
+    if let Err(e) = copy_verified(backup, &tmp_path, &info) {
+        let _ = std::fs::remove_dir_all(&tmp_path);
+        return Err(e);
+    }
+
+    if db_path.exists() {
+        std::fs::remove_dir(db_path)?;
+    }
+    std::fs::rename(&tmp_path, db_path)?;
+    if let Some(parent) = db_path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        File::open(parent)?.sync_all()?;
+    }
+
+    Ok(info)
+}
+
+/// Copies every file listed in `info` from `src` to `dst`, checking each against its checksum.
+fn copy_verified(src: &Path, dst: &Path, info: &BackupInfo) -> std::io::Result<()> {
+    for file in info.files.iter() {
+        let inside = Path::new(&file.path)
+            .components()
+            .all(|c| matches!(c, std::path::Component::Normal(_)));
+        if !inside {
+            return Err(invalid_data(format!(
+                "backup file {:?} is outside of the backup",
+                file.path
+            )));
+        }
+
+        let to = dst.join(&file.path);
+        if let Some(parent) = to.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        let (len, crc) = checksum_file(&src.join(&file.path), Some(&to))?;
+        if len != file.len || crc != file.crc {
+            return Err(invalid_data(format!(
+                "backup file {:?} is corrupt: expected {} bytes with crc {:08x}, found {} bytes \
+                 with crc {:08x}",
+                file.path, file.len, file.crc, len, crc
+            )));
+        }
+    }
+
+    std::fs::create_dir_all(dst)?;
+    File::open(dst)?.sync_all()
+}
+
+/// Returns the length and CRC of the file at `path`, copying it to `copy_to` along the way if
+/// given.
+fn checksum_file(path: &Path, copy_to: Option<&Path>) -> std::io::Result<(u64, u32)> {
+    let mut file = File::open(path)?;
+    let mut out = copy_to.map(File::create).transpose()?;
+
+    let mut crc = Crc32::new();
+    let mut len = 0;
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        crc.update(&buf[..n]);
+        if let Some(out) = out.as_mut() {
+            out.write_all(&buf[..n])?;
+        }
+        len += n as u64;
+    }
+
+    if let Some(out) = out {
+        out.sync_all()?;
+    }
+    Ok((len, crc.finish()))
+}
+
+/// Lists every file under `dir`, recursively.
+fn list_files(dir: &Path) -> std::io::Result<Vec<PathBuf>> {
+    let mut files = vec![];
+    let mut dirs = vec![dir.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        for entry in std::fs::read_dir(&dir)? {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() {
+                dirs.push(entry.path());
+            } else {
+                files.push(entry.path());
+            }
+        }
+    }
+    Ok(files)
+}
+
+fn relative(dir: &Path, path: &Path) -> String {
+    path.strip_prefix(dir)
+        .expect("file is not in the backup directory")
+        .to_string_lossy()
+        .into_owned()
+}
+
+fn invalid_data(msg: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::temp_db_path;
+
+    #[test]
+    fn test_pinned_file_is_copied_once_replaced() {
+        let dir = PathBuf::from(temp_db_path());
+        let path = dir.join("segment");
+        std::fs::write(&path, b"sealed").unwrap();
+        let pinned = PinnedFile::open(path.clone()).unwrap();
+
+        pinned.link_or_copy(&dir.join("linked")).unwrap();
+        assert_eq!(std::fs::read(dir.join("linked")).unwrap(), b"sealed");
+
+        // As when compaction rewrites a Segment after it was pinned.
+        std::fs::write(path.with_extension("tmp"), b"compacted").unwrap();
+        std::fs::rename(path.with_extension("tmp"), &path).unwrap();
+        pinned.link_or_copy(&dir.join("copied")).unwrap();
+        assert_eq!(std::fs::read(dir.join("copied")).unwrap(), b"sealed");
+
+        std::fs::remove_file(&path).unwrap();
+        pinned.link_or_copy(&dir.join("deleted")).unwrap();
+        assert_eq!(std::fs::read(dir.join("deleted")).unwrap(), b"sealed");
+    }
+}
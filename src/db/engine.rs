@@ -0,0 +1,306 @@
+use std::collections::hash_map::{Entry, Iter, IterMut};
+use std::collections::HashMap;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+use super::logs::{self, Log, PendingSync};
+use crate::commands::LogOptions;
+use crate::errors::Error as DbError;
+use storage::{Error, LogStats, Metadata, RawMessage, StorageEngine};
+
+/// The StorageEngine the server runs on. Each Log is kept in its own directory of Segments, under
+/// `<path>/logs/<name>`.
+#[derive(Debug)]
+pub struct SegmentEngine {
+    path: PathBuf,
+
+    /// Options for Logs created through the StorageEngine trait, which has no way to pass any.
+    defaults: LogOptions,
+
+    logs: HashMap<String, Log>,
+}
+
+impl SegmentEngine {
+    /// Creates an engine for the DB at `path`. No Logs are opened until asked for.
+    pub fn new(path: PathBuf, defaults: LogOptions) -> Self {
+        SegmentEngine {
+            path,
+            defaults,
+            logs: HashMap::new(),
+        }
+    }
+
+    /// Creates an engine for the DB at `path` with every Log already in it opened with `defaults`,
+    /// for running without a Manifest to say which Logs there are and what options they have.
+    pub fn open(path: PathBuf, defaults: LogOptions) -> std::io::Result<Self> {
+        let mut engine = Self::new(path, defaults);
+        let logs_dir = engine.path.join("logs");
+        if !logs_dir.exists() {
+            return Ok(engine);
+        }
+
+        for entry in std::fs::read_dir(&logs_dir)? {
+            let entry = entry?;
+            if !entry.file_type()?.is_dir() {
+                continue;
+            }
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let options = engine.defaults.clone();
+            engine.open_log(&name, options)?;
+        }
+        Ok(engine)
+    }
+
+    /// Opens the Log from its Segments on disk, creating it if it doesn't exist yet. `options`
+    /// should already have the server-wide defaults filled in. A Log that is already open is left
+    /// as it is.
+    pub fn open_log(&mut self, name: &str, options: LogOptions) -> std::io::Result<&Log> {
+        match self.logs.entry(name.to_string()) {
+            Entry::Occupied(e) => Ok(e.into_mut()),
+            Entry::Vacant(e) => Ok(e.insert(Log::new(self.path.clone(), name, options)?)),
+        }
+    }
+
+    /// Closes the Log and moves its directory to `dir` in the trash, from where it can be restored
+    /// or purged. The rename is atomic, so the Log never has half its data removed. Messages still
+    /// waiting to be synced have their own handles to its files, which stay valid after the move.
+    pub fn trash_log(&mut self, name: &str, dir: &str) -> std::io::Result<()> {
+        self.logs.remove(name);
+        let trash_dir = self.path.join("trash");
+        std::fs::create_dir_all(&trash_dir)?;
+        std::fs::rename(self.path.join("logs").join(name), trash_dir.join(dir))?;
+        File::open(&trash_dir)?.sync_all()
+    }
+
+    /// Removes the data of a deleted Log from `dir` in the trash.
+    pub fn purge_trashed(&self, dir: &str) -> std::io::Result<()> {
+        std::fs::remove_dir_all(self.path.join("trash").join(dir))
+    }
+
+    /// Picks a directory in the trash of the DB at `path` for a deleted Log's data. It is named
+    /// after when the Log was deleted, so another version of the Log deleted within the same
+    /// millisecond needs the next name along.
+    pub fn trash_dir_name(path: &Path, name: &str) -> String {
+        let mut millis = logs::now_millis();
+        loop {
+            let dir = format!("{}.{}", name, millis);
+            if !path.join("trash").join(&dir).exists() {
+                return dir;
+            }
+            millis += 1;
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Log> {
+        self.logs.get(name)
+    }
+
+    pub fn get_mut(&mut self, name: &str) -> Option<&mut Log> {
+        self.logs.get_mut(name)
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.logs.contains_key(name)
+    }
+
+    pub fn iter(&self) -> Iter<'_, String, Log> {
+        self.logs.iter()
+    }
+
+    pub fn iter_mut(&mut self) -> IterMut<'_, String, Log> {
+        self.logs.iter_mut()
+    }
+
+    /// Appends a message to a Log without waiting for it to be synced. See Log::add_msg.
+    pub fn append_pending(
+        &mut self,
+        log: &str,
+        body: Vec<u8>,
+        meta: Metadata,
+    ) -> Result<PendingSync, DbError> {
+        match self.logs.get_mut(log) {
+            Some(log) => log.add_msg(body, meta),
+            None => Err(DbError::LogDoesNotExist),
+        }
+    }
+
+    /// Like append_pending, but with an ingest time given by the writer. See Log::add_msg_at.
+    pub fn append_pending_at(
+        &mut self,
+        log: &str,
+        body: Vec<u8>,
+        meta: Metadata,
+        time: u64,
+    ) -> Result<PendingSync, DbError> {
+        match self.logs.get_mut(log) {
+            Some(log) => log.add_msg_at(body, meta, time),
+            None => Err(DbError::LogDoesNotExist),
+        }
+    }
+
+    fn log(&self, name: &str) -> Result<&Log, Error> {
+        self.logs.get(name).ok_or(Error::LogDoesNotExist)
+    }
+}
+
+impl StorageEngine for SegmentEngine {
+    fn create_log(&mut self, name: &str) -> Result<(), Error> {
+        if self.logs.contains_key(name) {
+            return Err(Error::LogAlreadyExists);
+        }
+
+        let options = self.defaults.clone();
+        self.open_log(name, options)
+            .map(|_| ())
+            .map_err(|e| Error::Io(e.to_string()))
+    }
+
+    /// Deletes a Log the way the DB does. A Log with a delete grace period is left in the trash,
+    /// where the DB reports it on open as data that can be removed once it is no longer needed,
+    /// since only the DB's Manifest keeps track of when deleted Logs are purged.
+    fn delete_log(&mut self, name: &str) -> Result<(), Error> {
+        let log = self.logs.get(name).ok_or(Error::LogDoesNotExist)?;
+        let grace_period = log.options().delete_grace_period;
+
+        let dir = Self::trash_dir_name(&self.path, name);
+        let res = match grace_period {
+            Some(secs) if secs > 0 => self.trash_log(name, &dir),
+            _ => self
+                .trash_log(name, &dir)
+                .and_then(|_| self.purge_trashed(&dir)),
+        };
+        res.map_err(|e| Error::Io(e.to_string()))
+    }
+
+    fn append(&mut self, log: &str, body: Vec<u8>, meta: Metadata) -> Result<u64, Error> {
+        let pending = self.append_pending(log, body, meta).map_err(|e| match e {
+            DbError::LogDoesNotExist => Error::LogDoesNotExist,
+            DbError::ErrWritingSegment => Error::Io(format!("{:?}", e)),
+            // Anything else is the Log refusing the message.
+            _ => Error::InvalidMessage,
+        })?;
+
+        let offset = pending.offset() as u64;
+        pending.wait().map_err(|e| Error::Io(e.to_string()))?;
+        Ok(offset)
+    }
+
+    fn read(&self, log: &str, offset: u64, count: usize) -> Result<Vec<RawMessage>, Error> {
+        match self.log(log)?.read_from(offset as usize, count) {
+            Ok(msgs) => Ok(msgs),
+            Err(DbError::MsgOffsetRetired) => Err(Error::OffsetRetired),
+            Err(e) => Err(Error::Io(format!("{:?}", e))),
+        }
+    }
+
+    fn offset_at_time(&self, log: &str, time: u64) -> Result<u64, Error> {
+        self.log(log)?
+            .offset_at_time(time)
+            .map(|offset| offset as u64)
+            .map_err(|e| Error::Io(format!("{:?}", e)))
+    }
+
+    fn stats(&self, log: &str) -> Result<LogStats, Error> {
+        let log = self.log(log)?;
+        Ok(LogStats {
+            earliest_offset: log.earliest_offset() as u64,
+            next_offset: log.len() as u64,
+            size_bytes: log.size_on_disk().map_err(|e| Error::Io(e.to_string()))?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::temp_db_path;
+
+    #[test]
+    fn test_segment_engine_through_trait() {
+        let mut engine = SegmentEngine::new(temp_db_path().into(), LogOptions::default());
+        let engine: &mut dyn StorageEngine = &mut engine;
+
+        engine.create_log("test").unwrap();
+        assert_eq!(engine.create_log("test"), Err(Error::LogAlreadyExists));
+        let meta = Metadata {
+            key: Some("k".to_string()),
+            ..Metadata::default()
+        };
+        assert_eq!(
+            engine
+                .append("test", vec![0x01], Metadata::default())
+                .unwrap(),
+            0
+        );
+        assert_eq!(
+            engine
+                .append("test", vec![0x19, 0x03, 0xE8], meta.clone())
+                .unwrap(),
+            1
+        );
+        assert_eq!(
+            engine.append("test", vec![0x1a, 0x01], Metadata::default()),
+            Err(Error::InvalidMessage)
+        );
+
+        let msgs = engine.read("test", 0, 10).unwrap();
+        assert_eq!(msgs.len(), 2);
+        assert_eq!(msgs[1].body, vec![0x19, 0x03, 0xE8]);
+        assert_eq!((msgs[1].offset, &msgs[1].meta), (1, &meta));
+        assert_eq!(
+            engine.offset_at_time("test", msgs[0].ingest_time).unwrap(),
+            0
+        );
+
+        let stats = engine.stats("test").unwrap();
+        assert_eq!((stats.earliest_offset, stats.next_offset), (0, 2));
+
+        engine.delete_log("test").unwrap();
+        assert_eq!(engine.read("test", 0, 1), Err(Error::LogDoesNotExist));
+    }
+
+    #[test]
+    fn test_segment_engine_reopens_logs() {
+        let path = PathBuf::from(temp_db_path());
+        let mut engine = SegmentEngine::open(path.clone(), LogOptions::default()).unwrap();
+        engine.create_log("test").unwrap();
+        engine
+            .append("test", vec![0x01], Metadata::default())
+            .unwrap();
+        drop(engine);
+
+        let engine = SegmentEngine::open(path, LogOptions::default()).unwrap();
+        let msgs = engine.read("test", 0, 10).unwrap();
+        assert_eq!(msgs.len(), 1);
+        assert_eq!(msgs[0].body, vec![0x01]);
+    }
+
+    #[test]
+    fn test_segment_engine_deletes_through_the_trash() {
+        let path = PathBuf::from(temp_db_path());
+        let defaults = LogOptions {
+            delete_grace_period: Some(60),
+            ..LogOptions::default()
+        };
+        let mut engine = SegmentEngine::new(path.clone(), defaults);
+        engine.create_log("kept").unwrap();
+        engine
+            .append("kept", vec![0x01], Metadata::default())
+            .unwrap();
+        engine.open_log("purged", LogOptions::default()).unwrap();
+
+        engine.delete_log("kept").unwrap();
+        engine.delete_log("purged").unwrap();
+        assert_eq!(engine.delete_log("kept"), Err(Error::LogDoesNotExist));
+        assert!(!path.join("logs").join("kept").exists());
+        assert!(!path.join("logs").join("purged").exists());
+
+        // Only the Log with a grace period is left in the trash.
+        let trashed: Vec<String> = std::fs::read_dir(path.join("trash"))
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        assert_eq!(trashed.len(), 1);
+        assert!(trashed[0].starts_with("kept."));
+    }
+}
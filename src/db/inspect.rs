@@ -0,0 +1,198 @@
+use serde_cbor::Value as CborValue;
+use std::path::PathBuf;
+use storage::Metadata;
+
+use super::logs::Segment;
+use super::manifest::Manifest;
+
+/// A DB directory opened for inspection while the server isn't running. Nothing is repaired,
+/// migrated or cleaned up, so the DB is left exactly as it was found.
+#[derive(Debug)]
+pub struct DataDir {
+    path: PathBuf,
+    manifest: Manifest,
+}
+
+/// What a Segment holds, as listed by DataDir::segments.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SegmentInfo {
+    /// Epoch of the Segment, which is also its name.
+    pub timestamp: u64,
+    pub compressed: bool,
+    /// Offset of the Segment's first message.
+    pub first_offset: usize,
+    /// Offset following the Segment's last message.
+    pub end_offset: usize,
+    /// Number of messages in the Segment, which is fewer than its range of offsets once it has
+    /// been compacted.
+    pub msgs: usize,
+    /// Ingest times of the Segment's first and last messages, in milliseconds since the Unix
+    /// Epoch. None if the Segment is empty.
+    pub time_range: Option<(u64, u64)>,
+    /// Number of bytes the Segment takes up on disk.
+    pub size_bytes: u64,
+}
+
+/// A problem found by DataDir::verify.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Problem {
+    pub log: String,
+    /// Name of the Segment the problem is in.
+    pub segment: u64,
+    pub description: String,
+}
+
+/// A message read by DataDir::messages.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+    pub offset: usize,
+    /// Ingest time in milliseconds since the Unix Epoch.
+    pub time: u64,
+    pub meta: Metadata,
+    pub body: Vec<u8>,
+}
+
+impl DataDir {
+    pub fn open(path: PathBuf) -> Result<Self, Box<dyn std::error::Error>> {
+        let manifest = Manifest::read(&path.join("manifest"))?;
+        Ok(DataDir { path, manifest })
+    }
+
+    /// The Manifest, with any Journal entries since its last checkpoint applied.
+    pub fn manifest(&self) -> CborValue {
+        serde_cbor::value::to_value(&self.manifest).expect("could not serialize manifest")
+    }
+
+    /// Names of the Logs registered in the Manifest, in order.
+    pub fn log_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.manifest.logs.keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    fn log_dir(&self, log: &str) -> PathBuf {
+        self.path.join("logs").join(log)
+    }
+
+    /// Lists the Segments of a Log, oldest first.
+    pub fn segments(&self, log: &str) -> std::io::Result<Vec<SegmentInfo>> {
+        let mut infos = vec![];
+        for segment in Segment::open_all_read_only(self.log_dir(log))? {
+            let time_range = segment
+                .time_of(segment.first_id())?
+                .map(|first| (first, segment.last_time()));
+
+            // Compaction can leave gaps, so the messages are counted rather than worked out from
+            // the range of offsets.
+            let mut msgs = 0;
+            segment.scan(|_| msgs += 1)?;
+
+            infos.push(SegmentInfo {
+                timestamp: segment.timestamp(),
+                compressed: segment.is_compressed(),
+                first_offset: segment.first_id(),
+                end_offset: segment.end_id(),
+                msgs,
+                time_range,
+                size_bytes: segment.size_on_disk()?,
+            });
+        }
+        Ok(infos)
+    }
+
+    /// Checks every Segment of a Log for corruption. See Segment::verify.
+    pub fn verify(&self, log: &str) -> std::io::Result<Vec<Problem>> {
+        let mut problems = vec![];
+        for timestamp in Segment::timestamps(&self.log_dir(log))? {
+            for description in Segment::verify(self.log_dir(log), timestamp)? {
+                problems.push(Problem {
+                    log: log.to_string(),
+                    segment: timestamp,
+                    description,
+                });
+            }
+        }
+        Ok(problems)
+    }
+
+    /// Calls `f` with each message of a Log with an offset from `start` up to but not including
+    /// `end`, in order, reading one at a time. Offsets that were deleted by retention or haven't
+    /// been written yet are skipped.
+    pub fn messages(
+        &self,
+        log: &str,
+        start: usize,
+        end: usize,
+        mut f: impl FnMut(Message),
+    ) -> std::io::Result<()> {
+        for segment in Segment::open_all_read_only(self.log_dir(log))? {
+            let from = start.max(segment.first_id());
+            let to = end.min(segment.end_id());
+            for offset in from..to {
+                let time = segment.time_of(offset)?;
+                let read = segment.read_with_meta(offset)?;
+                if let (Some(time), Some((body, meta))) = (time, read) {
+                    f(Message {
+                        offset,
+                        time,
+                        meta,
+                        body,
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::LogOptions;
+    use crate::db::DB;
+    use crate::test_util::temp_db_path;
+
+    #[test]
+    fn test_inspect_data_dir() {
+        let path = temp_db_path();
+        let db = DB::open(
+            path.clone(),
+            LogOptions {
+                max_segment_size: Some(5 + 2 * 12),
+                ..LogOptions::default()
+            },
+        );
+        db.log_add("test".into(), LogOptions::default());
+        for i in 0..3 {
+            db.msg_add("test".into(), vec![0x19, 0x03, i], Metadata::default());
+        }
+        drop(db);
+
+        let dir = DataDir::open(path.clone().into()).unwrap();
+        assert_eq!(dir.log_names(), vec!["test".to_string()]);
+
+        let segments = dir.segments("test").unwrap();
+        assert_eq!(segments.len(), 2);
+        assert_eq!((segments[1].first_offset, segments[1].msgs), (2, 1));
+        assert!(segments[0].time_range.is_some());
+
+        let mut msgs = vec![];
+        dir.messages("test", 1, 10, |msg| msgs.push(msg)).unwrap();
+        assert_eq!(msgs.len(), 2);
+        assert_eq!((msgs[0].offset, &msgs[0].body), (1, &vec![0x19, 0x03, 1]));
+
+        assert!(dir.verify("test").unwrap().is_empty());
+
+        // Flip a bit in the payload of the last message.
+        let dat = PathBuf::from(&path)
+            .join("logs/test")
+            .join(format!("{:020}.dat", segments[1].timestamp));
+        let mut bytes = std::fs::read(&dat).unwrap();
+        *bytes.last_mut().unwrap() ^= 0x01;
+        std::fs::write(&dat, bytes).unwrap();
+
+        let problems = dir.verify("test").unwrap();
+        assert_eq!(problems.len(), 1);
+        assert_eq!(problems[0].segment, segments[1].timestamp);
+    }
+}
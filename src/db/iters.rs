@@ -1,9 +1,11 @@
-use super::logs::Log;
-use crate::commands::IteratorKind;
+use crate::commands::{IngestionMode, IteratorKind};
 use crate::errors::Error;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use storage::{RawMessage, StorageEngine};
 
-#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Itr {
     pub log: String,
     pub name: String,
@@ -11,61 +13,302 @@ pub struct Itr {
     pub kind: IteratorKind,
 }
 
-impl Itr {
-    pub fn next(&self, log: &Log, offset: usize, count: usize) -> Result<Vec<Vec<u8>>, Error> {
-        let mut output: Vec<Vec<u8>> = Vec::with_capacity(count);
-        let mut error: Option<Error> = None;
+/// Most Lua states kept idle for a single Iterator. More are created when more requests run it at
+/// once, but the extra ones are dropped once they finish.
+const MAX_IDLE_STATES: usize = 4;
+
+/// Most messages a Filter Iterator reads in a single request, for each match it is asked for.
+/// This bounds how long a request runs when matches are sparse; the client carries on
+/// from the next offset.
+const FILTER_SCAN_FACTOR: usize = 100;
+
+/// A Lua state with an Iterator's function already compiled into it.
+pub struct CompiledItr {
+    lua: rlua::Lua,
+    /// Makes the function, taking `msg` and `meta` as arguments, when given the `_ENV` it sees as
+    /// its globals.
+    func: rlua::RegistryKey,
+    /// Source the function was compiled from, to tell if the Iterator has changed since.
+    source: String,
+}
 
+impl CompiledItr {
+    /// Compiles the body of an Iterator into a function of `msg` and `meta`. As when it was run as
+    /// a chunk of its own, the body can either be a single expression or a block that returns.
+    pub fn new(source: &str) -> Result<Self, Error> {
         let lua = rlua::Lua::new();
-        lua.context(|ctx| {
-            let globals = ctx.globals();
-            for i in 0..count {
-                // TODO: this will panic if count is out of bounds.
-                // Implement `get` on Log and return None if nothing exists.
-                let msg = log[offset + i].clone();
-                trace!("pulled msg from log: {:?}", msg);
-
-                let mut deserializer = serde_cbor::Deserializer::from_slice(&*msg);
-                let serializer = rlua_serde::ser::Serializer { lua: ctx };
-                let lua_msg = match serde_transcode::transcode(&mut deserializer, serializer) {
-                    Ok(msg) => msg,
-                    Err(e) => {
-                        debug!("error transcoding msgpack to lua: {:?}", e);
-                        error = Some(Error::MsgNotValidCbor);
-                        break;
-                    }
-                };
+        let func = lua.context(|ctx| {
+            let func = ctx
+                .load(&format!(
+                    "return function(_ENV) return function(msg, meta) return {}\nend end",
+                    source
+                ))
+                .eval::<rlua::Function>()
+                .or_else(|_| {
+                    ctx.load(&format!(
+                        "return function(_ENV) return function(msg, meta)\n{}\nend end",
+                        source
+                    ))
+                    .eval::<rlua::Function>()
+                })?;
+            ctx.create_registry_value(func)
+        });
+        let func = func.map_err(|e| {
+            debug!("error compiling lua: {:?}", e);
+            Error::ErrRunningLua
+        })?;
+
+        Ok(CompiledItr {
+            lua,
+            func,
+            source: source.to_string(),
+        })
+    }
+
+    /// Makes the function for a single request, with globals of its own. Globals it sets are
+    /// kept from one message to the next but not seen by later requests, whichever state they
+    /// run in. Globals it doesn't set, such as the standard library, are read from the state.
+    fn instance<'lua>(&self, ctx: rlua::Context<'lua>) -> rlua::Result<rlua::Function<'lua>> {
+        let env = ctx.create_table()?;
+        let env_meta = ctx.create_table()?;
+        env_meta.set("__index", ctx.globals())?;
+        env.set_metatable(Some(env_meta));
+
+        let make: rlua::Function = ctx.registry_value(&self.func)?;
+        make.call(env)
+    }
+}
+
+impl std::fmt::Debug for CompiledItr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CompiledItr")
+            .field("source", &self.source)
+            .finish()
+    }
+}
+
+/// Lua states for each Iterator, ready to run its function, by Iterator name.
+///
+/// Creating a Lua state and compiling an Iterator's function costs far more than running it over a
+/// message, so states are reused across requests rather than made for each one. A state is only
+/// used by one request at a time; requests running the same Iterator at once each take their own.
+#[derive(Debug, Default)]
+pub struct LuaPool {
+    states: Mutex<HashMap<String, Vec<CompiledItr>>>,
+}
+
+impl LuaPool {
+    /// Runs `f` with a Lua state for `itr`, compiling its function into a new one if none are idle.
+    pub fn with<T>(
+        &self,
+        itr: &Itr,
+        f: impl FnOnce(&CompiledItr) -> Result<T, Error>,
+    ) -> Result<T, Error> {
+        let idle = self
+            .states
+            .lock()
+            .expect("unwrapped poisoned lua pool lock")
+            .get_mut(&itr.name)
+            .and_then(|states| states.pop());
+        let compiled = match idle {
+            Some(compiled) if compiled.source == itr.func => compiled,
+            _ => CompiledItr::new(&itr.func)?,
+        };
+
+        let res = f(&compiled);
+
+        let mut states = self
+            .states
+            .lock()
+            .expect("unwrapped poisoned lua pool lock");
+        let idle = states.entry(itr.name.clone()).or_default();
+        // States for a function the Iterator no longer has are useless.
+        idle.retain(|c| c.source == compiled.source);
+        if idle.len() < MAX_IDLE_STATES {
+            idle.push(compiled);
+        }
+        res
+    }
+
+    /// Drops the Lua states of an Iterator, for when it is deleted or replaced.
+    pub fn invalidate(&self, name: &str) {
+        self.states
+            .lock()
+            .expect("unwrapped poisoned lua pool lock")
+            .remove(name);
+    }
+
+    /// Number of idle Lua states kept for an Iterator.
+    #[cfg(test)]
+    pub fn idle(&self, name: &str) -> usize {
+        self.states
+            .lock()
+            .expect("unwrapped poisoned lua pool lock")
+            .get(name)
+            .map_or(0, Vec::len)
+    }
+}
+
+impl Itr {
+    /// Runs the Iterator over up to `count` messages of its Log, starting at `offset` and stopping
+    /// before `end` if it is given. Returns the outputs along with the offset following the last
+    /// message read, which is where to resume from.
+    ///
+    /// A Filter Iterator instead outputs up to `count` of the messages its function returns a
+    /// truthy value for, reading on through the Log until it has found them all, reached the end
+    /// or read FILTER_SCAN_FACTOR messages for each one asked for.
+    ///
+    /// Messages of a Log that takes raw bytes are given to the function as a byte string rather
+    /// than decoded.
+    pub fn next(
+        &self,
+        compiled: &CompiledItr,
+        storage: &dyn StorageEngine,
+        ingestion: IngestionMode,
+        offset: u64,
+        end: Option<u64>,
+        count: usize,
+    ) -> Result<(Vec<Vec<u8>>, u64), Error> {
+        let read = |offset, count| Ok(storage.read(&self.log, offset, count)?);
+        self.next_with(compiled, read, ingestion, offset, end, count)
+    }
 
-                globals.set("msg", lua_msg).expect("could not set global");
-                let res = ctx.load(&*self.func).eval::<rlua::Value>();
-                if let Err(e) = res {
-                    debug!("error running lua: {:?} {:?}", e, msg);
-                    error = Some(Error::ErrRunningLua);
+    /// Like next, but reads each batch of messages with `read`, given the offset to start at and
+    /// the most messages to return. Nothing else is read during the call, so `read` can hold
+    /// whatever it needs to read just while it runs rather than for the whole of a long scan.
+    pub fn next_with(
+        &self,
+        compiled: &CompiledItr,
+        mut read: impl FnMut(u64, usize) -> Result<Vec<RawMessage>, Error>,
+        ingestion: IngestionMode,
+        offset: u64,
+        end: Option<u64>,
+        count: usize,
+    ) -> Result<(Vec<Vec<u8>>, u64), Error> {
+        compiled.lua.context(|ctx| {
+            let func = compiled.instance(ctx).map_err(|e| {
+                debug!("error running lua: {:?}", e);
+                Error::ErrRunningLua
+            })?;
+
+            let mut output: Vec<Vec<u8>> = Vec::with_capacity(count);
+            let mut next_offset = offset;
+            let mut budget = count.saturating_mul(FILTER_SCAN_FACTOR);
+            while budget > 0 {
+                let mut msgs = read(next_offset, count.min(budget))?;
+                if let Some(end) = end {
+                    msgs.retain(|m| m.offset < end);
+                }
+                if msgs.is_empty() {
                     break;
-                };
-
-                let mut buf: Vec<u8> = vec![];
-                let value = res.expect("couldnt unwrap response from eval");
-                let deserializer = rlua_serde::de::Deserializer {
-                    value: value.clone(),
-                };
-                let mut serializer = serde_cbor::Serializer::new(&mut buf);
-                match serde_transcode::transcode(deserializer, &mut serializer) {
-                    Ok(_ok) => (),
-                    Err(e) => {
-                        debug!("error transcoding lua to msgpack: {:?} {:?}", e, value);
-                        error = Some(Error::ErrReadingLuaResponse);
-                    }
-                };
+                }
 
-                output.push(buf);
+                next_offset = self.run(ctx, &func, ingestion, &msgs, count, &mut output)?;
+                budget -= msgs.len();
+                if self.kind != IteratorKind::Filter || output.len() >= count {
+                    break;
+                }
             }
-        });
 
-        if let Some(e) = error {
-            return Err(e);
+            Ok((output, next_offset))
+        })
+    }
+
+    /// Runs the function over `msgs` and adds what they output to `output`, stopping once it holds
+    /// `count`. Returns the offset following the last message run.
+    fn run<'lua>(
+        &self,
+        ctx: rlua::Context<'lua>,
+        func: &rlua::Function<'lua>,
+        ingestion: IngestionMode,
+        msgs: &[RawMessage],
+        count: usize,
+        output: &mut Vec<Vec<u8>>,
+    ) -> Result<u64, Error> {
+        let mut next_offset = 0;
+        for raw in msgs.iter() {
+            trace!("pulled msg from log: {:?}", raw.body);
+            let value = call(ctx, func, ingestion, raw)?;
+            next_offset = raw.offset + 1;
+
+            match self.kind {
+                IteratorKind::Filter => {
+                    if let rlua::Value::Nil | rlua::Value::Boolean(false) = value {
+                        continue;
+                    }
+                    output.push(encode_body(ingestion, raw.body.clone()));
+                    if output.len() >= count {
+                        break;
+                    }
+                }
+                IteratorKind::Map | IteratorKind::Reduce => {
+                    let mut buf: Vec<u8> = vec![];
+                    let deserializer = rlua_serde::de::Deserializer {
+                        value: value.clone(),
+                    };
+                    let mut serializer = serde_cbor::Serializer::new(&mut buf);
+                    if let Err(e) = serde_transcode::transcode(deserializer, &mut serializer) {
+                        debug!("error transcoding lua to msgpack: {:?} {:?}", e, value);
+                        return Err(Error::ErrReadingLuaResponse);
+                    }
+                    output.push(buf);
+                }
+            }
         }
+        Ok(next_offset)
+    }
+}
 
-        Ok(output)
+/// Encodes a message as it was added for a response. Messages of a Log that takes raw bytes
+/// become CBOR byte strings.
+pub fn encode_body(ingestion: IngestionMode, body: Vec<u8>) -> Vec<u8> {
+    match ingestion {
+        IngestionMode::Cbor => body,
+        IngestionMode::Raw => {
+            serde_cbor::to_vec(&serde_cbor::Value::Bytes(body)).expect("could not serialize msg")
+        }
     }
 }
+
+/// Calls an Iterator's function with a message and its metadata.
+fn call<'lua>(
+    ctx: rlua::Context<'lua>,
+    func: &rlua::Function<'lua>,
+    ingestion: IngestionMode,
+    raw: &RawMessage,
+) -> Result<rlua::Value<'lua>, Error> {
+    let msg = &raw.body;
+    let lua_msg = match ingestion {
+        IngestionMode::Raw => ctx
+            .create_string(msg)
+            .map(rlua::Value::String)
+            .map_err(|e| e.to_string()),
+        IngestionMode::Cbor => {
+            let mut deserializer = serde_cbor::Deserializer::from_slice(msg);
+            let serializer = rlua_serde::ser::Serializer { lua: ctx };
+            serde_transcode::transcode(&mut deserializer, serializer).map_err(|e| e.to_string())
+        }
+    };
+    let lua_msg = lua_msg.map_err(|e| {
+        debug!("error transcoding msgpack to lua: {:?}", e);
+        Error::MsgNotValidCbor
+    })?;
+
+    let meta = meta_table(ctx, raw).expect("could not build meta table");
+    func.call::<_, rlua::Value>((lua_msg, meta)).map_err(|e| {
+        debug!("error running lua: {:?} {:?}", e, msg);
+        Error::ErrRunningLua
+    })
+}
+
+/// Builds the `meta` table iterator functions see alongside `msg`, holding the message's offset,
+/// ingest time in milliseconds, and the key and headers it was added with. `meta.key` is nil if it
+/// had no key.
+fn meta_table<'lua>(ctx: rlua::Context<'lua>, msg: &RawMessage) -> rlua::Result<rlua::Table<'lua>> {
+    let meta = ctx.create_table()?;
+    meta.set("offset", msg.offset)?;
+    meta.set("ts", msg.ingest_time)?;
+    meta.set("key", msg.meta.key.clone())?;
+    meta.set("headers", ctx.create_table_from(msg.meta.headers.clone())?)?;
+    Ok(meta)
+}
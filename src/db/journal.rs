@@ -0,0 +1,360 @@
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::prelude::*;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+
+use super::iters::Itr;
+use super::logs::crc;
+use super::manifest::LogRegistrant;
+use crate::commands::LogOptions;
+
+/// A single change to the Manifest.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ManifestEdit {
+    AddLog(LogRegistrant),
+    /// Removes the Log along with every Iterator on it.
+    DelLog {
+        name: String,
+    },
+    AddItr(Itr),
+    DelItr {
+        log: String,
+        name: String,
+    },
+    /// Moves the Log and its Iterators to the deleted Logs, where they can be undeleted from
+    /// until `purge_at`. `dir` is where its data was moved to in the trash directory.
+    SoftDelLog {
+        name: String,
+        dir: String,
+        deleted_at: u64,
+        purge_at: u64,
+    },
+    UndelLog {
+        name: String,
+    },
+    /// Forgets a deleted Log for good.
+    PurgeLog {
+        name: String,
+    },
+    /// Replaces the options of the Log.
+    AlterLog {
+        name: String,
+        options: LogOptions,
+    },
+}
+
+/// A ManifestEdit as it is recorded in the Journal.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct JournalEntry {
+    /// Position of the edit in the Manifest's history, starting from 1.
+    pub seq: u64,
+    /// When the edit was made, in seconds since the Unix Epoch.
+    pub at: u64,
+    pub edit: ManifestEdit,
+}
+
+// Format:
+//
+// The Journal is a list of entries, each containing:
+//   - A 32bit integer representing the length of the payload.
+//   - A 32bit CRC of the payload.
+//   - The CBOR encoded JournalEntry.
+//
+// Entries are only ever appended. When the Manifest is checkpointed the Journal is renamed to
+// `<name>.<seq>`, where seq is that of its last entry, and a new one is started. The renamed
+// Journals are kept as the history of every change made to the Manifest.
+
+/// The append-only log of edits made to the Manifest since it was last checkpointed.
+#[derive(Debug)]
+pub struct Journal {
+    file: File,
+    path: PathBuf,
+
+    /// Number of entries in the file.
+    len: u64,
+}
+
+impl Journal {
+    const ENTRY_HEADER_LEN: u64 = 8;
+
+    /// Creates an empty Journal at `path`, replacing any that is already there.
+    pub fn create(path: PathBuf) -> std::io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&path)?;
+        file.set_len(0)?;
+        file.sync_all()?;
+
+        Ok(Journal { file, path, len: 0 })
+    }
+
+    /// Opens the Journal at `path`, creating it if it doesn't exist, and returns it along with its
+    /// entries. An entry left half written by a crash is removed.
+    pub fn open(path: PathBuf) -> std::io::Result<(Self, Vec<JournalEntry>)> {
+        let file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&path)?;
+
+        let (entries, valid_len) = read_entries(&file)?;
+        let file_len = file.metadata()?.len();
+        if valid_len < file_len {
+            warn!(
+                "truncating {} bytes of incomplete entry from manifest journal {:?}",
+                file_len - valid_len,
+                path
+            );
+            file.set_len(valid_len)?;
+            file.sync_all()?;
+        }
+
+        let journal = Journal {
+            file,
+            path,
+            len: entries.len() as u64,
+        };
+        Ok((journal, entries))
+    }
+
+    /// Number of entries in the Journal.
+    pub fn entry_count(&self) -> u64 {
+        self.len
+    }
+
+    /// Appends an entry and syncs it to disk.
+    pub fn append(&mut self, entry: &JournalEntry) -> std::io::Result<()> {
+        let payload = serde_cbor::to_vec(entry).expect("could not serialize journal entry");
+
+        let mut buf = Vec::with_capacity(Self::ENTRY_HEADER_LEN as usize + payload.len());
+        buf.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        buf.extend_from_slice(&crc::checksum(&payload).to_le_bytes());
+        buf.extend_from_slice(&payload);
+
+        self.file.write_all(&buf)?;
+        self.file.sync_data()?;
+        self.len += 1;
+        Ok(())
+    }
+
+    /// Archives the Journal, whose last entry has `last_seq`, and starts a new empty one. This
+    /// must only be done once the Manifest has been checkpointed at `last_seq`.
+    pub fn rotate(&mut self, last_seq: u64) -> std::io::Result<()> {
+        std::fs::rename(&self.path, archive_path(&self.path, last_seq))?;
+        *self = Self::create(self.path.clone())?;
+
+        if let Some(dir) = self.path.parent() {
+            File::open(dir)?.sync_all()?;
+        }
+        Ok(())
+    }
+}
+
+fn archive_path(path: &Path, last_seq: u64) -> PathBuf {
+    let mut archive = path.as_os_str().to_owned();
+    archive.push(format!(".{:020}", last_seq));
+    PathBuf::from(archive)
+}
+
+/// Reads every complete entry in the file, returning them and the number of bytes they take up.
+///
+/// Only the last entry can be left half written by a crash, so a bad checksum anywhere else, or an
+/// entry that is intact but can't be decoded, e.g. because it was written by a newer version, is an
+/// error rather than something to truncate.
+fn read_entries(file: &File) -> std::io::Result<(Vec<JournalEntry>, u64)> {
+    let file_len = file.metadata()?.len();
+    let mut entries = vec![];
+    let mut position = 0;
+
+    while position + Journal::ENTRY_HEADER_LEN <= file_len {
+        let mut header = [0u8; Journal::ENTRY_HEADER_LEN as usize];
+        file.read_exact_at(&mut header, position)?;
+        let len = u32::from_le_bytes([header[0], header[1], header[2], header[3]]) as u64;
+        let expected = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+
+        let end = position + Journal::ENTRY_HEADER_LEN + len;
+        if end > file_len {
+            break;
+        }
+
+        let mut payload = vec![0u8; len as usize];
+        file.read_exact_at(&mut payload, position + Journal::ENTRY_HEADER_LEN)?;
+        if crc::checksum(&payload) != expected {
+            if end == file_len {
+                break;
+            }
+            return Err(invalid_data(format!(
+                "journal entry at byte {} is corrupt",
+                position
+            )));
+        }
+
+        match serde_cbor::from_slice(&payload) {
+            Ok(entry) => entries.push(entry),
+            Err(e) => {
+                return Err(invalid_data(format!(
+                    "could not decode journal entry at byte {}: {}",
+                    position, e
+                )))
+            }
+        }
+        position = end;
+    }
+
+    Ok((entries, position))
+}
+
+fn invalid_data(msg: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, msg)
+}
+
+/// Reads the entries of the Journal at `path` without changing it, for inspecting a DB that isn't
+/// running. An entry left half written by a crash is left out.
+pub fn read(path: &Path) -> std::io::Result<Vec<JournalEntry>> {
+    if !path.exists() {
+        return Ok(vec![]);
+    }
+    Ok(read_entries(&File::open(path)?)?.0)
+}
+
+/// Reads every edit ever made to the Manifest whose Journal is at `path`, oldest first, from the
+/// archived Journals followed by the current one.
+pub fn history(path: &Path) -> std::io::Result<Vec<JournalEntry>> {
+    let dir = path.parent().unwrap_or_else(|| Path::new("."));
+    let prefix = format!(
+        "{}.",
+        path.file_name()
+            .and_then(|name| name.to_str())
+            .expect("journal path has no file name")
+    );
+
+    let mut archives: Vec<PathBuf> = vec![];
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let is_archive = matches!(name.to_str(),
+            Some(name) if name.starts_with(&prefix) && name[prefix.len()..].parse::<u64>().is_ok());
+        if is_archive {
+            archives.push(entry.path());
+        }
+    }
+    // Archives are named by a zero padded seq, so sorting them by name sorts them by age.
+    archives.sort();
+
+    let mut entries = vec![];
+    for archive in archives.iter().map(|p| p.as_path()).chain(Some(path)) {
+        if archive.exists() {
+            entries.extend(read_entries(&File::open(archive)?)?.0);
+        }
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::LogOptions;
+    use crate::test_util::temp_db_path;
+
+    fn del_log(seq: u64) -> JournalEntry {
+        JournalEntry {
+            seq,
+            at: 0,
+            edit: ManifestEdit::DelLog {
+                name: format!("log{}", seq),
+            },
+        }
+    }
+
+    #[test]
+    fn test_journal_entries_survive_reopen() {
+        let path = PathBuf::from(temp_db_path()).join("manifest.journal");
+        let (mut journal, entries) = Journal::open(path.clone()).unwrap();
+        assert!(entries.is_empty());
+
+        let add = JournalEntry {
+            seq: 1,
+            at: 10,
+            edit: ManifestEdit::AddLog(LogRegistrant {
+                name: "test".into(),
+                created_at: 10,
+                options: LogOptions::default(),
+            }),
+        };
+        journal.append(&add).unwrap();
+        journal.append(&del_log(2)).unwrap();
+
+        let (journal, entries) = Journal::open(path).unwrap();
+        assert_eq!(journal.entry_count(), 2);
+        assert_eq!(entries, vec![add, del_log(2)]);
+    }
+
+    #[test]
+    fn test_journal_truncates_torn_entry() {
+        let path = PathBuf::from(temp_db_path()).join("manifest.journal");
+        let (mut journal, _) = Journal::open(path.clone()).unwrap();
+        journal.append(&del_log(1)).unwrap();
+        let len = std::fs::metadata(&path).unwrap().len();
+        journal.append(&del_log(2)).unwrap();
+        journal.file.set_len(len + 5).unwrap();
+
+        let (mut journal, entries) = Journal::open(path.clone()).unwrap();
+        assert_eq!(entries, vec![del_log(1)]);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), len);
+
+        journal.append(&del_log(2)).unwrap();
+        assert_eq!(Journal::open(path).unwrap().1.len(), 2);
+    }
+
+    #[test]
+    fn test_journal_refuses_entries_it_cannot_decode() {
+        let path = PathBuf::from(temp_db_path()).join("manifest.journal");
+        let (mut journal, _) = Journal::open(path.clone()).unwrap();
+        journal.append(&del_log(1)).unwrap();
+        let len = std::fs::metadata(&path).unwrap().len();
+
+        // An intact entry, as a newer version with an edit this one doesn't know might write.
+        let payload = serde_cbor::to_vec(&serde_cbor::Value::Text("new_edit".into())).unwrap();
+        let mut entry = (payload.len() as u32).to_le_bytes().to_vec();
+        entry.extend_from_slice(&crc::checksum(&payload).to_le_bytes());
+        entry.extend_from_slice(&payload);
+        journal.file.write_all(&entry).unwrap();
+        journal.append(&del_log(3)).unwrap();
+        let full_len = std::fs::metadata(&path).unwrap().len();
+
+        let err = Journal::open(path.clone()).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), full_len);
+
+        // A bad checksum before the last entry isn't a torn write either.
+        let file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.write_all_at(&[0xFF], len + 8).unwrap();
+        let err = Journal::open(path.clone()).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), full_len);
+    }
+
+    #[test]
+    fn test_journal_history_spans_rotations() {
+        let path = PathBuf::from(temp_db_path()).join("manifest.journal");
+        let (mut journal, _) = Journal::open(path.clone()).unwrap();
+        journal.append(&del_log(1)).unwrap();
+        journal.append(&del_log(2)).unwrap();
+        journal.rotate(2).unwrap();
+        assert_eq!(journal.entry_count(), 0);
+        journal.append(&del_log(3)).unwrap();
+        journal.rotate(3).unwrap();
+        journal.append(&del_log(4)).unwrap();
+
+        assert!(archive_path(&path, 2).exists());
+        assert_eq!(Journal::open(path.clone()).unwrap().1, vec![del_log(4)]);
+        assert_eq!(
+            history(&path).unwrap(),
+            vec![del_log(1), del_log(2), del_log(3), del_log(4)]
+        );
+    }
+}
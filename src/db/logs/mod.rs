@@ -1,50 +1,739 @@
+use crate::commands::{CleanupPolicy, Compression, Durability, IngestionMode, LogOptions};
 use crate::errors::Error;
-use segment::Segment;
+use producers::Producers;
+pub use segment::RepairReport;
+pub(super) use segment::Segment;
+use segment::{PendingCompression, SegmentSnapshot};
+use serde::Serialize;
 use serde_cbor::{Error as CborError, Value as CborValue};
-use std::ops::Index;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::path::PathBuf;
+use std::sync::Arc;
+use storage::{Metadata, RawMessage};
+use sync::Syncer;
 
+pub mod crc;
+mod producers;
 mod segment;
+mod sync;
+
+pub use segment::now_millis;
+
+/// Number of seconds a tombstone is kept for in a compacted Log, unless the Log says otherwise.
+pub const DEFAULT_TOMBSTONE_GRACE_PERIOD: u64 = 24 * 60 * 60;
+
+/// The options a Log has when neither it nor the server sets them. Options that are off or have
+/// no limit unless they are set are left unset.
+pub fn builtin_options() -> LogOptions {
+    LogOptions {
+        max_segment_size: Some(segment::DEFAULT_MAX_SEGMENT_SIZE),
+        max_segment_age: Some(segment::DEFAULT_MAX_SEGMENT_AGE),
+        compression: Some(Compression::None),
+        durability: Some(Durability::default()),
+        cleanup_policy: Some(CleanupPolicy::default()),
+        tombstone_grace_period: Some(DEFAULT_TOMBSTONE_GRACE_PERIOD),
+        ingestion: Some(IngestionMode::default()),
+        ..LogOptions::default()
+    }
+}
+
+/// Number of seconds the append rate of a Log is measured over.
+const APPEND_RATE_WINDOW: u64 = 60;
+
+/// The body of a tombstone, a CBOR null.
+const TOMBSTONE: &[u8] = &[0xF6];
 
 #[derive(Debug)]
 pub struct Log {
     path: PathBuf,
+    options: LogOptions,
 
+    /// Segments that have been rolled over and are only read from, ordered from oldest to newest.
+    sealed_segments: Vec<Segment>,
     /// The Segment that is currently being written to.
     active_segment: Segment,
-    data: Vec<Vec<u8>>,
+
+    /// What was repaired in the active Segment when the Log was opened, if anything.
+    repair_report: Option<RepairReport>,
+
+    /// The newest message each idempotent producer has added.
+    producers: Producers,
+
+    /// Messages added to the Log recently, for reporting how fast it is growing.
+    appends: AppendRate,
+
+    /// Tracks which writes to the active Segment have been fsynced.
+    syncer: Arc<Syncer>,
+}
+
+/// A message that has been written to a Log, but that may not be on disk yet.
+#[derive(Debug)]
+pub struct PendingSync {
+    syncer: Arc<Syncer>,
+    ticket: u64,
+    durability: Durability,
+    offset: usize,
+    duplicate: bool,
+}
+
+impl PendingSync {
+    /// Blocks until the message is as durable as its Log's durability setting requires. The Log
+    /// doesn't need to be borrowed while waiting, so other messages can be added in the meantime
+    /// and synced along with this one.
+    pub fn wait(self) -> std::io::Result<()> {
+        self.syncer.wait(self.ticket, self.durability)
+    }
+
+    /// Offset of the message in the Log.
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// Whether the message had already been added by its producer, in which case it wasn't added
+    /// again and `offset` is where it was added the first time.
+    pub fn is_duplicate(&self) -> bool {
+        self.duplicate
+    }
+}
+
+/// What a Log holds, as reported by log_show.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct LogSummary {
+    /// Number of messages in the Log, which is fewer than its range of offsets once it has been
+    /// compacted.
+    pub message_count: u64,
+    /// Offsets of the oldest and newest messages, or None if the Log has none.
+    pub first_offset: Option<u64>,
+    pub last_offset: Option<u64>,
+    /// Offset the next message added will have.
+    pub next_offset: u64,
+    /// Number of bytes the Log's Segments take up on disk.
+    pub size_bytes: u64,
+    pub segment_count: u64,
+    /// Ingest times of the oldest and newest messages, in milliseconds since the Unix Epoch.
+    pub earliest_timestamp: Option<u64>,
+    pub latest_timestamp: Option<u64>,
+    /// Messages added per second over the last minute, since the Log was opened.
+    pub append_rate: f64,
+}
+
+/// Counts the messages added to a Log in each of the last APPEND_RATE_WINDOW seconds.
+#[derive(Debug, Default)]
+struct AppendRate {
+    /// Number of messages added in each second, in seconds since the Unix Epoch, oldest first.
+    /// Seconds without any are left out.
+    seconds: VecDeque<(u64, u64)>,
+}
+
+impl AppendRate {
+    fn record(&mut self, now: u64) {
+        match self.seconds.back_mut() {
+            Some((second, count)) if *second == now => *count += 1,
+            _ => self.seconds.push_back((now, 1)),
+        }
+        while matches!(self.seconds.front(), Some((second, _)) if second + APPEND_RATE_WINDOW <= now)
+        {
+            self.seconds.pop_front();
+        }
+    }
+
+    /// Messages added per second over the window ending at `now`.
+    fn per_second(&self, now: u64) -> f64 {
+        let added: u64 = self
+            .seconds
+            .iter()
+            .filter(|(second, _)| second + APPEND_RATE_WINDOW > now)
+            .map(|(_, count)| count)
+            .sum();
+        added as f64 / APPEND_RATE_WINDOW as f64
+    }
+}
+
+/// A Log's files at a point in time, taken by Log::snapshot.
+#[derive(Debug)]
+pub struct LogSnapshot {
+    /// Offset of the first message that isn't part of the snapshot.
+    pub next_offset: u64,
+    /// Files of the sealed Segments.
+    pub sealed: Vec<PathBuf>,
+    pub active: SegmentSnapshot,
+}
+
+/// A compaction started by Log::start_compaction, holding its own handles to the Log's Segments.
+#[derive(Debug)]
+pub struct PendingCompaction {
+    /// The sealed Segments followed by the active one.
+    segments: Vec<Segment>,
+    now: u64,
+    grace: u64,
+    /// For each sealed Segment, the ids of the messages to remove and whether that is all of them.
+    removals: Vec<(Segment, HashSet<usize>, bool)>,
+}
+
+impl PendingCompaction {
+    /// Reads the Log to find the messages to remove. Messages added after compaction was started
+    /// aren't seen, so they only get to replace older ones in the next compaction.
+    pub fn scan(&mut self) -> std::io::Result<()> {
+        let mut newest: HashMap<String, u64> = HashMap::new();
+        for segment in self.segments.iter() {
+            segment.scan(|msg| {
+                if let Some(key) = msg.meta.key {
+                    newest.insert(key, msg.offset);
+                }
+            })?;
+        }
+
+        let (now, grace) = (self.now, self.grace);
+        let active = self.segments.pop();
+        for segment in self.segments.drain(..) {
+            let mut remove = HashSet::new();
+            let mut msgs = 0;
+            segment.scan(|msg| {
+                msgs += 1;
+                if let Some(key) = &msg.meta.key {
+                    let superseded = newest[key] != msg.offset;
+                    let expired =
+                        msg.body == TOMBSTONE && now.saturating_sub(msg.ingest_time) >= grace;
+                    if superseded || expired {
+                        remove.insert(msg.offset as usize);
+                    }
+                }
+            })?;
+            let empty = remove.len() == msgs;
+            self.removals.push((segment, remove, empty));
+        }
+        self.segments.extend(active);
+        Ok(())
+    }
 }
 
 impl Log {
-    pub fn new(mut path: PathBuf, name: &str) -> Self {
+    /// Opens the Log, creating it if it does not exist yet. `options` should already have the
+    /// server-wide defaults filled in.
+    ///
+    /// Anything left half written at the end of the Log by a crash is removed before it is opened.
+    pub fn new(mut path: PathBuf, name: &str, options: LogOptions) -> std::io::Result<Self> {
         path.push("logs");
         path.push(name);
 
-        std::fs::create_dir_all(&path).expect("could not create log directory");
+        std::fs::create_dir_all(&path)?;
 
-        let active_segment = Segment::get_active_for(path.clone());
-        Log {
+        let repair_report = Segment::repair_active(path.clone())?;
+        if let Some(report) = &repair_report {
+            warn!("repaired torn write at end of {:?}: {:?}", path, report);
+        }
+
+        let mut sealed_segments = Segment::get_all_for(path.clone())?;
+        let active_segment = sealed_segments.pop().expect("log has no active segment");
+        let syncer = Arc::new(Syncer::new(active_segment.file_handles()?));
+
+        let segments: Vec<&Segment> = sealed_segments
+            .iter()
+            .chain(Some(&active_segment))
+            .collect();
+        let producers = Producers::recover(&path, &segments)?;
+
+        Ok(Log {
             path,
+            options,
+            sealed_segments,
             active_segment,
-            data: vec![],
+            repair_report,
+            producers,
+            appends: AppendRate::default(),
+            syncer,
+        })
+    }
+
+    /// The Log's options, with the server-wide defaults filled in.
+    pub fn options(&self) -> &LogOptions {
+        &self.options
+    }
+
+    /// Replaces the Log's options, which should already have the server-wide defaults filled in.
+    /// They apply from the next message added or Segment sealed on, so existing Segments aren't
+    /// rewritten to match.
+    pub fn set_options(&mut self, options: LogOptions) {
+        self.options = options;
+    }
+
+    /// What was repaired when the Log was opened, or None if it was consistent.
+    pub fn repair_report(&self) -> Option<&RepairReport> {
+        self.repair_report.as_ref()
+    }
+
+    /// Number of messages in the Log.
+    pub fn len(&self) -> usize {
+        self.active_segment.end_id()
+    }
+
+    /// Offset of the oldest message that hasn't been deleted by retention.
+    pub fn earliest_offset(&self) -> usize {
+        self.first_segment().first_id()
+    }
+
+    /// Reads up to `count` messages starting at `offset`. Offsets that were removed by compaction
+    /// are skipped, so the offsets of the messages read can have gaps.
+    pub fn read_from(&self, offset: usize, count: usize) -> Result<Vec<RawMessage>, Error> {
+        if offset < self.earliest_offset() {
+            return Err(Error::MsgOffsetRetired);
+        }
+
+        let mut msgs = Vec::with_capacity(count.min(self.len().saturating_sub(offset)));
+        let mut next = offset;
+        let segments = self
+            .sealed_segments
+            .iter()
+            .chain(std::iter::once(&self.active_segment))
+            .filter(|s| s.end_id() > offset);
+        for segment in segments {
+            while msgs.len() < count {
+                let msg = segment.read_from(next).map_err(|e| {
+                    error!("could not read msg {} from {:?}: {}", next, self.path, e);
+                    Error::ErrReadingSegment
+                })?;
+                match msg {
+                    Some(msg) => {
+                        next = msg.offset as usize + 1;
+                        msgs.push(msg);
+                    }
+                    None => break,
+                }
+            }
         }
+        Ok(msgs)
     }
 
-    pub fn add_msg(&mut self, msg: Vec<u8>) -> Result<(), Error> {
-        let res: Result<CborValue, CborError> = serde_cbor::from_reader(&mut &*msg);
-        if res.is_err() {
-            return Err(Error::MsgNotValidCbor);
+    /// Returns the offset of the first message ingested at or after `time`, in milliseconds since
+    /// the Unix Epoch. If every message is older than that, the Log's length is returned.
+    pub fn offset_at_time(&self, time: u64) -> Result<usize, Error> {
+        // Segments are named after the time they were started, so the message belongs to the
+        // last one started at or before `time`.
+        let segment = if self.active_segment.timestamp() <= time {
+            &self.active_segment
+        } else {
+            let i = self
+                .sealed_segments
+                .partition_point(|s| s.timestamp() <= time);
+            match i {
+                // Every message is newer than `time`.
+                0 => return Ok(self.earliest_offset()),
+                _ => &self.sealed_segments[i - 1],
+            }
+        };
+
+        segment.id_at_time(time).map_err(|e| {
+            error!("could not search {:?} by time: {}", self.path, e);
+            Error::ErrReadingSegment
+        })
+    }
+
+    fn first_segment(&self) -> &Segment {
+        self.sealed_segments.first().unwrap_or(&self.active_segment)
+    }
+
+    /// Summarises what the Log holds. Only the first and last messages of each Segment are read.
+    pub fn summary(&self) -> Result<LogSummary, Error> {
+        let read_error = |e: std::io::Error| {
+            error!("could not summarise {:?}: {}", self.path, e);
+            Error::ErrReadingSegment
+        };
+        let segments = || {
+            self.sealed_segments
+                .iter()
+                .chain(std::iter::once(&self.active_segment))
+        };
+
+        let mut message_count = 0;
+        for segment in segments() {
+            message_count += segment.msg_count().map_err(read_error)?;
+        }
+        let first = self.read_from(self.earliest_offset(), 1)?.pop();
+        let mut last = None;
+        for segment in segments().rev() {
+            last = segment.last_message().map_err(read_error)?;
+            if last.is_some() {
+                break;
+            }
+        }
+
+        Ok(LogSummary {
+            message_count: message_count as u64,
+            first_offset: first.as_ref().map(|m| m.offset),
+            last_offset: last.as_ref().map(|m| m.offset),
+            next_offset: self.len() as u64,
+            size_bytes: self.size_on_disk().map_err(read_error)?,
+            segment_count: self.segment_count() as u64,
+            earliest_timestamp: first.map(|m| m.ingest_time),
+            latest_timestamp: last.map(|m| m.ingest_time),
+            append_rate: self.appends.per_second(now_millis() / 1000),
+        })
+    }
+
+    /// Number of Segments the Log's messages are stored in.
+    pub fn segment_count(&self) -> usize {
+        self.sealed_segments.len() + 1
+    }
+
+    /// Number of bytes the Log's Segments take up on disk.
+    pub fn size_on_disk(&self) -> std::io::Result<u64> {
+        let mut size = self.active_segment.size_on_disk()?;
+        for segment in self.sealed_segments.iter() {
+            size += segment.size_on_disk()?;
+        }
+        Ok(size)
+    }
+
+    /// Captures the Log as it is now for a backup. Sealed Segments are never written to again, only
+    /// replaced by compaction, so only their paths are needed, while the active Segment is
+    /// captured up to its current end.
+    pub fn snapshot(&self) -> std::io::Result<LogSnapshot> {
+        let sealed = self
+            .sealed_segments
+            .iter()
+            .flat_map(|s| {
+                let (dat_path, idx_path) = s.file_paths(self.path.clone());
+                vec![dat_path, idx_path]
+            })
+            .collect();
+
+        Ok(LogSnapshot {
+            next_offset: self.len() as u64,
+            sealed,
+            active: self.active_segment.snapshot()?,
+        })
+    }
+
+    /// Deletes the oldest sealed Segments while they are past any of the Log's retention limits,
+    /// returning how many were deleted. The active Segment is never deleted.
+    pub fn apply_retention(&mut self) -> std::io::Result<usize> {
+        let now = now_millis();
+        let mut size = self.size_on_disk()?;
+        let mut deleted = 0;
+
+        // An empty active Segment doesn't know its first offset once the Log is reopened, so the
+        // newest sealed Segment is kept around to recover it from.
+        let keep = if self.active_segment.is_empty() { 1 } else { 0 };
+
+        while self.sealed_segments.len() > keep {
+            let oldest = &self.sealed_segments[0];
+            let oldest_size = oldest.size_on_disk()?;
+            let remaining_msgs = (self.len() - oldest.end_id()) as u64;
+
+            // Size and message limits only delete a Segment if the rest of the Log still meets
+            // them, so at least that much is always kept.
+            let expired = matches!(self.options.retention_max_age,
+                Some(age) if now.saturating_sub(oldest.last_time()) > age.saturating_mul(1000));
+            let too_big = matches!(self.options.retention_max_size,
+                Some(max) if size - oldest_size >= max);
+            let too_many = matches!(self.options.retention_max_messages,
+                Some(max) if remaining_msgs >= max);
+
+            if !(expired || too_big || too_many) {
+                break;
+            }
+
+            let oldest = self.sealed_segments.remove(0);
+            debug!("deleting segment {:?} in {:?}", oldest, self.path);
+            oldest.delete(self.path.clone())?;
+            size -= oldest_size;
+            deleted += 1;
+        }
+
+        Ok(deleted)
+    }
+
+    /// Prepares to compress the sealed Segments that aren't compressed yet, if the Log is set to
+    /// compress them. The compressed copies are written by PendingCompression::write, which can be
+    /// done without holding the Log, and swapped in by finish_compression.
+    pub fn start_compression(&self) -> std::io::Result<Vec<PendingCompression>> {
+        let compression = self.options.compression.unwrap_or(Compression::None);
+        let mut pending = vec![];
+        for segment in self.sealed_segments.iter() {
+            if let Some(p) = segment.start_compression(self.path.clone(), compression)? {
+                pending.push(p);
+            }
+        }
+        Ok(pending)
+    }
+
+    /// Swaps a compressed copy written since start_compression in for its Segment, or discards it
+    /// if the Segment has since been deleted.
+    pub fn finish_compression(&mut self, pending: PendingCompression) -> std::io::Result<()> {
+        match self
+            .sealed_segments
+            .iter_mut()
+            .find(|s| s.timestamp() == pending.timestamp())
+        {
+            Some(segment) => segment.finish_compression(pending),
+            None => pending.discard(),
+        }
+    }
+
+    /// Compresses the sealed Segments that aren't compressed yet, all while holding the Log.
+    #[cfg(test)]
+    pub fn compress(&mut self) -> std::io::Result<()> {
+        for pending in self.start_compression()? {
+            pending.write()?;
+            self.finish_compression(pending)?;
         }
-        self.data.push(msg);
         Ok(())
     }
-}
 
-impl Index<usize> for Log {
-    type Output = Vec<u8>;
+    /// Prepares to compact the Log, if its cleanup policy is to compact. Compaction removes messages
+    /// from the sealed Segments that have a newer message with the same key, along with tombstones
+    /// older than the Log's tombstone grace period. Messages without a key are always kept, as is
+    /// everything in the active Segment.
+    ///
+    /// Finding those messages reads the whole Log, so it is done by PendingCompaction::scan
+    /// without holding the Log. finish_compaction then removes them.
+    pub fn start_compaction(&self) -> std::io::Result<Option<PendingCompaction>> {
+        if self.options.cleanup_policy != Some(CleanupPolicy::Compact)
+            || self.sealed_segments.is_empty()
+        {
+            return Ok(None);
+        }
+
+        let mut segments = Vec::with_capacity(self.sealed_segments.len() + 1);
+        for segment in self
+            .sealed_segments
+            .iter()
+            .chain(std::iter::once(&self.active_segment))
+        {
+            segments.push(segment.reopen(self.path.clone())?);
+        }
+        let grace = self
+            .options
+            .tombstone_grace_period
+            .unwrap_or(DEFAULT_TOMBSTONE_GRACE_PERIOD)
+            .saturating_mul(1000);
+
+        Ok(Some(PendingCompaction {
+            segments,
+            now: now_millis(),
+            grace,
+            removals: vec![],
+        }))
+    }
+
+    /// Removes the messages found by `pending` from the sealed Segments, returning how many were
+    /// removed. Segments that were deleted, rewritten or compressed since compaction was started
+    /// are left alone until the next compaction.
+    pub fn finish_compaction(&mut self, pending: PendingCompaction) -> std::io::Result<usize> {
+        let mut removed = 0;
+        let mut emptied = vec![];
+        for (scanned, remove, empty) in pending.removals {
+            let segment = match self
+                .sealed_segments
+                .iter_mut()
+                .find(|s| s.timestamp() == scanned.timestamp())
+            {
+                Some(segment) if segment.same_files(&scanned)? => segment,
+                _ => continue,
+            };
+            if empty {
+                emptied.push(segment.timestamp());
+            }
+            if remove.is_empty() {
+                continue;
+            }
+
+            debug!(
+                "compacting {} msgs out of segment {} in {:?}",
+                remove.len(),
+                segment.timestamp(),
+                self.path
+            );
+            segment.rewrite(self.path.clone(), &remove)?;
+            removed += remove.len();
+        }
+
+        // Segments left with no messages are deleted, other than the oldest, which holds the
+        // Log's earliest offset, and the newest, which an empty active Segment finds its first
+        // offset from when the Log is reopened.
+        let newest = self.sealed_segments.len() - 1;
+        for i in (1..newest).rev() {
+            if emptied.contains(&self.sealed_segments[i].timestamp()) {
+                let segment = self.sealed_segments.remove(i);
+                debug!(
+                    "deleting compacted segment {:?} in {:?}",
+                    segment, self.path
+                );
+                segment.delete(self.path.clone())?;
+            }
+        }
+
+        Ok(removed)
+    }
+
+    /// Compacts the Log all while holding it.
+    #[cfg(test)]
+    pub fn compact(&mut self) -> std::io::Result<usize> {
+        match self.start_compaction()? {
+            Some(mut pending) => {
+                pending.scan()?;
+                self.finish_compaction(pending)
+            }
+            None => Ok(0),
+        }
+    }
 
-    fn index(&self, i: usize) -> &Vec<u8> {
-        &self.data[i]
+    /// Appends a message to the Log. The returned PendingSync can be waited on, once the Log is no
+    /// longer borrowed, for the message to be fsynced.
+    pub fn add_msg(&mut self, msg: Vec<u8>, meta: Metadata) -> Result<PendingSync, Error> {
+        self.add_msg_at(msg, meta, now_millis())
+    }
+
+    /// Appends a message with its metadata and an ingest time given by the writer, such as one
+    /// imported from another system, in milliseconds since the Unix Epoch. Ingest times never
+    /// decrease within a Log, so a message older than the newest one already in the Log is given
+    /// that one's time.
+    ///
+    /// A message from an idempotent producer that the Log already has isn't added again. The
+    /// PendingSync returned for it is for the original message instead.
+    pub fn add_msg_at(
+        &mut self,
+        msg: Vec<u8>,
+        meta: Metadata,
+        time: u64,
+    ) -> Result<PendingSync, Error> {
+        if let Some(producer) = &meta.producer {
+            if let Some(offset) = self.producers.check(producer)? {
+                return Ok(PendingSync {
+                    syncer: self.syncer.clone(),
+                    ticket: self.syncer.last_written(),
+                    durability: self.options.durability.unwrap_or_default(),
+                    offset,
+                    duplicate: true,
+                });
+            }
+        }
+
+        self.check_policy(&msg)?;
+
+        let max_size = self
+            .options
+            .max_segment_size
+            .unwrap_or(segment::DEFAULT_MAX_SEGMENT_SIZE)
+            // Positions in the IndexFile are 32bit, so a Segment can't be any larger.
+            .min(u32::MAX as u64);
+        let max_age = self
+            .options
+            .max_segment_age
+            .unwrap_or(segment::DEFAULT_MAX_SEGMENT_AGE);
+
+        let time = time.max(self.last_time());
+        let entry_len = segment::entry_len(&msg, &meta);
+        // A Segment from before metadata was stored is finished off rather than written to, so
+        // that no message loses its metadata.
+        let rolled = if self
+            .active_segment
+            .is_full(entry_len, time, max_size, max_age)
+            || !self.active_segment.stores_metadata()
+        {
+            self.roll_segment(time)
+        } else {
+            self.rewind_active_segment(time)
+        };
+        if let Err(e) = rolled {
+            error!("could not roll over segment in {:?}: {}", self.path, e);
+            return Err(Error::ErrWritingSegment);
+        }
+
+        let id = self.len();
+        if let Err(e) = self.active_segment.add_msg(id, &msg, &meta, time) {
+            error!("could not write msg to segment in {:?}: {}", self.path, e);
+            return Err(Error::ErrWritingSegment);
+        }
+        if let Some(producer) = &meta.producer {
+            self.producers.record(producer, id);
+        }
+        self.appends.record(now_millis() / 1000);
+
+        Ok(PendingSync {
+            syncer: self.syncer.clone(),
+            ticket: self.syncer.written(),
+            durability: self.options.durability.unwrap_or_default(),
+            offset: id,
+            duplicate: false,
+        })
+    }
+
+    /// Checks that a message is one the Log accepts: no larger than its max message size, and
+    /// unless the Log takes raw bytes, a CBOR value that fits its schema.
+    fn check_policy(&self, msg: &[u8]) -> Result<(), Error> {
+        if let Some(max) = self.options.max_message_size {
+            if msg.len() as u64 > max {
+                return Err(Error::MsgTooLarge);
+            }
+        }
+        if self.options.ingestion.unwrap_or_default() == IngestionMode::Raw {
+            return Ok(());
+        }
+
+        let res: Result<CborValue, CborError> = serde_cbor::from_reader(msg);
+        let value = res.map_err(|_| Error::MsgNotValidCbor)?;
+        match &self.options.schema {
+            Some(schema) => schema.validate(&value).map_err(|v| {
+                debug!(
+                    "msg breaks schema of {:?} at {}: {:?}",
+                    self.path, v.path, v.rule
+                );
+                v.rule
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /// The ingest time of the newest message in the Log, or 0 if it has none.
+    fn last_time(&self) -> u64 {
+        if !self.active_segment.is_empty() {
+            return self.active_segment.last_time();
+        }
+        self.sealed_segments.last().map_or(0, |s| s.last_time())
+    }
+
+    /// Moves the Epoch of an empty active Segment back to `time` if it is later, so that it can
+    /// hold a message ingested before the Segment was created.
+    fn rewind_active_segment(&mut self, time: u64) -> std::io::Result<()> {
+        // Segment names still need to increase.
+        let time = match self.sealed_segments.last() {
+            Some(newest) => time.max(newest.timestamp() + 1),
+            None => time,
+        };
+        if !self.active_segment.is_empty() || time >= self.active_segment.timestamp() {
+            return Ok(());
+        }
+
+        debug!(
+            "moving empty segment {} in {:?} back to {}",
+            self.active_segment.timestamp(),
+            self.path,
+            time
+        );
+        self.active_segment.recreate_at(self.path.clone(), time)?;
+        self.syncer.rolled(self.active_segment.file_handles()?);
+        Ok(())
+    }
+
+    /// Seals the active Segment and starts writing to a new one.
+    /// `time` is the ingest time of the first message for the new Segment.
+    fn roll_segment(&mut self, time: u64) -> std::io::Result<()> {
+        self.active_segment.seal()?;
+
+        let next = self.active_segment.create_next(self.path.clone(), time)?;
+        self.syncer.rolled(next.file_handles()?);
+        // The Segment is compressed later by start_compression, rather than holding up writes.
+        let sealed = std::mem::replace(&mut self.active_segment, next);
+        debug!("sealed segment {:?} in {:?}", sealed, self.path);
+        self.sealed_segments.push(sealed);
+
+        // Without a checkpoint, the Producers are rebuilt from an older one or a full scan.
+        let next_offset = self.len();
+        if let Err(e) = self.producers.checkpoint(&self.path, next_offset) {
+            warn!("could not checkpoint producers in {:?}: {}", self.path, e);
+        }
+        Ok(())
     }
 }
 
@@ -52,20 +741,571 @@ impl Index<usize> for Log {
 mod tests {
     use super::*;
     use crate::test_util::temp_db_path;
+    use storage::ProducerSequence;
+
+    fn body(log: &Log, offset: usize) -> Result<Option<Vec<u8>>, Error> {
+        let msg = log.read_from(offset, 1)?.pop();
+        Ok(msg.filter(|m| m.offset == offset as u64).map(|m| m.body))
+    }
 
     #[test]
     fn test_add_valid_cbor_msg() {
-        let mut log = Log::new(temp_db_path().into(), "test_log");
+        let mut log = Log::new(temp_db_path().into(), "test_log", LogOptions::default()).unwrap();
         let msg = vec![0x19, 0x03, 0xE8];
-        if let Err(e) = log.add_msg(msg) {
+        if let Err(e) = log.add_msg(msg, Metadata::default()) {
             panic!("threw error for valid cbor: {:?}", e);
         };
     }
 
     #[test]
     fn test_add_invalid_cbor_msg() {
-        let mut log = Log::new(temp_db_path().into(), "test_log");
+        let mut log = Log::new(temp_db_path().into(), "test_log", LogOptions::default()).unwrap();
         let buf = vec![0x1a, 0x01, 0x02];
-        assert_eq!(log.add_msg(buf).is_err(), true);
+        assert!(log.add_msg(buf, Metadata::default()).is_err());
+    }
+
+    #[test]
+    fn test_msg_policies() {
+        let schema: crate::schema::Schema = serde_cbor::from_slice(
+            &serde_cbor::to_vec(&crate::json::from_json(r#"{"type": "integer"}"#).unwrap())
+                .unwrap(),
+        )
+        .unwrap();
+        let options = LogOptions {
+            max_message_size: Some(2),
+            schema: Some(Box::new(schema)),
+            ..LogOptions::default()
+        };
+        let mut log = Log::new(temp_db_path().into(), "test_log", options.clone()).unwrap();
+        let mut add = |msg: &[u8]| log.add_msg(msg.to_vec(), Metadata::default()).map(|_| ());
+        assert_eq!(add(&[0x01]), Ok(()));
+        assert_eq!(add(&[0x19, 0x03, 0xE8]), Err(Error::MsgTooLarge));
+        assert_eq!(add(&[0x61, 0x61]), Err(Error::MsgSchemaTypeMismatch));
+        assert_eq!(add(&[0x1a, 0x01]), Err(Error::MsgNotValidCbor));
+
+        // Raw messages aren't decoded, so the schema doesn't apply to them.
+        let options = LogOptions {
+            ingestion: Some(IngestionMode::Raw),
+            ..options
+        };
+        let mut log = Log::new(temp_db_path().into(), "test_log", options).unwrap();
+        let mut add = |msg: &[u8]| log.add_msg(msg.to_vec(), Metadata::default()).map(|_| ());
+        assert_eq!(add(&[0x1a, 0x01]), Ok(()));
+        assert_eq!(add(&[0x61, 0x61]), Ok(()));
+        assert_eq!(add(&[0x00, 0x01, 0x02]), Err(Error::MsgTooLarge));
+    }
+
+    #[test]
+    fn test_msgs_survive_reopen() {
+        let path = temp_db_path();
+        let mut log = Log::new(path.clone().into(), "test_log", LogOptions::default()).unwrap();
+        log.add_msg(vec![0x19, 0x03, 0xE8], Metadata::default())
+            .unwrap();
+        log.add_msg(vec![0x61, 0x61], Metadata::default()).unwrap();
+        drop(log);
+
+        let mut log = Log::new(path.into(), "test_log", LogOptions::default()).unwrap();
+        assert_eq!(body(&log, 0).unwrap(), Some(vec![0x19, 0x03, 0xE8]));
+        assert_eq!(body(&log, 1).unwrap(), Some(vec![0x61, 0x61]));
+
+        log.add_msg(vec![0x01], Metadata::default()).unwrap();
+        assert_eq!(body(&log, 2).unwrap(), Some(vec![0x01]));
+        assert_eq!(body(&log, 3).unwrap(), None);
+    }
+
+    #[test]
+    fn test_msgs_are_synced_across_rollover() {
+        let options = LogOptions {
+            max_segment_size: Some(5 + 12),
+            durability: Some(Durability::Batch {
+                messages: 2,
+                millis: 10,
+            }),
+            ..LogOptions::default()
+        };
+
+        let mut log = Log::new(temp_db_path().into(), "test_log", options).unwrap();
+        let first = log
+            .add_msg(vec![0x19, 0x03, 0x01], Metadata::default())
+            .unwrap();
+        let second = log
+            .add_msg(vec![0x19, 0x03, 0x02], Metadata::default())
+            .unwrap();
+        let third = log
+            .add_msg(vec![0x19, 0x03, 0x03], Metadata::default())
+            .unwrap();
+        assert_eq!(log.segment_count(), 3);
+
+        third.wait().unwrap();
+        first.wait().unwrap();
+        second.wait().unwrap();
+    }
+
+    #[test]
+    fn test_segments_roll_over_at_max_size() {
+        let path = temp_db_path();
+        let options = LogOptions {
+            // Room for the header and two 3 byte messages.
+            max_segment_size: Some(5 + 2 * 12),
+            ..LogOptions::default()
+        };
+
+        let mut log = Log::new(path.clone().into(), "test_log", options.clone()).unwrap();
+        for i in 0..5 {
+            log.add_msg(vec![0x19, 0x03, i], Metadata::default())
+                .unwrap();
+        }
+        assert_eq!(log.segment_count(), 3);
+        drop(log);
+
+        let log = Log::new(path.into(), "test_log", options).unwrap();
+        assert_eq!(log.segment_count(), 3);
+        assert_eq!(log.len(), 5);
+        for i in 0..5 {
+            assert_eq!(body(&log, i).unwrap(), Some(vec![0x19, 0x03, i as u8]));
+        }
+        assert_eq!(body(&log, 5).unwrap(), None);
+    }
+
+    #[test]
+    fn test_segments_roll_over_at_max_age() {
+        let options = LogOptions {
+            max_segment_age: Some(0),
+            ..LogOptions::default()
+        };
+
+        let mut log = Log::new(temp_db_path().into(), "test_log", options).unwrap();
+        log.add_msg(vec![0x01], Metadata::default()).unwrap();
+        log.add_msg(vec![0x02], Metadata::default()).unwrap();
+        log.add_msg(vec![0x03], Metadata::default()).unwrap();
+        assert_eq!(log.segment_count(), 3);
+    }
+
+    #[test]
+    fn test_offset_at_time() {
+        let options = LogOptions {
+            max_segment_age: Some(0),
+            ..LogOptions::default()
+        };
+        let mut log = Log::new(temp_db_path().into(), "test_log", options).unwrap();
+
+        let before = now_millis();
+        std::thread::sleep(std::time::Duration::from_millis(5));
+        log.add_msg(vec![0x01], Metadata::default()).unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(5));
+        let middle = now_millis();
+        std::thread::sleep(std::time::Duration::from_millis(5));
+        log.add_msg(vec![0x02], Metadata::default()).unwrap();
+        log.add_msg(vec![0x03], Metadata::default()).unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(5));
+        let after = now_millis();
+
+        assert_eq!(log.segment_count(), 3);
+        assert_eq!(log.offset_at_time(0).unwrap(), 0);
+        assert_eq!(log.offset_at_time(before).unwrap(), 0);
+        assert_eq!(log.offset_at_time(middle).unwrap(), 1);
+        assert_eq!(log.offset_at_time(after).unwrap(), 3);
+    }
+
+    #[test]
+    fn test_add_msg_at_keeps_original_times() {
+        let path = temp_db_path();
+        let options = LogOptions {
+            max_segment_age: Some(60),
+            ..LogOptions::default()
+        };
+        let mut log = Log::new(path.clone().into(), "test_log", options.clone()).unwrap();
+
+        // A day apart, so each starts a new Segment.
+        let day = 24 * 60 * 60 * 1000;
+        let first = 1_000_000_000_000;
+        log.add_msg_at(vec![0x01], Metadata::default(), first)
+            .unwrap();
+        log.add_msg_at(vec![0x02], Metadata::default(), first + 1)
+            .unwrap();
+        log.add_msg_at(vec![0x03], Metadata::default(), first + day)
+            .unwrap();
+        log.add_msg(vec![0x04], Metadata::default()).unwrap();
+        assert_eq!(log.segment_count(), 3);
+        assert_eq!(log.first_segment().timestamp(), first);
+        drop(log);
+
+        let log = Log::new(path.into(), "test_log", options).unwrap();
+        let times: Vec<u64> = log
+            .read_from(0, 3)
+            .unwrap()
+            .iter()
+            .map(|m| m.ingest_time)
+            .collect();
+        assert_eq!(times, vec![first, first + 1, first + day]);
+        assert_eq!(log.offset_at_time(first + 1).unwrap(), 1);
+        assert_eq!(log.offset_at_time(first + 2).unwrap(), 2);
+        assert_eq!(log.offset_at_time(first + day + 1).unwrap(), 3);
+    }
+
+    #[test]
+    fn test_compressed_and_uncompressed_segments_mix() {
+        let path = temp_db_path();
+        let mut options = LogOptions {
+            max_segment_size: Some(5 + 2 * 12),
+            compression: Some(Compression::Lz4),
+            ..LogOptions::default()
+        };
+
+        let mut log = Log::new(path.clone().into(), "test_log", options.clone()).unwrap();
+        for i in 0..4 {
+            log.add_msg(vec![0x19, 0x03, i], Metadata::default())
+                .unwrap();
+        }
+        // Sealing a Segment leaves it uncompressed until it is compressed in the background.
+        assert!(log.sealed_segments.iter().all(|s| !s.is_compressed()));
+        log.compress().unwrap();
+        assert!(log.sealed_segments.iter().all(|s| s.is_compressed()));
+        drop(log);
+
+        options.compression = None;
+        let mut log = Log::new(path.clone().into(), "test_log", options.clone()).unwrap();
+        for i in 4..8 {
+            log.add_msg(vec![0x19, 0x03, i], Metadata::default())
+                .unwrap();
+        }
+        log.compress().unwrap();
+        drop(log);
+
+        let log = Log::new(path.into(), "test_log", options).unwrap();
+        assert_eq!(log.segment_count(), 4);
+        let compressed: Vec<bool> = log
+            .sealed_segments
+            .iter()
+            .map(|s| s.is_compressed())
+            .collect();
+        assert_eq!(compressed, vec![true, false, false]);
+        for i in 0..8 {
+            assert_eq!(body(&log, i).unwrap(), Some(vec![0x19, 0x03, i as u8]));
+        }
+    }
+
+    #[test]
+    fn test_compression_discards_copies_of_changed_segments() {
+        let options = LogOptions {
+            max_segment_size: Some(1),
+            compression: Some(Compression::Lz4),
+            cleanup_policy: Some(CleanupPolicy::Compact),
+            ..LogOptions::default()
+        };
+        let keyed = Metadata {
+            key: Some("a".to_string()),
+            ..Metadata::default()
+        };
+
+        let mut log = Log::new(temp_db_path().into(), "test_log", options).unwrap();
+        log.add_msg(vec![0x01], Metadata::default()).unwrap();
+        log.add_msg(vec![0x02], keyed.clone()).unwrap();
+        log.add_msg(vec![0x03], keyed).unwrap();
+        log.add_msg(vec![0x04], Metadata::default()).unwrap();
+
+        let pending = log.start_compression().unwrap();
+        assert_eq!(pending.len(), 3);
+        for p in pending.iter() {
+            p.write().unwrap();
+        }
+        // While the copies were written, the first Segment was deleted and the second compacted.
+        log.sealed_segments
+            .remove(0)
+            .delete(log.path.clone())
+            .unwrap();
+        assert_eq!(log.compact().unwrap(), 1);
+        for p in pending {
+            log.finish_compression(p).unwrap();
+        }
+
+        let compressed: Vec<bool> = log
+            .sealed_segments
+            .iter()
+            .map(|s| s.is_compressed())
+            .collect();
+        assert_eq!(compressed, vec![false, true]);
+        let leftovers = std::fs::read_dir(&log.path)
+            .unwrap()
+            .filter(|e| {
+                e.as_ref()
+                    .unwrap()
+                    .path()
+                    .extension()
+                    .is_some_and(|x| x == "tmp")
+            })
+            .count();
+        assert_eq!(leftovers, 0);
+        let msgs: Vec<Vec<u8>> = log
+            .read_from(1, 10)
+            .unwrap()
+            .into_iter()
+            .map(|m| m.body)
+            .collect();
+        assert_eq!(msgs, vec![vec![0x03], vec![0x04]]);
+    }
+
+    #[test]
+    fn test_retention_by_message_count() {
+        let path = temp_db_path();
+        let options = LogOptions {
+            max_segment_size: Some(5 + 2 * 12),
+            retention_max_messages: Some(3),
+            ..LogOptions::default()
+        };
+
+        let mut log = Log::new(path.clone().into(), "test_log", options.clone()).unwrap();
+        for i in 0..7 {
+            log.add_msg(vec![0x19, 0x03, i], Metadata::default())
+                .unwrap();
+        }
+        assert_eq!(log.segment_count(), 4);
+
+        // Segments hold offsets [0, 1], [2, 3], [4, 5], [6]. Deleting the first two still
+        // leaves 3 messages.
+        assert_eq!(log.apply_retention().unwrap(), 2);
+        assert_eq!(log.earliest_offset(), 4);
+        assert_eq!(body(&log, 3), Err(Error::MsgOffsetRetired));
+        assert_eq!(body(&log, 4).unwrap(), Some(vec![0x19, 0x03, 4]));
+        assert_eq!(log.apply_retention().unwrap(), 0);
+        drop(log);
+
+        let log = Log::new(path.into(), "test_log", options).unwrap();
+        assert_eq!(log.earliest_offset(), 4);
+        assert_eq!(log.len(), 7);
+    }
+
+    #[test]
+    fn test_compaction_keeps_newest_msg_per_key() {
+        let path = temp_db_path();
+        let options = LogOptions {
+            // Every message gets a Segment of its own.
+            max_segment_size: Some(1),
+            cleanup_policy: Some(CleanupPolicy::Compact),
+            tombstone_grace_period: Some(0),
+            ..LogOptions::default()
+        };
+        let keyed = |key: &str| Metadata {
+            key: Some(key.to_string()),
+            ..Metadata::default()
+        };
+
+        let mut log = Log::new(path.clone().into(), "test_log", options.clone()).unwrap();
+        log.add_msg(vec![0x01], keyed("a")).unwrap();
+        log.add_msg(vec![0x02], keyed("b")).unwrap();
+        log.add_msg(vec![0x03], Metadata::default()).unwrap();
+        log.add_msg(vec![0x04], keyed("a")).unwrap();
+        log.add_msg(TOMBSTONE.to_vec(), keyed("b")).unwrap();
+        log.add_msg(vec![0x05], keyed("c")).unwrap();
+        // Newer than anything sealed, but still in the active Segment.
+        log.add_msg(vec![0x06], keyed("c")).unwrap();
+
+        assert_eq!(log.compact().unwrap(), 4);
+        assert_eq!(log.compact().unwrap(), 0);
+        // Offsets 1 and 4 had Segments of their own, which are now empty. The oldest and newest
+        // sealed Segments are kept even when empty.
+        assert_eq!(log.segment_count(), 5);
+
+        let read = |log: &Log, offset| -> Vec<(u64, Vec<u8>)> {
+            let msgs = log.read_from(offset, 10).unwrap();
+            msgs.into_iter().map(|m| (m.offset, m.body)).collect()
+        };
+        let expected = vec![(2, vec![0x03]), (3, vec![0x04]), (6, vec![0x06])];
+        assert_eq!(read(&log, 0), expected);
+        assert_eq!(read(&log, 4), expected[2..].to_vec());
+        assert_eq!(log.earliest_offset(), 0);
+
+        let summary = log.summary().unwrap();
+        assert_eq!(summary.message_count, 3);
+        assert_eq!(summary.first_offset, Some(2));
+        assert_eq!(summary.last_offset, Some(6));
+        assert_eq!(summary.next_offset, 7);
+        assert_eq!(summary.segment_count, 5);
+        assert!(summary.earliest_timestamp <= summary.latest_timestamp);
+        assert!(summary.append_rate > 0.0);
+        drop(log);
+
+        let log = Log::new(path.into(), "test_log", options).unwrap();
+        assert_eq!(read(&log, 0), expected);
+        assert_eq!(log.len(), 7);
+        assert_eq!(log.summary().unwrap().message_count, 3);
+        assert_eq!(log.summary().unwrap().append_rate, 0.0);
+    }
+
+    #[test]
+    fn test_compaction_leaves_segments_changed_while_scanning() {
+        let options = LogOptions {
+            max_segment_size: Some(1),
+            cleanup_policy: Some(CleanupPolicy::Compact),
+            ..LogOptions::default()
+        };
+        let keyed = Metadata {
+            key: Some("a".to_string()),
+            ..Metadata::default()
+        };
+
+        let mut log = Log::new(temp_db_path().into(), "test_log", options).unwrap();
+        log.add_msg(vec![0x01], keyed.clone()).unwrap();
+        log.add_msg(vec![0x02], keyed.clone()).unwrap();
+        log.add_msg(vec![0x03], keyed).unwrap();
+        log.add_msg(vec![0x04], Metadata::default()).unwrap();
+
+        let mut pending = log.start_compaction().unwrap().unwrap();
+        pending.scan().unwrap();
+        // While the Log was scanned, another compaction rewrote the first Segment and deleted the
+        // second.
+        assert_eq!(log.compact().unwrap(), 2);
+        assert_eq!(log.segment_count(), 3);
+        assert_eq!(log.finish_compaction(pending).unwrap(), 0);
+
+        let msgs = log.read_from(0, 10).unwrap();
+        let offsets: Vec<u64> = msgs.iter().map(|m| m.offset).collect();
+        assert_eq!(offsets, vec![2, 3]);
+    }
+
+    #[test]
+    fn test_retention_applies_to_compacted_logs() {
+        let options = LogOptions {
+            max_segment_size: Some(1),
+            cleanup_policy: Some(CleanupPolicy::Compact),
+            retention_max_messages: Some(1),
+            ..LogOptions::default()
+        };
+        let keyed = |key: &str| Metadata {
+            key: Some(key.to_string()),
+            ..Metadata::default()
+        };
+
+        let mut log = Log::new(temp_db_path().into(), "test_log", options).unwrap();
+        log.add_msg(vec![0x01], keyed("a")).unwrap();
+        log.add_msg(vec![0x02], keyed("b")).unwrap();
+        log.add_msg(vec![0x03], keyed("c")).unwrap();
+
+        assert_eq!(log.apply_retention().unwrap(), 2);
+        assert_eq!(log.earliest_offset(), 2);
+    }
+
+    #[test]
+    fn test_append_rate_covers_last_minute() {
+        let mut rate = AppendRate::default();
+        for _ in 0..30 {
+            rate.record(100);
+        }
+        rate.record(130);
+        rate.record(130);
+        assert_eq!(rate.per_second(130), 32.0 / 60.0);
+        assert_eq!(rate.per_second(165), 2.0 / 60.0);
+        assert_eq!(rate.per_second(190), 0.0);
+
+        rate.record(200);
+        assert_eq!(rate.seconds.len(), 1);
+    }
+
+    #[test]
+    fn test_compaction_keeps_tombstones_for_grace_period() {
+        let options = LogOptions {
+            max_segment_size: Some(1),
+            cleanup_policy: Some(CleanupPolicy::Compact),
+            ..LogOptions::default()
+        };
+        let meta = Metadata {
+            key: Some("a".to_string()),
+            ..Metadata::default()
+        };
+
+        let mut log = Log::new(temp_db_path().into(), "test_log", options).unwrap();
+        log.add_msg(vec![0x01], meta.clone()).unwrap();
+        log.add_msg(TOMBSTONE.to_vec(), meta).unwrap();
+        log.add_msg(vec![0x02], Metadata::default()).unwrap();
+
+        assert_eq!(log.compact().unwrap(), 1);
+        let msgs = log.read_from(0, 10).unwrap();
+        let offsets: Vec<u64> = msgs.iter().map(|m| m.offset).collect();
+        assert_eq!(offsets, vec![1, 2]);
+    }
+
+    #[test]
+    fn test_retention_by_age_keeps_offsets_recoverable() {
+        let path = temp_db_path();
+        let options = LogOptions {
+            max_segment_age: Some(0),
+            retention_max_age: Some(0),
+            ..LogOptions::default()
+        };
+
+        let mut log = Log::new(path.clone().into(), "test_log", options.clone()).unwrap();
+        log.add_msg(vec![0x01], Metadata::default()).unwrap();
+        log.add_msg(vec![0x02], Metadata::default()).unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(5));
+
+        // The active Segment still has a message, so every sealed Segment can go.
+        assert_eq!(log.apply_retention().unwrap(), 1);
+        assert_eq!(log.earliest_offset(), 1);
+        assert_eq!(log.segment_count(), 1);
+        drop(log);
+
+        let log = Log::new(path.into(), "test_log", options).unwrap();
+        assert_eq!(log.len(), 2);
+    }
+
+    #[test]
+    fn test_retention_by_size() {
+        let options = LogOptions {
+            max_segment_size: Some(5 + 2 * 12),
+            retention_max_size: Some(1),
+            ..LogOptions::default()
+        };
+
+        let mut log = Log::new(temp_db_path().into(), "test_log", options).unwrap();
+        for i in 0..4 {
+            log.add_msg(vec![0x19, 0x03, i], Metadata::default())
+                .unwrap();
+        }
+        assert_eq!(log.apply_retention().unwrap(), 1);
+        assert_eq!(log.earliest_offset(), 2);
+        assert_eq!(log.segment_count(), 1);
+    }
+
+    #[test]
+    fn test_producer_msgs_are_added_once() {
+        let path = temp_db_path();
+        let options = LogOptions {
+            max_segment_size: Some(1),
+            ..LogOptions::default()
+        };
+        let from = |id: &str, sequence| Metadata {
+            producer: Some(ProducerSequence {
+                id: id.to_string(),
+                sequence,
+            }),
+            ..Metadata::default()
+        };
+        let add = |log: &mut Log, meta| {
+            log.add_msg(vec![0x01], meta)
+                .map(|p| (p.offset(), p.is_duplicate()))
+        };
+
+        let mut log = Log::new(path.clone().into(), "test_log", options.clone()).unwrap();
+        assert_eq!(add(&mut log, from("p1", 1)), Ok((0, false)));
+        assert_eq!(add(&mut log, from("p1", 1)), Ok((0, true)));
+        assert_eq!(add(&mut log, from("p1", 0)), Err(Error::MsgSequenceTooOld));
+        assert_eq!(add(&mut log, from("p2", 1)), Ok((1, false)));
+        assert_eq!(add(&mut log, from("p1", 3)), Ok((2, false)));
+        assert_eq!(log.len(), 3);
+        drop(log);
+
+        // Recovered from the checkpoint taken when the last Segment was sealed, and a scan of the
+        // active Segment.
+        let mut log = Log::new(path.clone().into(), "test_log", options.clone()).unwrap();
+        assert_eq!(add(&mut log, from("p1", 3)), Ok((2, true)));
+        assert_eq!(add(&mut log, from("p2", 1)), Ok((1, true)));
+        drop(log);
+
+        // Recovered from a scan of every Segment.
+        std::fs::remove_file(PathBuf::from(&path).join("logs/test_log/producers")).unwrap();
+        let mut log = Log::new(path.into(), "test_log", options).unwrap();
+        assert_eq!(add(&mut log, from("p1", 3)), Ok((2, true)));
+        assert_eq!(add(&mut log, from("p2", 1)), Ok((1, true)));
+        assert_eq!(add(&mut log, from("p2", 2)), Ok((3, false)));
     }
 }
@@ -0,0 +1,113 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+use storage::ProducerSequence;
+
+use super::segment::Segment;
+use crate::errors::Error;
+
+/// Name of the file in a Log's directory that records its Producers as of the newest sealed
+/// Segment.
+const CHECKPOINT: &str = "producers";
+
+/// The newest message each idempotent producer has added to a Log.
+///
+/// Every message from a producer carries its id and sequence number in its metadata, so the
+/// Segments are the record of what each producer has added. Scanning all of them whenever the Log
+/// is opened would be slow though, so the state is checkpointed each time a Segment is sealed and
+/// only the messages after the checkpoint are scanned.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Producers {
+    /// Offset following the last message accounted for.
+    next_offset: usize,
+    producers: HashMap<String, LastAdded>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct LastAdded {
+    sequence: u64,
+    offset: usize,
+}
+
+impl Producers {
+    /// Rebuilds the Producers of the Log in `path` from its checkpoint and the messages in
+    /// `segments` that came after it. Without a usable checkpoint, every message is scanned.
+    ///
+    /// A message removed by compaction is no longer seen by a full scan, so its producer's
+    /// retries can't be recognised after that.
+    pub fn recover(path: &Path, segments: &[&Segment]) -> std::io::Result<Self> {
+        let end = segments.last().map_or(0, |s| s.end_id());
+        let mut producers = match Self::load(path) {
+            Ok(Some(producers)) if producers.next_offset <= end => producers,
+            Ok(_) => Producers::default(),
+            Err(e) => {
+                warn!(
+                    "ignoring unreadable producers checkpoint in {:?}: {}",
+                    path, e
+                );
+                Producers::default()
+            }
+        };
+
+        let from = producers.next_offset;
+        for segment in segments.iter().filter(|s| s.end_id() > from) {
+            segment.scan(|msg| match &msg.meta.producer {
+                Some(producer) if msg.offset as usize >= from => {
+                    producers.record(producer, msg.offset as usize)
+                }
+                _ => (),
+            })?;
+        }
+        producers.next_offset = end;
+        Ok(producers)
+    }
+
+    fn load(path: &Path) -> std::io::Result<Option<Self>> {
+        match std::fs::read(path.join(CHECKPOINT)) {
+            Ok(bytes) => serde_cbor::from_slice(&bytes)
+                .map(Some)
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Checks whether a message from `producer` was already added. Returns the offset it was added
+    /// at if it was, or None if the message is new.
+    pub fn check(&self, producer: &ProducerSequence) -> Result<Option<usize>, Error> {
+        match self.producers.get(&producer.id) {
+            Some(last) if producer.sequence == last.sequence => Ok(Some(last.offset)),
+            // Only the newest message is remembered, so an older one can't be acknowledged.
+            Some(last) if producer.sequence < last.sequence => Err(Error::MsgSequenceTooOld),
+            _ => Ok(None),
+        }
+    }
+
+    /// Records that the message from `producer` was added at `offset`.
+    pub fn record(&mut self, producer: &ProducerSequence, offset: usize) {
+        self.producers.insert(
+            producer.id.clone(),
+            LastAdded {
+                sequence: producer.sequence,
+                offset,
+            },
+        );
+        self.next_offset = offset + 1;
+    }
+
+    /// Writes the Producers to the Log's directory, as of every message before `next_offset`.
+    /// The file is replaced atomically, so a crash leaves either the old checkpoint or the new one.
+    pub fn checkpoint(&mut self, path: &Path, next_offset: usize) -> std::io::Result<()> {
+        self.next_offset = next_offset;
+        let bytes = serde_cbor::to_vec(self).expect("could not serialize producers");
+
+        let tmp_path = path.join(format!("{}.tmp", CHECKPOINT));
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(&bytes)?;
+        file.sync_all()?;
+        std::fs::rename(tmp_path, path.join(CHECKPOINT))
+    }
+}
@@ -1,11 +1,25 @@
+use std::collections::HashSet;
+use std::convert::TryFrom;
 use std::fs::{File, OpenOptions};
 use std::io::prelude::*;
-use std::path::PathBuf;
+use std::os::unix::fs::{FileExt, MetadataExt};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
 use std::time::{SystemTime, UNIX_EPOCH};
 
-/// MAX_SEGMENT_SIZE is the number of bytes a segment can contain before we have to roll over to a
-/// new one.  Right now, this is non-configurable and is set to 1 GiB.
-//const MAX_SEGMENT_SIZE: usize = 1_073_741_824;
+use serde_cbor::Value as CborValue;
+use storage::{Metadata, ProducerSequence, RawMessage};
+
+use super::crc;
+use crate::commands::Compression;
+
+/// DEFAULT_MAX_SEGMENT_SIZE is the number of bytes a segment can contain before we have to roll
+/// over to a new one, unless the server or Log is configured otherwise. Set to 1 GiB.
+pub const DEFAULT_MAX_SEGMENT_SIZE: u64 = 1_073_741_824;
+
+/// DEFAULT_MAX_SEGMENT_AGE is the number of seconds after a segment's Epoch that we roll over to a
+/// new one, unless the server or Log is configured otherwise. Set to 7 days.
+pub const DEFAULT_MAX_SEGMENT_AGE: u64 = 7 * 24 * 60 * 60;
 
 /// A Segment is a set of two files, a DataFile and an IndexFile.
 /// The combination of these store all the data and where the
@@ -15,70 +29,880 @@ pub struct Segment {
     /// the DataFile and IndexFile before by their respective extensions.
     timestamp: u64,
     format_version: FormatVersion,
+    /// The id of the first message in the Segment. If the Segment is empty, this is the id the next
+    /// message written to it will have.
+    first_id: usize,
+    /// The id following the last message in the Segment. Ids are consecutive until the Segment is
+    /// compacted, after which it can have gaps.
+    end_id: usize,
 
     data_file: DataFile,
     index_file: IndexFile,
 }
 
 impl Segment {
-    pub fn get_active_for(path: PathBuf) -> Segment {
-        let mut files: Vec<String> = std::fs::read_dir(&path)
-            .expect("could not read segment directory")
-            .map(|entry| {
-                entry
-                    .expect("got error reading dir entry")
-                    .file_name()
-                    .into_string()
-                    .expect("segment file contains non-utf8 string")
-            })
-            .filter(|entry| entry.split('.').last().unwrap() == "dat")
-            .collect();
+    /// Opens every Segment in the directory, ordered from oldest to newest. The last Segment is the
+    /// one currently being written to. If the directory has no Segments, one is created.
+    pub fn get_all_for(path: PathBuf) -> std::io::Result<Vec<Segment>> {
+        Self::finish_rewrites(&path)?;
+
+        for entry in std::fs::read_dir(&path)? {
+            let name = entry?.file_name();
+
+            // Left over from being interrupted while compressing or rewriting a Segment. The
+            // original files are still intact.
+            if name.to_string_lossy().ends_with(".tmp") {
+                warn!("removing incomplete segment file {:?} in {:?}", name, path);
+                let _ = std::fs::remove_file(path.join(name));
+            }
+        }
+
+        let segments = Self::open_all(path.clone())?;
 
         // New log with no segments. Make a new one.
-        if files.is_empty() {
-            let timestamp = SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .unwrap()
-                .as_millis() as u64;
-            return Segment::create(path, timestamp);
+        if segments.is_empty() {
+            return Ok(vec![Segment::create(path, now_millis(), 0)?]);
         }
+        Ok(segments)
+    }
 
-        files.sort();
+    /// Opens every Segment in the directory, ordered from oldest to newest, without creating or
+    /// cleaning anything up.
+    pub fn open_all(path: PathBuf) -> std::io::Result<Vec<Segment>> {
+        Self::open_all_with(path, Segment::open)
+    }
 
-        let dat_path = files.first().unwrap();
-        let timestamp: u64 = dat_path[0..dat_path.len() - 4].parse().unwrap();
-        let mut idx_path = dat_path[0..dat_path.len() - 4].to_string();
-        idx_path.push_str("idx");
+    /// Opens every Segment in the directory like open_all, but with their files opened read only,
+    /// for inspecting a DB without any chance of writing to it.
+    pub fn open_all_read_only(path: PathBuf) -> std::io::Result<Vec<Segment>> {
+        Self::open_all_with(path, Segment::open_read_only)
+    }
 
-        Segment {
-            timestamp,
-            format_version: FormatVersion::Uncompressed,
-            data_file: DataFile {
-                file: File::open(dat_path).unwrap(),
-            },
-            index_file: IndexFile {
-                file: File::open(idx_path).unwrap(),
-            },
+    fn open_all_with(
+        path: PathBuf,
+        open: fn(PathBuf, u64, usize) -> std::io::Result<Segment>,
+    ) -> std::io::Result<Vec<Segment>> {
+        let timestamps = Self::timestamps(&path)?;
+
+        let mut segments: Vec<Segment> = Vec::with_capacity(timestamps.len());
+        for timestamp in timestamps {
+            let next_id = segments.last().map_or(0, |s| s.end_id());
+            segments.push(open(path.clone(), timestamp, next_id)?);
         }
+        Ok(segments)
+    }
+
+    /// Completes any rewrite that was interrupted after its new files were fully written, and
+    /// removes what is left of any that were interrupted before then. See Segment::rewrite.
+    fn finish_rewrites(path: &Path) -> std::io::Result<()> {
+        let mut changed = false;
+        for entry in std::fs::read_dir(path)? {
+            let name = entry?.file_name().to_string_lossy().into_owned();
+            if let Some(timestamp) = name.strip_suffix(".idx.compact") {
+                let (dat_path, idx_path) = Self::paths(
+                    path.to_path_buf(),
+                    timestamp.parse().map_err(|_| {
+                        invalid_data(format!("segment file name {:?} is not a timestamp", name))
+                    })?,
+                );
+                warn!(
+                    "finishing interrupted rewrite of segment {} in {:?}",
+                    timestamp, path
+                );
+
+                // The DataFile may already have been moved into place.
+                let dat_compact = dat_path.with_extension("dat.compact");
+                if dat_compact.exists() {
+                    std::fs::rename(dat_compact, dat_path)?;
+                }
+                std::fs::rename(path.join(&name), idx_path)?;
+                changed = true;
+            }
+        }
+
+        for entry in std::fs::read_dir(path)? {
+            let name = entry?.file_name().to_string_lossy().into_owned();
+            if name.ends_with(".dat.compact") {
+                warn!("removing incomplete segment file {:?} in {:?}", name, path);
+                std::fs::remove_file(path.join(name))?;
+                changed = true;
+            }
+        }
+
+        if changed {
+            File::open(path)?.sync_all()?;
+        }
+        Ok(())
+    }
+
+    /// Lists the names of the Segments in the directory, oldest first.
+    pub fn timestamps(path: &Path) -> std::io::Result<Vec<u64>> {
+        let mut timestamps = vec![];
+        for entry in std::fs::read_dir(path)? {
+            let name = entry?.file_name();
+            let name = name
+                .to_str()
+                .ok_or_else(|| invalid_data(format!("non-utf8 file name {:?}", name)))?;
+
+            if let Some(timestamp) = name.strip_suffix(".dat") {
+                timestamps.push(timestamp.parse().map_err(|_| {
+                    invalid_data(format!("segment file name {:?} is not a timestamp", name))
+                })?);
+            }
+        }
+
+        timestamps.sort_unstable();
+        Ok(timestamps)
+    }
+
+    /// Checks the newest Segment in the directory for a write that was cut short by a crash or
+    /// power loss, and truncates its files back to the last complete message. Returns what was
+    /// changed, or None if the Segment was already consistent.
+    ///
+    /// Only the newest Segment is ever written to, and only at its end, so only its tail is
+    /// scanned. Messages are acknowledged after both files are written, so nothing that is
+    /// removed was ever acknowledged unless the OS lost writes that it had accepted.
+    pub fn repair_active(path: PathBuf) -> std::io::Result<Option<RepairReport>> {
+        let timestamp = match Self::timestamps(&path)?.pop() {
+            Some(timestamp) => timestamp,
+            None => return Ok(None),
+        };
+        let (dat_path, idx_path) = Self::paths(path, timestamp);
+
+        let data = OpenOptions::new().read(true).write(true).open(dat_path)?;
+        // The IndexFile is created after the DataFile, so it may not exist yet.
+        let index = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(idx_path)?;
+        let data_len = data.metadata()?.len();
+        let index_len = index.metadata()?.len();
+
+        let mut report = RepairReport {
+            segment: timestamp,
+            msgs_kept: 0,
+            msgs_dropped: 0,
+            index_bytes_truncated: 0,
+            data_bytes_truncated: 0,
+            reasons: vec![],
+        };
+
+        if data_len < DataFile::HEADER_LEN || index_len < IndexFile::HEADER_LEN {
+            if index_len > IndexFile::HEADER_LEN {
+                return Err(invalid_data(format!(
+                    "datafile of segment {} has no header but its indexfile has entries",
+                    timestamp
+                )));
+            }
+
+            // Interrupted while creating the Segment, so nothing was ever written to it and it can
+            // be started over.
+            data.set_len(0)?;
+            data.write_all_at(&DataFile::header(FormatVersion::CURRENT), 0)?;
+            index.set_len(0)?;
+            index.write_all_at(&IndexFile::header(timestamp), 0)?;
+            data.sync_all()?;
+            index.sync_all()?;
+
+            report.data_bytes_truncated = data_len;
+            report.index_bytes_truncated = index_len;
+            report.reasons.push(RepairReason::IncompleteHeader);
+            return Ok(Some(report));
+        }
+
+        let mut header = [0u8; DataFile::HEADER_LEN as usize];
+        data.read_exact_at(&mut header, 0)?;
+        // Compressed DataFiles are written to a temporary file and renamed into place, so they are
+        // never left half written.
+        let version = match FormatVersion::from_u8(header[4]) {
+            Some(version) if !version.is_compressed() => version,
+            _ => return Ok(None),
+        };
+
+        let entries = ((index_len - IndexFile::HEADER_LEN) / IndexFile::ENTRY_LEN) as usize;
+        if IndexFile::HEADER_LEN + entries as u64 * IndexFile::ENTRY_LEN < index_len {
+            report.reasons.push(RepairReason::PartialIndexEntry);
+        }
+
+        let entry = |i: usize| -> std::io::Result<IndexEntry> {
+            let mut buf = [0u8; IndexFile::ENTRY_LEN as usize];
+            index.read_exact_at(
+                &mut buf,
+                IndexFile::HEADER_LEN + i as u64 * IndexFile::ENTRY_LEN,
+            )?;
+            Ok(IndexEntry::from_bytes(&buf))
+        };
+
+        // Walk back from the newest entry until we find one that follows on from the entry before
+        // it and whose payload matches its CRC. Everything after it was never fully written.
+        let mut kept = entries;
+        let mut data_end = DataFile::HEADER_LEN;
+        while kept > 0 {
+            let last = entry(kept - 1)?;
+            let follows_on = match kept {
+                1 => last.position as u64 == DataFile::HEADER_LEN,
+                _ => {
+                    let prev = entry(kept - 2)?;
+                    last.id > prev.id && last.time >= prev.time && last.position > prev.position
+                }
+            };
+
+            let position = last.position as u64;
+            if !follows_on || position + version.entry_header_len() > data_len {
+                report.reasons.push(RepairReason::InconsistentIndexEntry);
+            } else if let Some(end) = entry_end(&data, version, position, data_len)? {
+                data_end = end;
+                break;
+            } else {
+                report.reasons.push(RepairReason::CrcMismatch);
+            }
+            kept -= 1;
+        }
+
+        if kept == entries && data_end < data_len {
+            report.reasons.push(RepairReason::UnindexedData);
+        }
+        report.reasons.dedup();
+
+        if report.reasons.is_empty() {
+            return Ok(None);
+        }
+
+        let index_end = IndexFile::HEADER_LEN + kept as u64 * IndexFile::ENTRY_LEN;
+        index.set_len(index_end)?;
+        data.set_len(data_end)?;
+        index.sync_all()?;
+        data.sync_all()?;
+
+        report.msgs_kept = kept;
+        report.msgs_dropped = entries - kept;
+        report.index_bytes_truncated = index_len - index_end;
+        report.data_bytes_truncated = data_len - data_end;
+        Ok(Some(report))
+    }
+
+    /// Creates the Segment that follows this one in the same directory, with its Epoch at `time`,
+    /// the ingest time of the first message it will hold.
+    pub fn create_next(&self, path: PathBuf, time: u64) -> std::io::Result<Segment> {
+        // Segment names need to be unique and increasing, even if we roll twice within a
+        // millisecond or the clock steps backwards.
+        let timestamp = time.max(self.timestamp + 1);
+        Segment::create(path, timestamp, self.end_id)
+    }
+
+    /// Replaces an empty Segment with one whose Epoch is at `timestamp` instead, so that it can
+    /// hold messages ingested before its current Epoch.
+    pub fn recreate_at(&mut self, path: PathBuf, timestamp: u64) -> std::io::Result<()> {
+        assert!(self.is_empty(), "only empty segments can be recreated");
+
+        // The new Segment is created before the old one is deleted, so a crash in between leaves
+        // an extra empty Segment rather than none at all.
+        let segment = Segment::create(path.clone(), timestamp, self.first_id)?;
+        std::mem::replace(self, segment).delete(path.clone())?;
+        File::open(&path)?.sync_all()
+    }
+
+    fn create(path: PathBuf, timestamp: u64, first_id: usize) -> std::io::Result<Segment> {
+        let (dat_path, idx_path) = Self::paths(path, timestamp);
+
+        Ok(Segment {
+            timestamp,
+            format_version: FormatVersion::CURRENT,
+            first_id,
+            end_id: first_id,
+            data_file: DataFile::create(dat_path)?,
+            index_file: IndexFile::create(idx_path, timestamp)?,
+        })
+    }
+
+    /// Opens an existing Segment. `next_id` is the id following the previous Segment's last
+    /// message, which is where this Segment starts if it is empty.
+    fn open(path: PathBuf, timestamp: u64, next_id: usize) -> std::io::Result<Segment> {
+        let (dat_path, idx_path) = Self::paths(path, timestamp);
+        Self::from_files(
+            timestamp,
+            next_id,
+            DataFile::open(dat_path)?,
+            IndexFile::open(idx_path)?,
+        )
     }
 
-    fn create(path: PathBuf, timestamp: u64) -> Segment {
-        let dat = format!("{:020}.dat", timestamp);
-        let idx = format!("{:020}.idx", timestamp);
+    fn open_read_only(path: PathBuf, timestamp: u64, next_id: usize) -> std::io::Result<Segment> {
+        let (dat_path, idx_path) = Self::paths(path, timestamp);
+        Self::from_files(
+            timestamp,
+            next_id,
+            DataFile::open_read_only(dat_path)?,
+            IndexFile::open_read_only(idx_path)?,
+        )
+    }
 
+    fn from_files(
+        timestamp: u64,
+        next_id: usize,
+        data_file: DataFile,
+        index_file: IndexFile,
+    ) -> std::io::Result<Segment> {
+        let (first_id, end_id) = match index_file.len {
+            0 => (next_id, next_id),
+            len => (
+                index_file.entry(0)?.id as usize,
+                index_file.entry(len - 1)?.id as usize + 1,
+            ),
+        };
+
+        Ok(Segment {
+            timestamp,
+            format_version: data_file.format_version(),
+            first_id,
+            end_id,
+            data_file,
+            index_file,
+        })
+    }
+
+    fn paths(path: PathBuf, timestamp: u64) -> (PathBuf, PathBuf) {
         let mut dat_path = path.clone();
-        dat_path.push(dat);
+        dat_path.push(format!("{:020}.dat", timestamp));
 
         let mut idx_path = path;
-        idx_path.push(idx);
+        idx_path.push(format!("{:020}.idx", timestamp));
 
-        Segment {
-            timestamp,
-            format_version: FormatVersion::Uncompressed,
-            data_file: DataFile::create(dat_path),
-            index_file: IndexFile::create(idx_path, timestamp),
+        (dat_path, idx_path)
+    }
+
+    /// The id of the first message in the Segment.
+    pub fn first_id(&self) -> usize {
+        self.first_id
+    }
+
+    /// The id following the last message in the Segment.
+    pub fn end_id(&self) -> usize {
+        self.end_id
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.index_file.len == 0
+    }
+
+    /// The ingest time of the newest message in the Segment, in milliseconds since the Unix Epoch.
+    /// An empty Segment reports its Epoch.
+    pub fn last_time(&self) -> u64 {
+        self.timestamp + self.index_file.last_time as u64
+    }
+
+    /// Number of bytes the Segment takes up on disk.
+    pub fn size_on_disk(&self) -> std::io::Result<u64> {
+        Ok(self.data_file.file.metadata()?.len() + self.index_file.file.metadata()?.len())
+    }
+
+    /// Removes the Segment's files from disk.
+    pub fn delete(self, path: PathBuf) -> std::io::Result<()> {
+        let (dat_path, idx_path) = Self::paths(path, self.timestamp);
+        // Segments are found by their DataFile, so removing it first means a crash in between
+        // leaves a stray IndexFile rather than a Segment that can't be opened.
+        std::fs::remove_file(dat_path)?;
+        std::fs::remove_file(idx_path)
+    }
+
+    /// Returns whether a message whose entry is `entry_len` bytes, as given by `entry_len()`,
+    /// ingested at `time` should go to a new Segment instead of this one. An empty Segment is never
+    /// full, so a message larger than `max_size` still gets written.
+    pub fn is_full(&self, entry_len: usize, time: u64, max_size: u64, max_age: u64) -> bool {
+        if self.index_file.len == 0 {
+            return false;
         }
+
+        // Age is measured by ingest time rather than the clock, so that imported messages with
+        // old ingest times are split into Segments the same way they would have been originally.
+        let age = time.saturating_sub(self.timestamp);
+        let size =
+            self.data_file.len + self.data_file.version.entry_header_len() + entry_len as u64;
+
+        // The IndexFile stores ingest times as 32bit offsets from the Epoch, so that caps the age
+        // regardless of configuration.
+        size > max_size || age >= max_age.saturating_mul(1000) || age >= u32::MAX as u64
+    }
+
+    /// Opens new handles to the Segment's files, for fsyncing them without borrowing the Segment.
+    pub fn file_handles(&self) -> std::io::Result<Vec<File>> {
+        Ok(vec![
+            self.data_file.file.try_clone()?,
+            self.index_file.file.try_clone()?,
+        ])
     }
+
+    /// Flushes the Segment to disk. Once sealed, a Segment is never written to again.
+    pub fn seal(&self) -> std::io::Result<()> {
+        self.data_file.file.sync_all()?;
+        self.index_file.file.sync_all()
+    }
+
+    /// Prepares to rewrite a sealed Segment's DataFile with the given compression, returning None
+    /// if there is nothing to do because it is already compressed. The Segment's files are opened
+    /// again, so the compressed copy can be written without holding the Segment, which only has
+    /// to be held again to swap it in with finish_compression.
+    pub fn start_compression(
+        &self,
+        path: PathBuf,
+        compression: Compression,
+    ) -> std::io::Result<Option<PendingCompression>> {
+        if compression == Compression::None || self.format_version.is_compressed() {
+            return Ok(None);
+        }
+
+        let (dat_path, idx_path) = Self::paths(path.clone(), self.timestamp);
+        Ok(Some(PendingCompression {
+            timestamp: self.timestamp,
+            tmp_path: dat_path.with_extension("dat.tmp"),
+            data_file: DataFile::open(dat_path)?,
+            index_file: IndexFile::open(idx_path)?,
+            path,
+        }))
+    }
+
+    /// Opens the Segment's files again, so that they can be read without borrowing the Segment.
+    /// Messages added to the Segment after it is reopened aren't seen by the copy.
+    pub fn reopen(&self, path: PathBuf) -> std::io::Result<Segment> {
+        Segment::open(path, self.timestamp, self.first_id)
+    }
+
+    /// Whether both Segments were opened from the same DataFile, so that neither was rewritten or
+    /// compressed since the other was opened.
+    pub fn same_files(&self, other: &Segment) -> std::io::Result<bool> {
+        let (a, b) = (
+            self.data_file.file.metadata()?,
+            other.data_file.file.metadata()?,
+        );
+        Ok(self.timestamp == other.timestamp && a.dev() == b.dev() && a.ino() == b.ino())
+    }
+
+    /// Swaps in the compressed DataFile written by `pending`. If the Segment was rewritten by
+    /// compaction since the copy was started, the copy is out of date and is discarded instead.
+    pub fn finish_compression(&mut self, pending: PendingCompression) -> std::io::Result<()> {
+        let (dat_path, _) = Self::paths(pending.path.clone(), self.timestamp);
+        let (current, copied) = (
+            self.data_file.file.metadata()?,
+            pending.data_file.file.metadata()?,
+        );
+        if pending.timestamp != self.timestamp
+            || current.dev() != copied.dev()
+            || current.ino() != copied.ino()
+        {
+            return pending.discard();
+        }
+
+        // The rename is atomic, so the Segment is readable as one version or the other even if we
+        // crash here.
+        std::fs::rename(&pending.tmp_path, &dat_path)?;
+        File::open(&pending.path)?.sync_all()?;
+
+        self.data_file = DataFile::open(dat_path)?;
+        self.format_version = self.data_file.format_version();
+        Ok(())
+    }
+
+    /// Appends a message to the Segment. The message is written to the DataFile first so that the
+    /// IndexFile never points at bytes that don't exist.
+    ///
+    /// `time` is the ingest time of the message in milliseconds since the Unix Epoch. Ingest times
+    /// never decrease within a Segment, so that the IndexFile can be searched by time.
+    ///
+    /// Segments written before metadata was stored can't hold it, so it is dropped for them; the
+    /// Log starts a new Segment rather than write to one of those.
+    pub fn add_msg(
+        &mut self,
+        id: usize,
+        msg: &[u8],
+        meta: &Metadata,
+        time: u64,
+    ) -> std::io::Result<()> {
+        let time = time.saturating_sub(self.timestamp).min(u32::MAX as u64) as u32;
+
+        let entry = match self.format_version.has_metadata() {
+            true => encode_entry(msg, meta),
+            false => msg.to_vec(),
+        };
+        let position = self.data_file.append(&entry)?;
+        let indexed = self.index_file.append(IndexEntry {
+            time: time.max(self.index_file.last_time),
+            id: id as u32,
+            position: position as u32,
+        });
+        if let Err(e) = indexed {
+            // The message was never added, so its entry can't be left for the next one to follow.
+            self.data_file.truncate(position)?;
+            return Err(e);
+        }
+        self.end_id = id + 1;
+        Ok(())
+    }
+
+    /// The Segment's Epoch, in milliseconds since the Unix Epoch.
+    pub fn timestamp(&self) -> u64 {
+        self.timestamp
+    }
+
+    /// Returns the id of the first message in the Segment ingested at or after `time`, or
+    /// `end_id()` if there is none. This is a binary search over the IndexFile's time column.
+    pub fn id_at_time(&self, time: u64) -> std::io::Result<usize> {
+        let (mut low, mut high) = (0, self.index_file.len);
+        while low < high {
+            let mid = low + (high - low) / 2;
+            let entry_time = self.timestamp + self.index_file.entry(mid)?.time as u64;
+            if entry_time < time {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+
+        match low < self.index_file.len {
+            true => Ok(self.index_file.entry(low)?.id as usize),
+            false => Ok(self.end_id),
+        }
+    }
+
+    /// Finds the IndexFile entry of the message with the given id, or None if it is not in this
+    /// Segment.
+    fn entry_index(&self, id: usize) -> std::io::Result<Option<usize>> {
+        if id < self.first_id || id >= self.end_id {
+            return Ok(None);
+        }
+
+        // Without gaps the entry is at the id's distance from the first. Gaps only ever move it
+        // closer to the start.
+        let i = (id - self.first_id).min(self.index_file.len - 1);
+        if self.index_file.entry(i)?.id as usize == id {
+            return Ok(Some(i));
+        }
+        let low = self.first_entry_from(id, i)?;
+        match self.index_file.entry(low)?.id as usize == id {
+            true => Ok(Some(low)),
+            false => Ok(None),
+        }
+    }
+
+    /// Binary searches the first `len` IndexFile entries for the first with an id at or after
+    /// `id`, returning `len` if there is none.
+    fn first_entry_from(&self, id: usize, len: usize) -> std::io::Result<usize> {
+        let (mut low, mut high) = (0, len);
+        while low < high {
+            let mid = low + (high - low) / 2;
+            if (self.index_file.entry(mid)?.id as usize) < id {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+        Ok(low)
+    }
+
+    /// Reads the raw entry the `i`th IndexFile entry points to, verifying its CRC.
+    fn read_entry(&self, i: usize) -> std::io::Result<Vec<u8>> {
+        let start = self.index_file.entry(i)?.position as u64;
+        let end = if i + 1 < self.index_file.len {
+            self.index_file.entry(i + 1)?.position as u64
+        } else {
+            self.data_file.len
+        };
+        self.data_file.read(start, end)
+    }
+
+    /// Decodes the message the `i`th IndexFile entry points to, or None if it is the placeholder
+    /// of a message removed by compaction.
+    fn message_at(&self, i: usize) -> std::io::Result<Option<RawMessage>> {
+        let entry = self.index_file.entry(i)?;
+        let record = self.read_entry(i)?;
+        let decoded = match self.format_version.has_metadata() {
+            true => decode_entry(record)?,
+            false => Some((record, Metadata::default())),
+        };
+
+        Ok(decoded.map(|(body, meta)| RawMessage {
+            offset: entry.id as u64,
+            ingest_time: self.timestamp + entry.time as u64,
+            meta,
+            body,
+        }))
+    }
+
+    /// Reads the first message with an id at or after `id`, skipping over gaps left by
+    /// compaction, or None if there is none in this Segment.
+    pub fn read_from(&self, id: usize) -> std::io::Result<Option<RawMessage>> {
+        let mut i = self.first_entry_from(id, self.index_file.len)?;
+        while i < self.index_file.len {
+            if let Some(msg) = self.message_at(i)? {
+                return Ok(Some(msg));
+            }
+            i += 1;
+        }
+        Ok(None)
+    }
+
+    /// Reads the newest message in the Segment, or None if it has none.
+    pub fn last_message(&self) -> std::io::Result<Option<RawMessage>> {
+        for i in (0..self.index_file.len).rev() {
+            if let Some(msg) = self.message_at(i)? {
+                return Ok(Some(msg));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Number of messages in the Segment. Compaction only leaves placeholders in place of the
+    /// first and last messages, so only those entries are read.
+    pub fn msg_count(&self) -> std::io::Result<usize> {
+        let len = self.index_file.len;
+        let mut count = len;
+        if len > 0 && self.message_at(0)?.is_none() {
+            count -= 1;
+        }
+        if len > 1 && self.message_at(len - 1)?.is_none() {
+            count -= 1;
+        }
+        Ok(count)
+    }
+
+    /// Calls `f` with every message in the Segment, in order.
+    pub fn scan(&self, mut f: impl FnMut(RawMessage)) -> std::io::Result<()> {
+        for i in 0..self.index_file.len {
+            if let Some(msg) = self.message_at(i)? {
+                f(msg);
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads the message with the given id, or None if it is not in this Segment.
+    pub fn read(&self, id: usize) -> std::io::Result<Option<Vec<u8>>> {
+        Ok(self.read_with_meta(id)?.map(|(msg, _)| msg))
+    }
+
+    /// Reads the message with the given id along with its metadata, or None if it is not in this
+    /// Segment. Only the IndexFile entries for the message and the one after it are read to find
+    /// it in the DataFile.
+    pub fn read_with_meta(&self, id: usize) -> std::io::Result<Option<(Vec<u8>, Metadata)>> {
+        match self.entry_index(id)? {
+            Some(i) => Ok(self.message_at(i)?.map(|msg| (msg.body, msg.meta))),
+            None => Ok(None),
+        }
+    }
+
+    /// Whether the Segment stores message metadata. Segments written before it was stored don't.
+    pub fn stores_metadata(&self) -> bool {
+        self.format_version.has_metadata()
+    }
+
+    /// Returns the ingest time of the message with the given id, in milliseconds since the Unix
+    /// Epoch, or None if it is not in this Segment.
+    pub fn time_of(&self, id: usize) -> std::io::Result<Option<u64>> {
+        match self.entry_index(id)? {
+            Some(i) => Ok(Some(self.timestamp + self.index_file.entry(i)?.time as u64)),
+            None => Ok(None),
+        }
+    }
+
+    /// Rewrites a sealed Segment without the messages whose ids are in `remove`. The messages
+    /// that are kept keep their ids, leaving gaps where the others were. If the first or last
+    /// message is removed, an empty placeholder is kept in its place, so that the range of ids the
+    /// Segment covers doesn't change. The rewritten Segment is uncompressed.
+    ///
+    /// The new files are written next to the old ones and renamed over them once complete. The
+    /// IndexFile's rename to its ".compact" name is the point at which the rewrite takes effect:
+    /// if we crash before it the rewrite is discarded when the Segment is next opened, and if we
+    /// crash after it the rewrite is finished.
+    pub fn rewrite(&mut self, path: PathBuf, remove: &HashSet<usize>) -> std::io::Result<()> {
+        let (dat_path, idx_path) = Self::paths(path.clone(), self.timestamp);
+        let dat_compact = dat_path.with_extension("dat.compact");
+        let idx_compact = idx_path.with_extension("idx.compact");
+        let idx_tmp = idx_path.with_extension("idx.compact.tmp");
+
+        if let Err(e) = self.write_rewritten(&dat_compact, &idx_tmp, remove) {
+            let _ = std::fs::remove_file(&dat_compact);
+            let _ = std::fs::remove_file(&idx_tmp);
+            return Err(e);
+        }
+
+        std::fs::rename(&idx_tmp, &idx_compact)?;
+        File::open(&path)?.sync_all()?;
+        std::fs::rename(&dat_compact, &dat_path)?;
+        std::fs::rename(&idx_compact, &idx_path)?;
+        File::open(&path)?.sync_all()?;
+
+        *self = Segment::open(path, self.timestamp, self.first_id)?;
+        Ok(())
+    }
+
+    fn write_rewritten(
+        &self,
+        dat_path: &Path,
+        idx_path: &Path,
+        remove: &HashSet<usize>,
+    ) -> std::io::Result<()> {
+        let _ = std::fs::remove_file(dat_path);
+        let _ = std::fs::remove_file(idx_path);
+        let mut data_file = DataFile::create(dat_path.to_path_buf())?;
+        let mut index_file = IndexFile::create(idx_path.to_path_buf(), self.timestamp)?;
+
+        let last = self.index_file.len.saturating_sub(1);
+        for i in 0..self.index_file.len {
+            let entry = self.index_file.entry(i)?;
+            let record = if !remove.contains(&(entry.id as usize)) {
+                match self.format_version.has_metadata() {
+                    true => self.read_entry(i)?,
+                    false => encode_entry(&self.read_entry(i)?, &Metadata::default()),
+                }
+            } else if i == 0 || i == last {
+                vec![REMOVED]
+            } else {
+                continue;
+            };
+
+            let position = data_file.append(&record)?;
+            index_file.append(IndexEntry {
+                position: position as u32,
+                ..entry
+            })?;
+        }
+
+        data_file.file.sync_all()?;
+        index_file.file.sync_all()
+    }
+
+    pub fn is_compressed(&self) -> bool {
+        self.format_version.is_compressed()
+    }
+
+    /// Checks the files of the Segment named `timestamp` for corruption: their headers, that each
+    /// IndexFile entry follows on from the one before it, and the CRC of every message. Returns a
+    /// description of each problem found, which is empty if there are none.
+    pub fn verify(path: PathBuf, timestamp: u64) -> std::io::Result<Vec<String>> {
+        let (dat_path, idx_path) = Self::paths(path.clone(), timestamp);
+        let mut problems = vec![];
+
+        let data_header = read_header(&dat_path, DataFile::HEADER_LEN)?;
+        if !data_header.starts_with(DataFile::MAGIC_NUMBER) {
+            problems.push("datafile has bad magic number".to_string());
+        }
+        let index_header = read_header(&idx_path, IndexFile::HEADER_LEN)?;
+        if !index_header.starts_with(IndexFile::MAGIC_NUMBER) {
+            problems.push("indexfile has bad magic number".to_string());
+        } else if index_header[4..] != timestamp.to_le_bytes() {
+            problems.push("indexfile epoch does not match segment name".to_string());
+        }
+        if !problems.is_empty() {
+            return Ok(problems);
+        }
+
+        let segment = match Self::open_read_only(path, timestamp, 0) {
+            Ok(segment) => segment,
+            Err(e) => return Ok(vec![format!("could not open segment: {}", e)]),
+        };
+
+        let index_len = std::fs::metadata(&idx_path)?.len();
+        let entries_len = segment.index_file.len as u64 * IndexFile::ENTRY_LEN;
+        if IndexFile::HEADER_LEN + entries_len != index_len {
+            problems.push("indexfile ends with a partial entry".to_string());
+        }
+
+        let mut prev: Option<IndexEntry> = None;
+        for i in 0..segment.index_file.len {
+            let entry = segment.index_file.entry(i)?;
+            let follows = match prev {
+                None => entry.position as u64 == DataFile::HEADER_LEN,
+                Some(prev) => {
+                    entry.id > prev.id && entry.position > prev.position && entry.time >= prev.time
+                }
+            };
+            if !follows {
+                problems.push(format!(
+                    "indexfile entry {} does not follow on from the one before it: {:?}",
+                    i, entry
+                ));
+            }
+            prev = Some(entry);
+        }
+
+        // Reading a message through an inconsistent index would only report the same problem
+        // again.
+        if problems.is_empty() {
+            for i in 0..segment.index_file.len {
+                let id = segment.index_file.entry(i)?.id as usize;
+                if let Err(e) = segment.read(id) {
+                    problems.push(format!("message {}: {}", id, e));
+                }
+            }
+        }
+
+        Ok(problems)
+    }
+
+    /// The paths of the Segment's DataFile and IndexFile in the Log directory `path`.
+    pub fn file_paths(&self, path: PathBuf) -> (PathBuf, PathBuf) {
+        Self::paths(path, self.timestamp)
+    }
+
+    /// Captures the Segment's files as they are now, so they can be copied out while the Segment
+    /// keeps being written to.
+    pub fn snapshot(&self) -> std::io::Result<SegmentSnapshot> {
+        Ok(SegmentSnapshot {
+            timestamp: self.timestamp,
+            data: self.data_file.file.try_clone()?,
+            data_len: self
+                .data_file
+                .file
+                .metadata()?
+                .len()
+                .min(self.data_file.len),
+            index: self.index_file.file.try_clone()?,
+            index_len: IndexFile::HEADER_LEN + self.index_file.len as u64 * IndexFile::ENTRY_LEN,
+        })
+    }
+}
+
+/// A Segment's files at a point in time. Segments are only ever appended to, so everything up to
+/// the captured lengths stays the same however much is written afterwards.
+#[derive(Debug)]
+pub struct SegmentSnapshot {
+    timestamp: u64,
+    data: File,
+    data_len: u64,
+    index: File,
+    index_len: u64,
+}
+
+impl SegmentSnapshot {
+    /// Writes the captured part of the Segment's files into the Log directory `path`.
+    pub fn copy_into(&self, path: PathBuf) -> std::io::Result<()> {
+        let (dat_path, idx_path) = Segment::paths(path, self.timestamp);
+        copy_prefix(&self.data, self.data_len, &dat_path)?;
+        copy_prefix(&self.index, self.index_len, &idx_path)
+    }
+}
+
+/// Copies the first `len` bytes of `src` to a new file at `dst`.
+fn copy_prefix(src: &File, len: u64, dst: &Path) -> std::io::Result<()> {
+    let mut out = File::create(dst)?;
+    let mut buf = vec![0u8; 64 * 1024];
+    let mut position = 0;
+    while position < len {
+        let n = buf.len().min((len - position) as usize);
+        src.read_exact_at(&mut buf[..n], position)?;
+        out.write_all(&buf[..n])?;
+        position += n as u64;
+    }
+    out.sync_all()
+}
+
+/// The current time in milliseconds since the Unix Epoch.
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("could not get system time")
+        .as_millis() as u64
 }
 
 // Magic numbers are derived from the order of the Monster Group.
@@ -89,8 +913,6 @@ impl Segment {
 //  DataFile  Header Number - 0x86FA3F51
 //  IndexFile Header Number - 0x0644E13F
 
-/// TODO: Add message to segment
-
 // Format:
 //
 // The beginning of the Datafile has a header that includes:
@@ -100,32 +922,553 @@ impl Segment {
 // After the Header is a list of entries for each message.
 // Each message entry contains:
 //   - A 32bit CRC of the message payload.
+//   - From FormatVersion UncompressedSized on, a 32bit integer representing the length of the
+//     message payload.
 //   - The variable byte message payload.
+//
+// From FormatVersion UncompressedMeta on, the payload starts with the message's metadata:
+//   - An 8bit set of flags. With HAS_METADATA set, it is followed by:
+//     - A 32bit integer representing the length of the metadata.
+//     - The metadata, as a CBOR map with an optional "key" and "headers", and a "producer" and
+//       "sequence" if it was added by an idempotent producer.
+//   - The message body.
+//
+// An entry with the REMOVED flag set has nothing after its flags. It holds the place of a message
+// that was removed by compaction, so that the Segment still covers the same ids.
+//
+// Before FormatVersion UncompressedSized, entries are not length prefixed. The length of a payload
+// is the distance between its position and the position of the next entry in the IndexFile, or
+// the end of the file for the last entry.
+//
+// In a compressed DataFile, the list of entries is split into blocks of whole entries and each
+// block is compressed on its own. After the Header is a list of blocks, each containing:
+//   - A 32bit CRC of the compressed block.
+//   - A 32bit integer representing the length of the compressed block.
+//   - A 32bit integer representing the length of the block once decompressed.
+//   - The compressed block.
+//
+// Positions in the IndexFile always refer to where an entry would be in the uncompressed
+// DataFile, so compressing a DataFile does not change its IndexFile.
 #[derive(Debug)]
 struct DataFile {
     file: File,
+
+    /// Total length of the file in bytes, including the header. For a compressed file this is the
+    /// length it would have uncompressed.
+    len: u64,
+
+    version: FormatVersion,
+
+    /// The blocks of a compressed file, ordered by position. None if the file is uncompressed.
+    blocks: Option<Vec<Block>>,
+    /// The index and contents of the last block that was decompressed. Messages tend to be read
+    /// in order, so this saves decompressing the same block for each message in it.
+    cache: Mutex<Option<(usize, Vec<u8>)>>,
+}
+
+/// Block index entry for a compressed DataFile.
+#[derive(Debug, Clone, Copy)]
+struct Block {
+    /// Position of the block's first entry in the uncompressed DataFile.
+    start: u64,
+    /// Position of the block's header in the compressed DataFile.
+    position: u64,
+    compressed_len: u32,
+    uncompressed_len: u32,
 }
 
 impl DataFile {
     const MAGIC_NUMBER: &'static [u8] = &[0x86, 0xFA, 0x3F, 0x51];
+    const HEADER_LEN: u64 = 5;
+    const BLOCK_HEADER_LEN: u64 = 12;
+
+    /// Size in bytes a block of entries grows to before it is compressed.
+    const BLOCK_SIZE: u64 = 64 * 1024;
+
+    fn header(version: FormatVersion) -> [u8; Self::HEADER_LEN as usize] {
+        let mut header = [0u8; Self::HEADER_LEN as usize];
+        header[0..4].copy_from_slice(Self::MAGIC_NUMBER);
+        header[4] = version as u8;
+        header
+    }
 
-    fn create(path: PathBuf) -> Self {
+    fn create(path: PathBuf) -> std::io::Result<Self> {
         let mut file = OpenOptions::new()
+            .read(true)
             .append(true)
             .create(true)
-            .open(path)
-            .expect("could not create datafile");
+            .open(path)?;
+
+        file.write_all(&Self::header(FormatVersion::CURRENT))?;
+
+        Ok(Self {
+            file,
+            len: Self::HEADER_LEN,
+            version: FormatVersion::CURRENT,
+            blocks: None,
+            cache: Mutex::new(None),
+        })
+    }
+
+    fn open(path: PathBuf) -> std::io::Result<Self> {
+        Self::from_file(OpenOptions::new().read(true).append(true).open(path)?)
+    }
+
+    fn open_read_only(path: PathBuf) -> std::io::Result<Self> {
+        Self::from_file(File::open(path)?)
+    }
+
+    fn from_file(file: File) -> std::io::Result<Self> {
+        let file_len = file.metadata()?.len();
+
+        let mut header = [0u8; Self::HEADER_LEN as usize];
+        file.read_exact_at(&mut header, 0)?;
+        if &header[0..4] != Self::MAGIC_NUMBER {
+            return Err(invalid_data("datafile has bad magic number".to_string()));
+        }
+
+        let version = FormatVersion::from_u8(header[4]).ok_or_else(|| {
+            invalid_data(format!(
+                "unknown datafile format version {:#04x}",
+                header[4]
+            ))
+        })?;
+        let mut data_file = Self {
+            file,
+            len: file_len,
+            version,
+            blocks: None,
+            cache: Mutex::new(None),
+        };
+        if version.is_compressed() {
+            data_file.load_blocks(file_len)?;
+        }
+
+        Ok(data_file)
+    }
+
+    /// Builds the block index of a compressed file by walking the block headers.
+    fn load_blocks(&mut self, file_len: u64) -> std::io::Result<()> {
+        let mut blocks = vec![];
+        let (mut start, mut position) = (Self::HEADER_LEN, Self::HEADER_LEN);
 
-        file.write_all(Self::MAGIC_NUMBER)
-            .expect("could not write initial bytes to datafile");
+        while position < file_len {
+            let mut header = [0u8; Self::BLOCK_HEADER_LEN as usize];
+            self.file.read_exact_at(&mut header, position)?;
 
-        file.write_all(&(0x00 as u8).to_le_bytes())
-            .expect("could not write initial bytes to datafile");
+            let block = Block {
+                start,
+                position,
+                compressed_len: u32::from_le_bytes([header[4], header[5], header[6], header[7]]),
+                uncompressed_len: u32::from_le_bytes([
+                    header[8], header[9], header[10], header[11],
+                ]),
+            };
+
+            start += block.uncompressed_len as u64;
+            position += Self::BLOCK_HEADER_LEN + block.compressed_len as u64;
+            blocks.push(block);
+        }
 
-        Self { file }
+        self.len = start;
+        self.blocks = Some(blocks);
+        Ok(())
+    }
+
+    fn format_version(&self) -> FormatVersion {
+        self.version
+    }
+
+    /// Appends a message entry to the file, returning the byte position the entry starts at.
+    fn append(&mut self, msg: &[u8]) -> std::io::Result<u64> {
+        let mut entry = Vec::with_capacity(self.version.entry_header_len() as usize + msg.len());
+        entry.extend_from_slice(&crc::checksum(msg).to_le_bytes());
+        if self.version.has_length() {
+            entry.extend_from_slice(&(msg.len() as u32).to_le_bytes());
+        }
+        entry.extend_from_slice(msg);
+
+        let position = self.len;
+        append_or_truncate(&mut self.file, position, &entry)?;
+        self.len += entry.len() as u64;
+        Ok(position)
+    }
+
+    /// Cuts the file back to `len` bytes, dropping the entries from there on.
+    fn truncate(&mut self, len: u64) -> std::io::Result<()> {
+        self.file.set_len(len)?;
+        self.len = len;
+        Ok(())
+    }
+
+    /// Reads the entry spanning the bytes `start..end`, verifying its CRC and length.
+    fn read(&self, start: u64, end: u64) -> std::io::Result<Vec<u8>> {
+        let mut entry = match &self.blocks {
+            None => {
+                let mut entry = vec![0u8; (end - start) as usize];
+                self.file.read_exact_at(&mut entry, start)?;
+                entry
+            }
+            Some(blocks) => self.read_compressed(blocks, start, end)?,
+        };
+
+        let msg = entry.split_off(self.version.entry_header_len() as usize);
+        if self.version.has_length()
+            && u32::from_le_bytes([entry[4], entry[5], entry[6], entry[7]]) as usize != msg.len()
+        {
+            return Err(invalid_data(format!(
+                "length mismatch for datafile entry at byte {}",
+                start
+            )));
+        }
+        let expected = u32::from_le_bytes([entry[0], entry[1], entry[2], entry[3]]);
+        if crc::checksum(&msg) != expected {
+            return Err(invalid_data(format!(
+                "crc mismatch for datafile entry at byte {}",
+                start
+            )));
+        }
+
+        Ok(msg)
+    }
+
+    fn read_compressed(&self, blocks: &[Block], start: u64, end: u64) -> std::io::Result<Vec<u8>> {
+        let i = blocks.partition_point(|b| b.start <= start);
+        if i == 0 {
+            return Err(invalid_data(format!("no block contains byte {}", start)));
+        }
+        let block = blocks[i - 1];
+        if end > block.start + block.uncompressed_len as u64 {
+            return Err(invalid_data(format!(
+                "entry at byte {} spans multiple blocks",
+                start
+            )));
+        }
+
+        let mut cache = self
+            .cache
+            .lock()
+            .expect("unwrapped poisoned block cache lock");
+        if !matches!(&*cache, Some((cached, _)) if *cached == i - 1) {
+            *cache = Some((i - 1, self.decompress(block)?));
+        }
+
+        let (_, contents) = cache.as_ref().unwrap();
+        let offset = (start - block.start) as usize;
+        Ok(contents[offset..offset + (end - start) as usize].to_vec())
+    }
+
+    fn decompress(&self, block: Block) -> std::io::Result<Vec<u8>> {
+        let mut header = [0u8; 4];
+        self.file.read_exact_at(&mut header, block.position)?;
+
+        let mut compressed = vec![0u8; block.compressed_len as usize];
+        self.file
+            .read_exact_at(&mut compressed, block.position + Self::BLOCK_HEADER_LEN)?;
+        if crc::checksum(&compressed) != u32::from_le_bytes(header) {
+            return Err(invalid_data(format!(
+                "crc mismatch for datafile block at byte {}",
+                block.position
+            )));
+        }
+
+        lz4_flex::block::decompress(&compressed, block.uncompressed_len as usize)
+            .map_err(|e| invalid_data(format!("could not decompress block: {}", e)))
+    }
+
+    /// Writes a compressed copy of this file to `path`. The block boundaries are taken from
+    /// `index`, so that no entry is split across two blocks.
+    fn write_compressed(&self, index: &IndexFile, path: &Path) -> std::io::Result<()> {
+        let mut out = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+
+        out.write_all(Self::MAGIC_NUMBER)?;
+        out.write_all(&[self.version.compressed() as u8])?;
+
+        let position = |i: usize| -> std::io::Result<u64> {
+            if i < index.len {
+                Ok(index.entry(i)?.position as u64)
+            } else {
+                Ok(self.len)
+            }
+        };
+
+        let mut i = 0;
+        while i < index.len {
+            let block_start = position(i)?;
+            let mut j = i + 1;
+            while j < index.len && position(j)? - block_start < Self::BLOCK_SIZE {
+                j += 1;
+            }
+
+            let mut block = vec![0u8; (position(j)? - block_start) as usize];
+            self.file.read_exact_at(&mut block, block_start)?;
+            let compressed = lz4_flex::block::compress(&block);
+
+            out.write_all(&crc::checksum(&compressed).to_le_bytes())?;
+            out.write_all(&(compressed.len() as u32).to_le_bytes())?;
+            out.write_all(&(block.len() as u32).to_le_bytes())?;
+            out.write_all(&compressed)?;
+            i = j;
+        }
+
+        out.sync_all()
+    }
+}
+
+/// A compressed copy of a sealed Segment's DataFile, written next to it while the Log carries on.
+/// See Segment::start_compression.
+#[derive(Debug)]
+pub struct PendingCompression {
+    /// The Segment being compressed, and the Log directory it is in.
+    timestamp: u64,
+    path: PathBuf,
+    tmp_path: PathBuf,
+    data_file: DataFile,
+    index_file: IndexFile,
+}
+
+impl PendingCompression {
+    /// Writes the compressed copy. This is the slow part, which reads the whole Segment.
+    pub fn write(&self) -> std::io::Result<()> {
+        if let Err(e) = self
+            .data_file
+            .write_compressed(&self.index_file, &self.tmp_path)
+        {
+            let _ = std::fs::remove_file(&self.tmp_path);
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// The timestamp of the Segment being compressed.
+    pub fn timestamp(&self) -> u64 {
+        self.timestamp
+    }
+
+    /// Removes the copy, for when the Segment it was made from is gone or has changed.
+    pub fn discard(self) -> std::io::Result<()> {
+        match std::fs::remove_file(&self.tmp_path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Reads the first `len` bytes of the file at `path`, or fewer if it is shorter than that.
+fn read_header(path: &Path, len: u64) -> std::io::Result<Vec<u8>> {
+    let mut header = vec![];
+    File::open(path)?.take(len).read_to_end(&mut header)?;
+    Ok(header)
+}
+
+fn invalid_data(msg: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, msg)
+}
+
+/// Entry flag set when the message's metadata follows it.
+const HAS_METADATA: u8 = 0x01;
+/// Entry flag set on the placeholder of a message removed by compaction.
+const REMOVED: u8 = 0x02;
+
+/// The number of bytes a message's payload takes up in a DataFile, not counting the CRC and length
+/// before it.
+pub fn entry_len(msg: &[u8], meta: &Metadata) -> usize {
+    match encode_metadata(meta) {
+        Some(encoded) => 1 + 4 + encoded.len() + msg.len(),
+        None => 1 + msg.len(),
     }
 }
 
+fn encode_entry(msg: &[u8], meta: &Metadata) -> Vec<u8> {
+    let encoded = encode_metadata(meta);
+    let mut entry = Vec::with_capacity(1 + encoded.as_ref().map_or(0, |e| 4 + e.len()) + msg.len());
+    match encoded {
+        Some(encoded) => {
+            entry.push(HAS_METADATA);
+            entry.extend_from_slice(&(encoded.len() as u32).to_le_bytes());
+            entry.extend_from_slice(&encoded);
+        }
+        None => entry.push(0),
+    }
+    entry.extend_from_slice(msg);
+    entry
+}
+
+/// Splits an entry into its body and metadata, or None if it is a placeholder.
+fn decode_entry(mut entry: Vec<u8>) -> std::io::Result<Option<(Vec<u8>, Metadata)>> {
+    let flags = *entry
+        .first()
+        .ok_or_else(|| invalid_data("datafile entry has no flags".to_string()))?;
+    if flags & REMOVED != 0 {
+        return Ok(None);
+    }
+    if flags & HAS_METADATA == 0 {
+        return Ok(Some((entry.split_off(1), Metadata::default())));
+    }
+
+    let meta_end = entry
+        .get(1..5)
+        .map(|len| 5 + u32::from_le_bytes([len[0], len[1], len[2], len[3]]) as usize)
+        .filter(|end| *end <= entry.len())
+        .ok_or_else(|| invalid_data("datafile entry has truncated metadata".to_string()))?;
+    let meta = decode_metadata(&entry[5..meta_end])?;
+    Ok(Some((entry.split_off(meta_end), meta)))
+}
+
+/// Encodes metadata as a CBOR map, or None if there is none to store.
+fn encode_metadata(meta: &Metadata) -> Option<Vec<u8>> {
+    if meta.is_empty() {
+        return None;
+    }
+
+    let mut map = std::collections::BTreeMap::new();
+    if let Some(key) = &meta.key {
+        map.insert(CborValue::Text("key".into()), CborValue::Text(key.clone()));
+    }
+    if !meta.headers.is_empty() {
+        let headers = meta
+            .headers
+            .iter()
+            .map(|(k, v)| (CborValue::Text(k.clone()), CborValue::Text(v.clone())))
+            .collect();
+        map.insert(CborValue::Text("headers".into()), CborValue::Map(headers));
+    }
+    if let Some(producer) = &meta.producer {
+        map.insert(
+            CborValue::Text("producer".into()),
+            CborValue::Text(producer.id.clone()),
+        );
+        map.insert(
+            CborValue::Text("sequence".into()),
+            CborValue::Integer(producer.sequence.into()),
+        );
+    }
+    Some(serde_cbor::to_vec(&CborValue::Map(map)).expect("metadata is always encodable"))
+}
+
+fn decode_metadata(bytes: &[u8]) -> std::io::Result<Metadata> {
+    let bad = || invalid_data("datafile entry has malformed metadata".to_string());
+    let map = match serde_cbor::from_slice(bytes).map_err(|_| bad())? {
+        CborValue::Map(map) => map,
+        _ => return Err(bad()),
+    };
+
+    let mut meta = Metadata::default();
+    let (mut producer, mut sequence) = (None, None);
+    for (k, v) in map {
+        match (k, v) {
+            (CborValue::Text(k), CborValue::Text(key)) if k == "key" => meta.key = Some(key),
+            (CborValue::Text(k), CborValue::Map(headers)) if k == "headers" => {
+                for (name, value) in headers {
+                    match (name, value) {
+                        (CborValue::Text(name), CborValue::Text(value)) => {
+                            meta.headers.insert(name, value);
+                        }
+                        _ => return Err(bad()),
+                    }
+                }
+            }
+            (CborValue::Text(k), CborValue::Text(id)) if k == "producer" => producer = Some(id),
+            (CborValue::Text(k), CborValue::Integer(seq)) if k == "sequence" => {
+                sequence = Some(u64::try_from(seq).map_err(|_| bad())?)
+            }
+            // Fields added by later versions are skipped.
+            _ => (),
+        }
+    }
+    meta.producer = match (producer, sequence) {
+        (Some(id), Some(sequence)) => Some(ProducerSequence { id, sequence }),
+        (None, None) => None,
+        _ => return Err(bad()),
+    };
+    Ok(meta)
+}
+
+/// Writes `buf` to the end of `file`, which is `len` bytes long. If the write fails, the file is cut
+/// back to `len` so that none of `buf` is left for the next write to follow.
+fn append_or_truncate(file: &mut File, len: u64, buf: &[u8]) -> std::io::Result<()> {
+    #[cfg(test)]
+    let res = tests::write_all_or_fail(file, buf);
+    #[cfg(not(test))]
+    let res = file.write_all(buf);
+
+    if let Err(e) = res {
+        file.set_len(len)?;
+        return Err(e);
+    }
+    Ok(())
+}
+
+/// Finds where the uncompressed DataFile entry at `position` ends, returning None if its payload
+/// doesn't match its CRC.
+fn entry_end(
+    data: &File,
+    version: FormatVersion,
+    position: u64,
+    data_len: u64,
+) -> std::io::Result<Option<u64>> {
+    if !version.has_length() {
+        return payload_end(data, position, data_len);
+    }
+
+    let mut header = [0u8; 8];
+    data.read_exact_at(&mut header, position)?;
+    let expected = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+    let len = u32::from_le_bytes([header[4], header[5], header[6], header[7]]) as u64;
+
+    let end = position + 8 + len;
+    if end > data_len {
+        return Ok(None);
+    }
+    let mut payload = vec![0u8; len as usize];
+    data.read_exact_at(&mut payload, position + 8)?;
+    if crc::checksum(&payload) != expected {
+        return Ok(None);
+    }
+    Ok(Some(end))
+}
+
+/// Finds where the payload of the DataFile entry at `position` ends in a version without length
+/// prefixes, by looking for the end that makes it match its CRC. Returns None if no end does.
+///
+/// Bytes from a message that was never indexed may follow the entry, so the whole rest of the file
+/// is tried first, and then the shortest payload that matches.
+fn payload_end(data: &File, position: u64, data_len: u64) -> std::io::Result<Option<u64>> {
+    let mut expected = [0u8; 4];
+    data.read_exact_at(&mut expected, position)?;
+    let expected = u32::from_le_bytes(expected);
+
+    let mut crc = crc::Crc32::new();
+    let mut end = position + 4;
+    let mut shortest = if crc.finish() == expected {
+        Some(end)
+    } else {
+        None
+    };
+
+    let mut buf = vec![0u8; 64 * 1024];
+    while end < data_len {
+        let n = ((data_len - end) as usize).min(buf.len());
+        data.read_exact_at(&mut buf[..n], end)?;
+        for (i, b) in buf[..n].iter().enumerate() {
+            crc.update(std::slice::from_ref(b));
+            if shortest.is_none() && crc.finish() == expected {
+                shortest = Some(end + i as u64 + 1);
+            }
+        }
+        end += n as u64;
+    }
+
+    if crc.finish() == expected {
+        return Ok(Some(data_len));
+    }
+    Ok(shortest)
+}
+
 // Format:
 //
 // The IndexFile begins with a header that includes:
@@ -143,30 +1486,718 @@ impl DataFile {
 #[derive(Debug)]
 struct IndexFile {
     file: File,
+
+    /// Number of entries in the file.
+    len: usize,
+    /// The time column of the last entry in the file.
+    last_time: u32,
 }
 
 impl IndexFile {
     const MAGIC_NUMBER: &'static [u8] = &[0x06, 0x44, 0xE1, 0x3F];
+    const HEADER_LEN: u64 = 12;
+    const ENTRY_LEN: u64 = 12;
 
-    fn create(path: PathBuf, ts: u64) -> Self {
+    fn header(ts: u64) -> [u8; Self::HEADER_LEN as usize] {
+        let mut header = [0u8; Self::HEADER_LEN as usize];
+        header[0..4].copy_from_slice(Self::MAGIC_NUMBER);
+        header[4..12].copy_from_slice(&ts.to_le_bytes());
+        header
+    }
+
+    fn create(path: PathBuf, ts: u64) -> std::io::Result<Self> {
         let mut file = OpenOptions::new()
+            .read(true)
             .append(true)
             .create(true)
-            .open(path)
-            .expect("could not create datafile");
+            .open(path)?;
+
+        file.write_all(&Self::header(ts))?;
+
+        Ok(Self {
+            file,
+            len: 0,
+            last_time: 0,
+        })
+    }
+
+    fn open(path: PathBuf) -> std::io::Result<Self> {
+        Self::from_file(OpenOptions::new().read(true).append(true).open(path)?)
+    }
+
+    fn open_read_only(path: PathBuf) -> std::io::Result<Self> {
+        Self::from_file(File::open(path)?)
+    }
+
+    fn from_file(file: File) -> std::io::Result<Self> {
+        let mut magic = [0u8; 4];
+        file.read_exact_at(&mut magic, 0)?;
+        if magic != Self::MAGIC_NUMBER {
+            return Err(invalid_data("indexfile has bad magic number".to_string()));
+        }
+
+        // A trailing partial entry is ignored here. Segment::repair_active removes it from the
+        // active Segment, and sealed Segments never have one.
+        let bytes = file.metadata()?.len();
+        let len = (bytes.saturating_sub(Self::HEADER_LEN) / Self::ENTRY_LEN) as usize;
+
+        let mut index = Self {
+            file,
+            len,
+            last_time: 0,
+        };
+        if len > 0 {
+            index.last_time = index.entry(len - 1)?.time;
+        }
+        Ok(index)
+    }
+
+    fn append(&mut self, entry: IndexEntry) -> std::io::Result<()> {
+        let len = Self::HEADER_LEN + self.len as u64 * Self::ENTRY_LEN;
+        append_or_truncate(&mut self.file, len, &entry.to_bytes())?;
+        self.len += 1;
+        self.last_time = entry.time;
+        Ok(())
+    }
 
-        file.write_all(Self::MAGIC_NUMBER)
-            .expect("could not write initial bytes to datafile");
+    /// Reads the `i`th entry of the file.
+    fn entry(&self, i: usize) -> std::io::Result<IndexEntry> {
+        let mut buf = [0u8; Self::ENTRY_LEN as usize];
+        self.file
+            .read_exact_at(&mut buf, Self::HEADER_LEN + i as u64 * Self::ENTRY_LEN)?;
+        Ok(IndexEntry::from_bytes(&buf))
+    }
+}
 
-        file.write_all(&ts.to_le_bytes())
-            .expect("could not write initial bytes to datafile");
+/// A single entry of an IndexFile.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct IndexEntry {
+    /// Milliseconds since the Segment's Epoch.
+    time: u32,
+    /// Id of the message within the Log.
+    id: u32,
+    /// Byte position of the message entry in the DataFile.
+    position: u32,
+}
 
-        Self { file }
+impl IndexEntry {
+    fn to_bytes(self) -> [u8; 12] {
+        let mut buf = [0u8; 12];
+        buf[0..4].copy_from_slice(&self.time.to_le_bytes());
+        buf[4..8].copy_from_slice(&self.id.to_le_bytes());
+        buf[8..12].copy_from_slice(&self.position.to_le_bytes());
+        buf
     }
+
+    fn from_bytes(buf: &[u8]) -> Self {
+        let word = |i: usize| u32::from_le_bytes([buf[i], buf[i + 1], buf[i + 2], buf[i + 3]]);
+        IndexEntry {
+            time: word(0),
+            id: word(4),
+            position: word(8),
+        }
+    }
+}
+
+/// What Segment::repair_active changed to make a Segment consistent again.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RepairReport {
+    /// Epoch of the repaired Segment, which is also its name.
+    pub segment: u64,
+    /// Messages left in the Segment.
+    pub msgs_kept: usize,
+    /// Messages removed from the end of the Segment.
+    pub msgs_dropped: usize,
+    /// Bytes removed from the end of the IndexFile.
+    pub index_bytes_truncated: u64,
+    /// Bytes removed from the end of the DataFile.
+    pub data_bytes_truncated: u64,
+    /// Every kind of damage that was found, in the order it was found walking back from the end.
+    pub reasons: Vec<RepairReason>,
+}
+
+/// A kind of damage a crash can leave at the end of a Segment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RepairReason {
+    /// The Segment's files were created, but their headers weren't fully written.
+    IncompleteHeader,
+    /// Only part of an IndexFile entry was written.
+    PartialIndexEntry,
+    /// An IndexFile entry doesn't follow on from the one before it, or points past the end of the
+    /// DataFile.
+    InconsistentIndexEntry,
+    /// A message's payload doesn't match its CRC.
+    CrcMismatch,
+    /// The DataFile has bytes after the last message, from a message that was never indexed.
+    UnindexedData,
 }
 
 #[repr(u8)]
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 enum FormatVersion {
     Uncompressed = 0x00,
+    /// Entries are stored in LZ4 compressed blocks.
+    Lz4 = 0x01,
+    /// Like Uncompressed, but each entry's payload starts with the message's metadata.
+    UncompressedMeta = 0x02,
+    /// Like Lz4, but each entry's payload starts with the message's metadata.
+    Lz4Meta = 0x03,
+    /// Like UncompressedMeta, but each entry's CRC is followed by the length of its payload.
+    UncompressedSized = 0x04,
+    /// Like Lz4Meta, but each entry's CRC is followed by the length of its payload.
+    Lz4Sized = 0x05,
+}
+
+impl FormatVersion {
+    /// The version new Segments are written with.
+    const CURRENT: FormatVersion = FormatVersion::UncompressedSized;
+
+    fn from_u8(v: u8) -> Option<FormatVersion> {
+        match v {
+            0x00 => Some(FormatVersion::Uncompressed),
+            0x01 => Some(FormatVersion::Lz4),
+            0x02 => Some(FormatVersion::UncompressedMeta),
+            0x03 => Some(FormatVersion::Lz4Meta),
+            0x04 => Some(FormatVersion::UncompressedSized),
+            0x05 => Some(FormatVersion::Lz4Sized),
+            _ => None,
+        }
+    }
+
+    fn is_compressed(self) -> bool {
+        matches!(
+            self,
+            FormatVersion::Lz4 | FormatVersion::Lz4Meta | FormatVersion::Lz4Sized
+        )
+    }
+
+    fn has_metadata(self) -> bool {
+        !matches!(self, FormatVersion::Uncompressed | FormatVersion::Lz4)
+    }
+
+    fn has_length(self) -> bool {
+        matches!(
+            self,
+            FormatVersion::UncompressedSized | FormatVersion::Lz4Sized
+        )
+    }
+
+    /// The number of bytes in front of each entry's payload: its CRC, and its length if it has one.
+    fn entry_header_len(self) -> u64 {
+        if self.has_length() {
+            8
+        } else {
+            4
+        }
+    }
+
+    /// The version a DataFile of this version has once it is compressed.
+    fn compressed(self) -> FormatVersion {
+        match self {
+            FormatVersion::Uncompressed => FormatVersion::Lz4,
+            FormatVersion::UncompressedMeta => FormatVersion::Lz4Meta,
+            FormatVersion::UncompressedSized => FormatVersion::Lz4Sized,
+            v => v,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::temp_db_path;
+    use std::cell::Cell;
+
+    thread_local! {
+        /// Makes a write to a Segment file on this thread fail, after letting through the given
+        /// number of writes and then writing the given number of bytes of the failing one.
+        static SHORT_WRITE: Cell<Option<(usize, usize)>> = const { Cell::new(None) };
+    }
+
+    pub fn write_all_or_fail(file: &mut File, buf: &[u8]) -> std::io::Result<()> {
+        match SHORT_WRITE.with(Cell::get) {
+            Some((0, written)) => {
+                SHORT_WRITE.with(|s| s.set(None));
+                file.write_all(&buf[..written.min(buf.len())])?;
+                Err(std::io::Error::new(
+                    std::io::ErrorKind::WriteZero,
+                    "short write",
+                ))
+            }
+            Some((writes, written)) => {
+                SHORT_WRITE.with(|s| s.set(Some((writes - 1, written))));
+                file.write_all(buf)
+            }
+            None => file.write_all(buf),
+        }
+    }
+
+    fn compress(segment: &mut Segment, path: &Path) {
+        let pending = segment
+            .start_compression(path.to_path_buf(), Compression::Lz4)
+            .unwrap()
+            .unwrap();
+        pending.write().unwrap();
+        segment.finish_compression(pending).unwrap();
+    }
+
+    #[test]
+    fn test_segment_persists_messages() {
+        let path = PathBuf::from(temp_db_path());
+        let mut segment = Segment::get_all_for(path.clone()).unwrap().pop().unwrap();
+        segment
+            .add_msg(0, &[0x19, 0x03, 0xE8], &Metadata::default(), now_millis())
+            .unwrap();
+        segment
+            .add_msg(1, &[0x61, 0x61], &Metadata::default(), now_millis())
+            .unwrap();
+        assert_eq!(segment.index_file.len, 2);
+
+        let reopened = Segment::get_all_for(path).unwrap().pop().unwrap();
+        assert_eq!(reopened.timestamp, segment.timestamp);
+        assert_eq!(reopened.index_file.len, 2);
+        assert_eq!(reopened.read(0).unwrap(), Some(vec![0x19, 0x03, 0xE8]));
+        assert_eq!(reopened.read(1).unwrap(), Some(vec![0x61, 0x61]));
+        assert_eq!(reopened.read(2).unwrap(), None);
+    }
+
+    #[test]
+    fn test_segment_persists_metadata() {
+        let path = PathBuf::from(temp_db_path());
+        let mut segment = Segment::get_all_for(path.clone()).unwrap().pop().unwrap();
+        let meta = Metadata {
+            key: Some("user-1".to_string()),
+            headers: vec![("source".to_string(), "web".to_string())]
+                .into_iter()
+                .collect(),
+            producer: Some(ProducerSequence {
+                id: "p1".to_string(),
+                sequence: 7,
+            }),
+        };
+        segment.add_msg(0, b"abc", &meta, now_millis()).unwrap();
+        segment
+            .add_msg(1, b"def", &Metadata::default(), now_millis())
+            .unwrap();
+        assert_eq!(
+            segment.data_file.len as usize,
+            DataFile::HEADER_LEN as usize
+                + 8
+                + entry_len(b"abc", &meta)
+                + 8
+                + entry_len(b"def", &Metadata::default())
+        );
+
+        segment.seal().unwrap();
+        compress(&mut segment, &path);
+        let reopened = Segment::get_all_for(path).unwrap().pop().unwrap();
+        assert_eq!(
+            reopened.read_with_meta(0).unwrap(),
+            Some((b"abc".to_vec(), meta))
+        );
+        assert_eq!(
+            reopened.read_with_meta(1).unwrap(),
+            Some((b"def".to_vec(), Metadata::default()))
+        );
+    }
+
+    #[test]
+    fn test_segment_reads_format_without_metadata() {
+        let path = PathBuf::from(temp_db_path());
+        std::fs::create_dir_all(&path).unwrap();
+        let (dat_path, idx_path) = Segment::paths(path.clone(), 1000);
+
+        let mut dat = DataFile::header(FormatVersion::Uncompressed).to_vec();
+        dat.extend_from_slice(&crc::checksum(b"abc").to_le_bytes());
+        dat.extend_from_slice(b"abc");
+        std::fs::write(&dat_path, dat).unwrap();
+        let mut idx = IndexFile::header(1000).to_vec();
+        idx.extend_from_slice(
+            &IndexEntry {
+                time: 0,
+                id: 0,
+                position: DataFile::HEADER_LEN as u32,
+            }
+            .to_bytes(),
+        );
+        std::fs::write(&idx_path, idx).unwrap();
+
+        let segment = Segment::get_all_for(path.clone()).unwrap().pop().unwrap();
+        assert!(!segment.stores_metadata());
+        assert_eq!(
+            segment.read_with_meta(0).unwrap(),
+            Some((b"abc".to_vec(), Metadata::default()))
+        );
+        assert!(Segment::verify(path, 1000).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_segment_file_layout() {
+        let path = PathBuf::from(temp_db_path());
+        let mut segment = Segment::get_all_for(path.clone()).unwrap().pop().unwrap();
+        segment
+            .add_msg(0, b"abc", &Metadata::default(), now_millis())
+            .unwrap();
+
+        let (dat_path, idx_path) = Segment::paths(path, segment.timestamp);
+        let dat = std::fs::read(dat_path).unwrap();
+        assert_eq!(&dat[0..4], DataFile::MAGIC_NUMBER);
+        assert_eq!(dat[4], FormatVersion::UncompressedSized as u8);
+        assert_eq!(&dat[5..9], &crc::checksum(b"\0abc").to_le_bytes());
+        assert_eq!(&dat[9..13], &4u32.to_le_bytes());
+        assert_eq!(dat[13], 0);
+        assert_eq!(&dat[14..], b"abc");
+
+        let idx = std::fs::read(idx_path).unwrap();
+        assert_eq!(&idx[0..4], IndexFile::MAGIC_NUMBER);
+        assert_eq!(&idx[4..12], &segment.timestamp.to_le_bytes());
+        let entry = IndexEntry::from_bytes(&idx[12..24]);
+        assert_eq!(entry.id, 0);
+        assert_eq!(entry.position, 5);
+    }
+
+    #[test]
+    fn test_segment_is_full() {
+        let path = PathBuf::from(temp_db_path());
+        let mut segment = Segment::get_all_for(path.clone()).unwrap().pop().unwrap();
+        let now = now_millis();
+        assert!(!segment.is_full(100, now, 10, DEFAULT_MAX_SEGMENT_AGE));
+
+        segment
+            .add_msg(0, b"abc", &Metadata::default(), now)
+            .unwrap();
+        // header (5) + entry (8 + 1 + 3) + next entry (8 + 1 + 3)
+        assert!(!segment.is_full(4, now, 29, DEFAULT_MAX_SEGMENT_AGE));
+        assert!(segment.is_full(5, now, 29, DEFAULT_MAX_SEGMENT_AGE));
+        assert!(segment.is_full(4, now, DEFAULT_MAX_SEGMENT_SIZE, 0));
+        // Messages ingested too long after the Epoch can't be indexed.
+        let later = segment.timestamp + u32::MAX as u64;
+        assert!(segment.is_full(4, later, DEFAULT_MAX_SEGMENT_SIZE, u64::MAX));
+
+        let next = segment.create_next(path.clone(), now).unwrap();
+        assert!(next.timestamp > segment.timestamp);
+        assert_eq!(next.first_id(), 1);
+
+        let segments = Segment::get_all_for(path).unwrap();
+        assert_eq!(segments.len(), 2);
+        assert_eq!(segments[1].first_id(), 1);
+    }
+
+    #[test]
+    fn test_segment_id_at_time() {
+        let path = PathBuf::from(temp_db_path());
+        let mut segment = Segment::get_all_for(path).unwrap().pop().unwrap();
+        let epoch = segment.timestamp;
+
+        segment
+            .add_msg(0, &[0x01], &Metadata::default(), epoch + 10)
+            .unwrap();
+        segment
+            .add_msg(1, &[0x02], &Metadata::default(), epoch + 20)
+            .unwrap();
+        segment
+            .add_msg(2, &[0x03], &Metadata::default(), epoch + 20)
+            .unwrap();
+        // The clock stepping backwards shouldn't make the time column decrease.
+        segment
+            .add_msg(3, &[0x04], &Metadata::default(), epoch + 5)
+            .unwrap();
+        segment
+            .add_msg(4, &[0x05], &Metadata::default(), epoch + 30)
+            .unwrap();
+
+        assert_eq!(segment.id_at_time(0).unwrap(), 0);
+        assert_eq!(segment.id_at_time(epoch + 10).unwrap(), 0);
+        assert_eq!(segment.id_at_time(epoch + 11).unwrap(), 1);
+        assert_eq!(segment.id_at_time(epoch + 20).unwrap(), 1);
+        assert_eq!(segment.id_at_time(epoch + 21).unwrap(), 4);
+        assert_eq!(segment.id_at_time(epoch + 31).unwrap(), 5);
+    }
+
+    #[test]
+    fn test_segment_compress() {
+        let path = PathBuf::from(temp_db_path());
+        let mut segment = Segment::get_all_for(path.clone()).unwrap().pop().unwrap();
+
+        // Large enough that the entries need more than one block.
+        let msgs: Vec<Vec<u8>> = (0..10u8).map(|i| vec![i; 20_000]).collect();
+        for (i, msg) in msgs.iter().enumerate() {
+            segment
+                .add_msg(i, msg, &Metadata::default(), now_millis())
+                .unwrap();
+        }
+        segment.seal().unwrap();
+        compress(&mut segment, &path);
+
+        assert_eq!(segment.format_version, FormatVersion::Lz4Sized);
+        assert!(segment.data_file.blocks.as_ref().unwrap().len() > 1);
+        let (dat_path, _) = Segment::paths(path.clone(), segment.timestamp);
+        assert!(std::fs::metadata(dat_path).unwrap().len() < 10_000);
+
+        for (i, msg) in msgs.iter().enumerate() {
+            assert_eq!(segment.read(i).unwrap().as_ref(), Some(msg));
+        }
+
+        let reopened = Segment::get_all_for(path).unwrap().pop().unwrap();
+        assert_eq!(reopened.format_version, FormatVersion::Lz4Sized);
+        assert_eq!(reopened.read(9).unwrap(), Some(vec![9; 20_000]));
+        assert_eq!(reopened.read(3).unwrap(), Some(vec![3; 20_000]));
+    }
+
+    fn append_to(path: &Path, bytes: &[u8]) {
+        let mut file = OpenOptions::new().append(true).open(path).unwrap();
+        file.write_all(bytes).unwrap();
+    }
+
+    #[test]
+    fn test_segment_add_msg_takes_back_short_writes() {
+        let path = PathBuf::from(temp_db_path());
+        let mut segment = Segment::get_all_for(path.clone()).unwrap().pop().unwrap();
+        segment
+            .add_msg(0, b"abc", &Metadata::default(), now_millis())
+            .unwrap();
+        let (dat_path, idx_path) = Segment::paths(path.clone(), segment.timestamp);
+        let file_lens = || {
+            (
+                std::fs::metadata(&dat_path).unwrap().len(),
+                std::fs::metadata(&idx_path).unwrap().len(),
+            )
+        };
+        let lens = file_lens();
+
+        // Part of the DataFile entry is written.
+        SHORT_WRITE.with(|s| s.set(Some((0, 6))));
+        assert!(segment
+            .add_msg(1, b"defg", &Metadata::default(), now_millis())
+            .is_err());
+        assert_eq!(file_lens(), lens);
+
+        // The DataFile entry is written, but only part of the IndexFile entry.
+        SHORT_WRITE.with(|s| s.set(Some((1, 5))));
+        assert!(segment
+            .add_msg(1, b"defg", &Metadata::default(), now_millis())
+            .is_err());
+        assert_eq!(file_lens(), lens);
+        assert_eq!(segment.end_id(), 1);
+
+        segment
+            .add_msg(1, b"hij", &Metadata::default(), now_millis())
+            .unwrap();
+        assert_eq!(segment.read(0).unwrap(), Some(b"abc".to_vec()));
+        assert_eq!(segment.read(1).unwrap(), Some(b"hij".to_vec()));
+        assert_eq!(Segment::repair_active(path).unwrap(), None);
+    }
+
+    #[test]
+    fn test_segment_repair_leaves_consistent_segment_alone() {
+        let path = PathBuf::from(temp_db_path());
+        assert_eq!(Segment::repair_active(path.clone()).unwrap(), None);
+
+        let mut segment = Segment::get_all_for(path.clone()).unwrap().pop().unwrap();
+        assert_eq!(Segment::repair_active(path.clone()).unwrap(), None);
+
+        segment
+            .add_msg(0, b"abc", &Metadata::default(), now_millis())
+            .unwrap();
+        segment
+            .add_msg(1, b"", &Metadata::default(), now_millis())
+            .unwrap();
+        assert_eq!(Segment::repair_active(path).unwrap(), None);
+    }
+
+    #[test]
+    fn test_segment_repair_truncates_torn_tail() {
+        let path = PathBuf::from(temp_db_path());
+        let mut segment = Segment::get_all_for(path.clone()).unwrap().pop().unwrap();
+        segment
+            .add_msg(0, b"abc", &Metadata::default(), now_millis())
+            .unwrap();
+        segment
+            .add_msg(1, b"defg", &Metadata::default(), now_millis())
+            .unwrap();
+        segment
+            .add_msg(2, b"hij", &Metadata::default(), now_millis())
+            .unwrap();
+        let (dat_path, idx_path) = Segment::paths(path.clone(), segment.timestamp);
+        drop(segment);
+
+        // The last payload lost its final byte, then a zeroed page and half an entry made it to
+        // the IndexFile.
+        let dat_len = std::fs::metadata(&dat_path).unwrap().len();
+        OpenOptions::new()
+            .write(true)
+            .open(&dat_path)
+            .unwrap()
+            .set_len(dat_len - 1)
+            .unwrap();
+        append_to(&idx_path, &[0u8; 12]);
+        append_to(&idx_path, &[0x01, 0x02, 0x03]);
+
+        let report = Segment::repair_active(path.clone()).unwrap().unwrap();
+        assert_eq!(
+            report.reasons,
+            vec![
+                RepairReason::PartialIndexEntry,
+                RepairReason::InconsistentIndexEntry,
+                RepairReason::CrcMismatch
+            ]
+        );
+        assert_eq!(report.msgs_kept, 2);
+        assert_eq!(report.msgs_dropped, 2);
+        assert_eq!(report.index_bytes_truncated, 27);
+        assert_eq!(report.data_bytes_truncated, 11);
+
+        let mut segment = Segment::get_all_for(path.clone()).unwrap().pop().unwrap();
+        assert_eq!(segment.end_id(), 2);
+        assert_eq!(segment.read(1).unwrap(), Some(b"defg".to_vec()));
+        segment
+            .add_msg(2, b"klm", &Metadata::default(), now_millis())
+            .unwrap();
+        assert_eq!(segment.read(2).unwrap(), Some(b"klm".to_vec()));
+        assert_eq!(Segment::repair_active(path).unwrap(), None);
+    }
+
+    #[test]
+    fn test_segment_repair_drops_unindexed_data() {
+        let path = PathBuf::from(temp_db_path());
+        let mut segment = Segment::get_all_for(path.clone()).unwrap().pop().unwrap();
+        segment
+            .add_msg(0, b"abc", &Metadata::default(), now_millis())
+            .unwrap();
+        let (dat_path, _) = Segment::paths(path.clone(), segment.timestamp);
+        drop(segment);
+
+        // A second message whose IndexFile entry was never written.
+        append_to(&dat_path, &crc::checksum(b"\0def").to_le_bytes());
+        append_to(&dat_path, &4u32.to_le_bytes());
+        append_to(&dat_path, b"\0de");
+
+        let report = Segment::repair_active(path.clone()).unwrap().unwrap();
+        assert_eq!(report.reasons, vec![RepairReason::UnindexedData]);
+        assert_eq!(report.msgs_kept, 1);
+        assert_eq!(report.data_bytes_truncated, 11);
+
+        let segment = Segment::get_all_for(path).unwrap().pop().unwrap();
+        assert_eq!(segment.read(0).unwrap(), Some(b"abc".to_vec()));
+    }
+
+    #[test]
+    fn test_segment_repair_scans_entries_without_length() {
+        let path = PathBuf::from(temp_db_path());
+        std::fs::create_dir_all(&path).unwrap();
+        let (dat_path, idx_path) = Segment::paths(path.clone(), 1000);
+
+        let mut dat = DataFile::header(FormatVersion::UncompressedMeta).to_vec();
+        let mut idx = IndexFile::header(1000).to_vec();
+        for (id, payload) in [&b"\0abc"[..], &b"\0defg"[..]].iter().enumerate() {
+            let entry = IndexEntry {
+                time: 0,
+                id: id as u32,
+                position: dat.len() as u32,
+            };
+            idx.extend_from_slice(&entry.to_bytes());
+            dat.extend_from_slice(&crc::checksum(payload).to_le_bytes());
+            dat.extend_from_slice(payload);
+        }
+        // The last payload lost its final byte.
+        dat.pop();
+        std::fs::write(&dat_path, dat).unwrap();
+        std::fs::write(&idx_path, idx).unwrap();
+
+        let report = Segment::repair_active(path.clone()).unwrap().unwrap();
+        assert_eq!(report.reasons, vec![RepairReason::CrcMismatch]);
+        assert_eq!(report.msgs_kept, 1);
+        assert_eq!(report.data_bytes_truncated, 8);
+
+        let segment = Segment::get_all_for(path).unwrap().pop().unwrap();
+        assert_eq!(segment.read(0).unwrap(), Some(b"abc".to_vec()));
+        assert_eq!(segment.end_id(), 1);
+    }
+
+    #[test]
+    fn test_segment_repair_restarts_segment_with_torn_header() {
+        let path = PathBuf::from(temp_db_path());
+        std::fs::create_dir_all(&path).unwrap();
+        let (dat_path, idx_path) = Segment::paths(path.clone(), 1000);
+        std::fs::write(&dat_path, &DataFile::MAGIC_NUMBER[0..2]).unwrap();
+
+        let report = Segment::repair_active(path.clone()).unwrap().unwrap();
+        assert_eq!(report.reasons, vec![RepairReason::IncompleteHeader]);
+        assert_eq!(std::fs::read(idx_path).unwrap(), IndexFile::header(1000));
+
+        let mut segment = Segment::get_all_for(path).unwrap().pop().unwrap();
+        assert_eq!(segment.timestamp(), 1000);
+        segment
+            .add_msg(0, b"abc", &Metadata::default(), now_millis())
+            .unwrap();
+        assert_eq!(segment.read(0).unwrap(), Some(b"abc".to_vec()));
+    }
+
+    #[test]
+    fn test_segment_rewrite_keeps_ids() {
+        let path = PathBuf::from(temp_db_path());
+        let mut segment = Segment::get_all_for(path.clone()).unwrap().pop().unwrap();
+        let epoch = segment.timestamp;
+        for i in 0..5u8 {
+            segment
+                .add_msg(i as usize, &[i], &Metadata::default(), epoch + i as u64)
+                .unwrap();
+        }
+        segment.seal().unwrap();
+
+        let remove: HashSet<usize> = vec![0, 2, 4].into_iter().collect();
+        segment.rewrite(path.clone(), &remove).unwrap();
+
+        // The first and last messages leave placeholders so the Segment still covers 0..5.
+        assert_eq!((segment.first_id(), segment.end_id()), (0, 5));
+        assert_eq!(segment.index_file.len, 4);
+        assert_eq!(segment.read(0).unwrap(), None);
+        assert_eq!(segment.read(1).unwrap(), Some(vec![1]));
+        assert_eq!(segment.read(2).unwrap(), None);
+        assert_eq!(segment.time_of(3).unwrap(), Some(epoch + 3));
+        assert_eq!(segment.read_from(2).unwrap().unwrap().offset, 3);
+        assert_eq!(segment.read_from(4).unwrap(), None);
+        assert_eq!(segment.id_at_time(epoch + 2).unwrap(), 3);
+        assert_eq!(segment.msg_count().unwrap(), 2);
+        assert_eq!(segment.last_message().unwrap().unwrap().offset, 3);
+
+        let reopened = Segment::get_all_for(path.clone()).unwrap().pop().unwrap();
+        assert_eq!((reopened.first_id(), reopened.end_id()), (0, 5));
+        assert_eq!(reopened.read(3).unwrap(), Some(vec![3]));
+        assert!(Segment::verify(path, epoch).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_segment_rewrite_recovers_from_crash() {
+        let path = PathBuf::from(temp_db_path());
+        let mut segment = Segment::get_all_for(path.clone()).unwrap().pop().unwrap();
+        for i in 0..3u8 {
+            segment
+                .add_msg(i as usize, &[i], &Metadata::default(), now_millis())
+                .unwrap();
+        }
+        let (dat_path, idx_path) = Segment::paths(path.clone(), segment.timestamp);
+        let dat_compact = dat_path.with_extension("dat.compact");
+        let idx_tmp = idx_path.with_extension("idx.compact.tmp");
+        let remove: HashSet<usize> = vec![1].into_iter().collect();
+
+        // Crashing before the new IndexFile is renamed discards the rewrite.
+        segment
+            .write_rewritten(&dat_compact, &idx_tmp, &remove)
+            .unwrap();
+        let reopened = Segment::get_all_for(path.clone()).unwrap().pop().unwrap();
+        assert_eq!(reopened.read(1).unwrap(), Some(vec![1]));
+        assert!(!dat_compact.exists() && !idx_tmp.exists());
+
+        // Crashing after it finishes the rewrite, even if the DataFile was already moved.
+        segment
+            .write_rewritten(&dat_compact, &idx_tmp, &remove)
+            .unwrap();
+        std::fs::rename(&idx_tmp, idx_path.with_extension("idx.compact")).unwrap();
+        std::fs::rename(&dat_compact, &dat_path).unwrap();
+        let reopened = Segment::get_all_for(path.clone()).unwrap().pop().unwrap();
+        assert_eq!(reopened.read(1).unwrap(), None);
+        assert_eq!(reopened.read(2).unwrap(), Some(vec![2]));
+        assert!(Segment::verify(path, segment.timestamp).unwrap().is_empty());
+    }
 }
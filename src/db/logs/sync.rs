@@ -0,0 +1,244 @@
+use std::fs::File;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::commands::Durability;
+
+/// Tracks which of a Log's writes have been fsynced, so that writers can wait for theirs to reach
+/// the disk without holding the Log's lock. Whichever waiter finds its group ready does the fsync
+/// for everyone in it.
+#[derive(Debug)]
+pub struct Syncer {
+    state: Mutex<SyncState>,
+    synced: Condvar,
+}
+
+#[derive(Debug)]
+struct SyncState {
+    /// Number of writes made to the Log since it was opened.
+    written: u64,
+    /// Number of those writes known to be on disk.
+    synced: u64,
+    /// When the oldest write that isn't being synced yet was made.
+    oldest_unsynced: Option<Instant>,
+    /// Whether a waiter is currently fsyncing.
+    syncing: bool,
+    /// The writes covered by the last fsync that failed, after the first `synced` and through the
+    /// second, and the error it failed with. They may never reach the disk, so their waiters are
+    /// given the error however the next fsync goes.
+    failed: Option<(u64, u64, std::io::ErrorKind, String)>,
+    /// The active Segment's files, which every unsynced write went to.
+    files: Arc<Vec<File>>,
+}
+
+impl Syncer {
+    pub fn new(files: Vec<File>) -> Self {
+        Syncer {
+            state: Mutex::new(SyncState {
+                written: 0,
+                synced: 0,
+                oldest_unsynced: None,
+                syncing: false,
+                failed: None,
+                files: Arc::new(files),
+            }),
+            synced: Condvar::new(),
+        }
+    }
+
+    /// Records a write to the active Segment, returning the ticket to wait on for it.
+    pub fn written(&self) -> u64 {
+        let mut state = self.lock();
+        state.written += 1;
+        state.oldest_unsynced.get_or_insert_with(Instant::now);
+        state.written
+    }
+
+    /// The ticket of the newest write, which is synced once every write made so far is.
+    pub fn last_written(&self) -> u64 {
+        self.lock().written
+    }
+
+    /// Records that the active Segment was sealed, which synced every write made so far, and that
+    /// writes now go to `files`.
+    pub fn rolled(&self, files: Vec<File>) {
+        let mut state = self.lock();
+        state.synced = state.written;
+        state.oldest_unsynced = None;
+        state.files = Arc::new(files);
+        self.synced.notify_all();
+    }
+
+    /// Blocks until the write with `ticket` is as durable as `durability` requires.
+    pub fn wait(&self, ticket: u64, durability: Durability) -> std::io::Result<()> {
+        let (messages, max_wait) = match durability {
+            Durability::Os => return Ok(()),
+            Durability::Always => (1, Duration::from_millis(0)),
+            Durability::Batch { messages, millis } => {
+                (messages.max(1), Duration::from_millis(millis))
+            }
+        };
+
+        let mut state = self.lock();
+        loop {
+            if let Some((after, through, kind, msg)) = &state.failed {
+                if ticket > *after && ticket <= *through {
+                    return Err(std::io::Error::new(*kind, msg.clone()));
+                }
+            }
+            if state.synced >= ticket {
+                return Ok(());
+            }
+
+            let waited = state
+                .oldest_unsynced
+                .map_or(Duration::from_millis(0), |t| t.elapsed());
+            let group_ready = state.written - state.synced >= messages || waited >= max_wait;
+
+            if state.syncing || !group_ready {
+                let timeout = max_wait
+                    .checked_sub(waited)
+                    .unwrap_or_default()
+                    .max(Duration::from_millis(1));
+                state = self
+                    .synced
+                    .wait_timeout(state, timeout)
+                    .expect("unwrapped poisoned sync lock")
+                    .0;
+                continue;
+            }
+
+            // Writes made while we're syncing start a new group.
+            state.syncing = true;
+            state.oldest_unsynced = None;
+            let target = state.written;
+            let files = state.files.clone();
+            drop(state);
+
+            let res = files.iter().try_for_each(|f| f.sync_data());
+
+            state = self.lock();
+            state.syncing = false;
+            match &res {
+                Ok(()) => state.synced = state.synced.max(target),
+                Err(e) => {
+                    // Every write in the group is woken with the error, rather than waiting for
+                    // another write to start a new group.
+                    let after = match &state.failed {
+                        Some((after, _, _, _)) => (*after).min(state.synced),
+                        None => state.synced,
+                    };
+                    state.failed = Some((after, target, e.kind(), e.to_string()));
+                }
+            }
+            self.synced.notify_all();
+            res?;
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, SyncState> {
+        self.state.lock().expect("unwrapped poisoned sync lock")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::temp_db_path;
+    use std::thread;
+
+    fn syncer() -> Syncer {
+        let path = format!("{}/file", temp_db_path());
+        Syncer::new(vec![File::create(path).unwrap()])
+    }
+
+    #[test]
+    fn test_os_and_always_return_once_synced() {
+        let syncer = syncer();
+        let ticket = syncer.written();
+        syncer.wait(ticket, Durability::Os).unwrap();
+        assert_eq!(syncer.lock().synced, 0);
+
+        syncer.wait(ticket, Durability::Always).unwrap();
+        assert_eq!(syncer.lock().synced, 1);
+    }
+
+    #[test]
+    fn test_batch_syncs_full_group() {
+        let syncer = Arc::new(syncer());
+        let durability = Durability::Batch {
+            messages: 3,
+            millis: 60_000,
+        };
+
+        let waiters: Vec<_> = (0..3)
+            .map(|_| {
+                let ticket = syncer.written();
+                let syncer = syncer.clone();
+                thread::spawn(move || syncer.wait(ticket, durability))
+            })
+            .collect();
+        for waiter in waiters {
+            waiter.join().unwrap().unwrap();
+        }
+        assert_eq!(syncer.lock().synced, 3);
+    }
+
+    #[test]
+    fn test_batch_syncs_after_interval() {
+        let syncer = syncer();
+        let ticket = syncer.written();
+
+        let start = Instant::now();
+        let durability = Durability::Batch {
+            messages: 100,
+            millis: 20,
+        };
+        syncer.wait(ticket, durability).unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(20));
+        assert_eq!(syncer.lock().synced, 1);
+    }
+
+    #[test]
+    fn test_failed_sync_wakes_its_group() {
+        // Devices can't be synced.
+        let null = std::fs::OpenOptions::new()
+            .write(true)
+            .open("/dev/null")
+            .unwrap();
+        let syncer = Arc::new(Syncer::new(vec![null]));
+        let durability = Durability::Batch {
+            messages: 2,
+            millis: 60_000,
+        };
+
+        let first = syncer.written();
+        let waiter = {
+            let syncer = syncer.clone();
+            thread::spawn(move || syncer.wait(first, durability))
+        };
+        let second = syncer.written();
+        let start = Instant::now();
+        let res = syncer.wait(second, durability);
+        let other = waiter.join().unwrap();
+
+        // Whichever waiter didn't sync is woken with the error rather than waiting out the batch.
+        assert!(res.is_err());
+        assert!(other.is_err());
+        assert!(start.elapsed() < Duration::from_secs(10));
+        assert!(syncer.wait(first, durability).is_err());
+    }
+
+    #[test]
+    fn test_roll_counts_as_synced() {
+        let syncer = syncer();
+        let ticket = syncer.written();
+        syncer.rolled(vec![]);
+
+        let durability = Durability::Batch {
+            messages: 100,
+            millis: 60_000,
+        };
+        syncer.wait(ticket, durability).unwrap();
+    }
+}
@@ -1,13 +1,14 @@
 use serde::{Deserialize, Serialize};
-use std::collections::hash_map::Entry;
+use serde_cbor::Value as CborValue;
 use std::collections::HashMap;
 use std::fs::{File, OpenOptions};
 use std::io::prelude::*;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::time::SystemTime;
 
 use super::iters::Itr;
-use crate::commands::IteratorKind;
+use super::journal::{self, Journal, JournalEntry, ManifestEdit};
+use crate::commands::{IteratorKind, LogOptions};
 use crate::errors::Error;
 
 /// The Manifest is a file at the root of the database directory that is used
@@ -17,28 +18,46 @@ use crate::errors::Error;
 ///
 /// Right now the Manifest is held in memory, just like the rest of POC database
 /// until we are happy with the interface.
+///
+/// Changes are appended to a Journal next to the Manifest file rather than rewriting it. Once the
+/// Journal has `CHECKPOINT_EVERY` entries, the Manifest file is rewritten to include them and the
+/// Journal is archived.
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Manifest {
+    /// Version of the encoding the Manifest was written with. See `Manifest::migrate`.
+    pub version: u32,
+    /// Seq of the last Journal entry applied to the Manifest.
+    pub seq: u64,
     pub logs: HashMap<String, LogRegistrant>,
     pub itrs: HashMap<String, Itr>,
+    /// Logs that were soft deleted and can still be undeleted, by name.
+    pub deleted_logs: HashMap<String, DeletedLog>,
 
     #[serde(skip)]
-    file_handle: Option<File>,
+    path: PathBuf,
+    #[serde(skip)]
+    journal: Option<Journal>,
+    #[serde(skip)]
+    checkpoint_every: u64,
 }
 
 impl Manifest {
-    pub fn new(path: &Path) -> Self {
-        let file = OpenOptions::new()
-            .write(true)
-            .create(true)
-            .append(false)
-            .open(path)
-            .expect("could not create manifest file");
+    /// The version of the encoding this build writes.
+    pub const VERSION: u32 = 3;
+
+    /// Number of Journal entries after which the Manifest is checkpointed.
+    const CHECKPOINT_EVERY: u64 = 1000;
 
+    pub fn new(path: &Path) -> Self {
         let mut manifest = Manifest {
+            version: Self::VERSION,
+            seq: 0,
             logs: HashMap::new(),
             itrs: HashMap::new(),
-            file_handle: Some(file),
+            deleted_logs: HashMap::new(),
+            path: path.to_path_buf(),
+            journal: None,
+            checkpoint_every: Self::CHECKPOINT_EVERY,
         };
 
         if let Err(e) = manifest.flush_to_file() {
@@ -46,60 +65,304 @@ impl Manifest {
             panic!("shutting down");
         };
 
+        let journal = Journal::create(Self::journal_path(path)).expect("could not create journal");
+        manifest.journal = Some(journal);
         manifest
     }
 
     pub fn load(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
-        let file = OpenOptions::new()
-            .write(true)
-            .read(true)
-            .append(false)
-            .open(path)?;
-        let mut m: Manifest = serde_cbor::from_reader(&file)?;
-        m.file_handle = Some(file);
+        // Left over from being interrupted while flushing. The rename never happened, so the
+        // Manifest itself is still the last complete version.
+        let tmp_path = Self::tmp_path(path);
+        if tmp_path.exists() {
+            warn!("removing incomplete manifest file {:?}", tmp_path);
+            std::fs::remove_file(&tmp_path)?;
+        }
+
+        let value: CborValue = serde_cbor::from_reader(File::open(path)?)?;
+        let (mut m, from_version) = Self::migrate(value)?;
+        m.path = path.to_path_buf();
+        m.checkpoint_every = Self::CHECKPOINT_EVERY;
+
+        if from_version != Self::VERSION {
+            info!(
+                "migrating manifest from version {} to {}",
+                from_version,
+                Self::VERSION
+            );
+            m.flush_to_file()?;
+        }
+
+        // Entries up to the Manifest's seq were already applied when it was checkpointed.
+        let (journal, entries) = Journal::open(Self::journal_path(path))?;
+        let checkpoint_seq = m.seq;
+        for entry in entries.into_iter().filter(|e| e.seq > checkpoint_seq) {
+            m.seq = entry.seq;
+            m.apply(entry.edit);
+        }
+        m.journal = Some(journal);
         Ok(m)
     }
 
+    /// Reads the Manifest at `path` along with its Journal without changing anything on disk, for
+    /// inspecting a DB that isn't running. The result can't be edited.
+    pub fn read(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let value: CborValue = serde_cbor::from_reader(File::open(path)?)?;
+        let (mut m, _) = Self::migrate(value)?;
+        m.path = path.to_path_buf();
+
+        let checkpoint_seq = m.seq;
+        for entry in journal::read(&Self::journal_path(path))? {
+            if entry.seq > checkpoint_seq {
+                m.seq = entry.seq;
+                m.apply(entry.edit);
+            }
+        }
+        Ok(m)
+    }
+
+    /// Every change ever recorded in the Journal of the Manifest at `path`, oldest first.
+    /// Manifests written before the Journal existed have no history from before then.
+    pub fn history(path: &Path) -> std::io::Result<Vec<JournalEntry>> {
+        journal::history(&Self::journal_path(path))
+    }
+
+    fn journal_path(path: &Path) -> PathBuf {
+        let mut journal = path.as_os_str().to_owned();
+        journal.push(".journal");
+        PathBuf::from(journal)
+    }
+
+    /// Decodes a Manifest written by any version up to the current one, returning it along with
+    /// the version it was written with. Each step upgrades the encoding by one version, so
+    /// adding a version only means adding a step.
+    fn migrate(mut value: CborValue) -> Result<(Self, u32), Box<dyn std::error::Error>> {
+        let map = match &mut value {
+            CborValue::Map(map) => map,
+            _ => return Err("manifest is not a map".into()),
+        };
+
+        let version_key = CborValue::Text("version".into());
+        let from_version = match map.get(&version_key) {
+            // Manifests were written without a version before the field was added.
+            None => 0,
+            Some(CborValue::Integer(v)) if *v >= 0 && *v <= Self::VERSION as i128 => *v as u32,
+            Some(v) => return Err(format!("unsupported manifest version {:?}", v).into()),
+        };
+
+        // 0 -> 1: Only the version field was added.
+        if from_version < 1 {
+            map.insert(version_key.clone(), CborValue::Integer(1));
+        }
+
+        // 1 -> 2: The Journal was added. Nothing had been journaled yet.
+        if from_version < 2 {
+            map.insert(version_key.clone(), CborValue::Integer(2));
+            map.insert(CborValue::Text("seq".into()), CborValue::Integer(0));
+        }
+
+        // 2 -> 3: Soft deleted Logs were added.
+        if from_version < 3 {
+            map.insert(version_key, CborValue::Integer(3));
+            map.insert(
+                CborValue::Text("deleted_logs".into()),
+                CborValue::Map(Default::default()),
+            );
+        }
+
+        Ok((serde_cbor::value::from_value(value)?, from_version))
+    }
+
+    fn tmp_path(path: &Path) -> PathBuf {
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        PathBuf::from(tmp)
+    }
+
+    /// Encodes the Manifest as it is now, the way it is stored on disk. Written to a file of its
+    /// own, it loads with no Journal, as if it had just been checkpointed.
+    pub fn snapshot(&self) -> Vec<u8> {
+        serde_cbor::to_vec(&self).expect("could not serialize manifest")
+    }
+
+    /// Atomically replaces the Manifest on disk. The new version is written to a temporary file
+    /// and renamed over the old one, so a crash leaves one version or the other intact.
     fn flush_to_file(&mut self) -> Result<(), std::io::Error> {
         let bytes = serde_cbor::to_vec(&self).expect("could not serialize manifest");
-        let mut handle = self.file_handle.as_ref().unwrap();
+        let tmp_path = Self::tmp_path(&self.path);
+
+        let mut tmp = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&tmp_path)?;
+        tmp.write_all(&bytes)?;
+        tmp.sync_all()?;
 
-        handle.seek(std::io::SeekFrom::Start(0))?;
-        handle.write_all(&*bytes)?;
-        handle.set_len(bytes.len() as u64)?;
-        handle.sync_data()?;
-        Ok(())
+        std::fs::rename(&tmp_path, &self.path)?;
+
+        // The rename is only durable once the directory holding it is synced.
+        let dir = match self.path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        File::open(dir)?.sync_all()
     }
 
-    pub fn add_log(&mut self, name: String) {
-        self.logs
-            .entry(name.clone())
-            .or_insert_with(|| LogRegistrant {
+    /// Records `edit` in the Journal and applies it, checkpointing if the Journal is due.
+    fn commit(&mut self, edit: ManifestEdit) {
+        let entry = JournalEntry {
+            seq: self.seq + 1,
+            at: SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .expect("could not get system time")
+                .as_secs(),
+            edit,
+        };
+
+        let journal = self.journal.as_mut().expect("manifest has no journal");
+        journal
+            .append(&entry)
+            .expect("could not write to manifest journal");
+        let journal_len = journal.entry_count();
+
+        self.seq = entry.seq;
+        self.apply(entry.edit);
+
+        if journal_len >= self.checkpoint_every {
+            self.checkpoint().expect("could not checkpoint manifest");
+        }
+    }
+
+    fn apply(&mut self, edit: ManifestEdit) {
+        match edit {
+            ManifestEdit::AddLog(registrant) => {
+                self.logs.insert(registrant.name.clone(), registrant);
+            }
+            ManifestEdit::DelLog { name } => {
+                self.logs.remove(&name);
+                self.itrs.retain(|_, itr| itr.log != name);
+            }
+            ManifestEdit::AddItr(itr) => {
+                self.itrs.insert(itr.name.clone(), itr);
+            }
+            ManifestEdit::DelItr { name, .. } => {
+                self.itrs.remove(&name);
+            }
+            ManifestEdit::SoftDelLog {
                 name,
-                created_at: SystemTime::now()
-                    .duration_since(SystemTime::UNIX_EPOCH)
-                    .expect("could not get system time")
-                    .as_secs() as usize,
-            });
+                dir,
+                deleted_at,
+                purge_at,
+            } => {
+                if let Some(registrant) = self.logs.remove(&name) {
+                    let itr_names: Vec<String> = self
+                        .itrs
+                        .values()
+                        .filter(|itr| itr.log == name)
+                        .map(|itr| itr.name.clone())
+                        .collect();
+                    let itrs = itr_names
+                        .iter()
+                        .filter_map(|itr| self.itrs.remove(itr))
+                        .collect();
+
+                    let deleted = DeletedLog {
+                        registrant,
+                        itrs,
+                        dir,
+                        deleted_at,
+                        purge_at,
+                    };
+                    self.deleted_logs.insert(name, deleted);
+                }
+            }
+            ManifestEdit::UndelLog { name } => {
+                if let Some(deleted) = self.deleted_logs.remove(&name) {
+                    self.logs.insert(name, deleted.registrant);
+                    for itr in deleted.itrs {
+                        self.itrs.insert(itr.name.clone(), itr);
+                    }
+                }
+            }
+            ManifestEdit::PurgeLog { name } => {
+                self.deleted_logs.remove(&name);
+            }
+            ManifestEdit::AlterLog { name, options } => {
+                if let Some(registrant) = self.logs.get_mut(&name) {
+                    registrant.options = options;
+                }
+            }
+        }
+    }
 
-        self.flush_to_file().expect("could not flush manifest");
+    /// Rewrites the Manifest file to include every Journal entry, then archives the Journal. If
+    /// we crash in between, the entries are skipped on load since the Manifest's seq covers them.
+    fn checkpoint(&mut self) -> Result<(), std::io::Error> {
+        self.flush_to_file()?;
+        let seq = self.seq;
+        self.journal
+            .as_mut()
+            .expect("manifest has no journal")
+            .rotate(seq)
     }
 
-    pub fn del_log(&mut self, name: String) {
-        self.logs.remove(&name.clone());
-        let to_be_deleted: Vec<String> = self
-            .itrs
-            .iter()
-            .filter(|(_, itr)| itr.log == name)
-            .map(|(_, x)| x.name.clone())
-            .collect();
+    pub fn add_log(&mut self, name: String, options: LogOptions) {
+        if self.logs.contains_key(&name) {
+            return;
+        }
+
+        self.commit(ManifestEdit::AddLog(LogRegistrant {
+            name,
+            created_at: SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .expect("could not get system time")
+                .as_secs() as usize,
+            options,
+        }));
+    }
 
-        for itr in to_be_deleted.iter() {
-            self.del_itr(name.clone(), itr.into())
-                .expect("Could not delete itrs associated with log");
+    /// Replaces the options of a Log.
+    pub fn alter_log(&mut self, name: String, options: LogOptions) -> Result<(), Error> {
+        match self.logs.get(&name) {
+            Some(registrant) if registrant.options == options => Ok(()),
+            Some(_) => {
+                self.commit(ManifestEdit::AlterLog { name, options });
+                Ok(())
+            }
+            None => Err(Error::LogDoesNotExist),
         }
+    }
+
+    /// Removes a Log along with every Iterator on it.
+    pub fn del_log(&mut self, name: String) {
+        self.commit(ManifestEdit::DelLog { name });
+    }
+
+    /// Moves a Log and its Iterators to the deleted Logs until `purge_at`, in seconds since the
+    /// Unix Epoch. Its data is expected to be moved to `dir` in the trash directory.
+    pub fn soft_del_log(&mut self, name: String, dir: String, purge_at: u64) {
+        let deleted_at = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .expect("could not get system time")
+            .as_secs();
+
+        self.commit(ManifestEdit::SoftDelLog {
+            name,
+            dir,
+            deleted_at,
+            purge_at,
+        });
+    }
+
+    /// Restores a soft deleted Log and its Iterators.
+    pub fn undel_log(&mut self, name: String) {
+        self.commit(ManifestEdit::UndelLog { name });
+    }
 
-        self.flush_to_file().expect("could not flush manifest");
+    /// Forgets a soft deleted Log, so it can no longer be undeleted.
+    pub fn purge_log(&mut self, name: String) {
+        self.commit(ManifestEdit::PurgeLog { name });
     }
 
     pub fn add_itr(
@@ -111,45 +374,29 @@ impl Manifest {
     ) -> Result<(), Error> {
         let itr = Itr {
             log,
-            name: name.clone(),
+            name,
             kind,
             func,
         };
 
-        let entry = self.itrs.entry(name);
-        match entry {
-            Entry::Occupied(e) => {
-                let stored_itr = e.get();
-                if *stored_itr != itr {
-                    return Err(Error::ItrExistsWithSameName);
-                };
-            }
-            Entry::Vacant(e) => {
-                e.insert(itr);
+        match self.itrs.get(&itr.name) {
+            Some(stored_itr) if *stored_itr != itr => Err(Error::ItrExistsWithSameName),
+            Some(_) => Ok(()),
+            None => {
+                self.commit(ManifestEdit::AddItr(itr));
+                Ok(())
             }
-        };
-
-        self.flush_to_file().expect("could not flush manifest");
-        Ok(())
+        }
     }
 
     pub fn del_itr(&mut self, log: String, name: String) -> Result<(), Error> {
-        let entry = self.itrs.entry(name);
-        match entry {
-            Entry::Occupied(e) => {
-                let itr = e.get();
-                if itr.log != log {
-                    return Err(Error::ItrDoesNotExist);
-                }
-                let _ = e.remove();
-            }
-            Entry::Vacant(_e) => {
-                return Err(Error::ItrDoesNotExist);
+        match self.itrs.get(&name) {
+            Some(itr) if itr.log == log => {
+                self.commit(ManifestEdit::DelItr { log, name });
+                Ok(())
             }
-        };
-
-        self.flush_to_file().expect("could not flush manifest");
-        Ok(())
+            _ => Err(Error::ItrDoesNotExist),
+        }
     }
 }
 
@@ -158,6 +405,24 @@ impl Manifest {
 pub struct LogRegistrant {
     pub name: String,
     pub created_at: usize,
+    /// Options the Log was created with, as changed by LogAlter since. These do not include
+    /// server-wide defaults.
+    #[serde(default)]
+    pub options: LogOptions,
+}
+
+/// The Manifest entry for a soft deleted Log.
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeletedLog {
+    pub registrant: LogRegistrant,
+    /// The Iterators that were on the Log when it was deleted.
+    pub itrs: Vec<Itr>,
+    /// Name of the directory under `trash` that holds the Log's data.
+    pub dir: String,
+    /// When the Log was deleted, in seconds since the Unix Epoch.
+    pub deleted_at: u64,
+    /// When the Log's data will be removed for good, in seconds since the Unix Epoch.
+    pub purge_at: u64,
 }
 
 #[cfg(test)]
@@ -167,29 +432,216 @@ mod tests {
 
     #[test]
     fn test_manifest_add_log() {
-        let mut manifest = Manifest::new(&*temp_manifest_path());
-        manifest.add_log("test".into());
-        manifest.add_log("test2".into());
-        manifest.add_log("test3".into());
+        let mut manifest = Manifest::new(&temp_manifest_path());
+        manifest.add_log("test".into(), LogOptions::default());
+        manifest.add_log("test2".into(), LogOptions::default());
+        manifest.add_log("test3".into(), LogOptions::default());
         assert!(manifest.logs.contains_key("test"));
         assert!(manifest.logs.contains_key("test2"));
         assert!(manifest.logs.contains_key("test3"));
-        assert_eq!(manifest.logs.contains_key("test1"), false);
+        assert!(!manifest.logs.contains_key("test1"));
 
         // This second add_log is here to make sure code does not panic
-        manifest.add_log("test".into());
+        manifest.add_log("test".into(), LogOptions::default());
+    }
+
+    #[test]
+    fn test_manifest_survives_reload() {
+        let path = temp_manifest_path();
+        let mut manifest = Manifest::new(&path);
+        manifest.add_log("test".into(), LogOptions::default());
+        let _ = manifest.add_itr("test".into(), "fun".into(), "map".into(), "func".into());
+
+        let loaded = Manifest::load(&path).unwrap();
+        assert_eq!(loaded.version, Manifest::VERSION);
+        assert_eq!(loaded.logs, manifest.logs);
+        assert_eq!(loaded.itrs, manifest.itrs);
+        assert!(!Manifest::tmp_path(&path).exists());
+    }
+
+    #[test]
+    fn test_manifest_replays_journal() {
+        let path = temp_manifest_path();
+        let mut manifest = Manifest::new(&path);
+        manifest.add_log("test".into(), LogOptions::default());
+        manifest.add_log("test2".into(), LogOptions::default());
+        let _ = manifest.add_itr("test".into(), "fun".into(), "map".into(), "func".into());
+        let _ = manifest.add_itr("test2".into(), "fun2".into(), "map".into(), "func".into());
+        manifest.del_log("test".into());
+        // Edits that change nothing aren't journaled.
+        manifest.add_log("test2".into(), LogOptions::default());
+        assert_eq!(manifest.seq, 5);
+
+        let loaded = Manifest::load(&path).unwrap();
+        assert_eq!(loaded.seq, 5);
+        assert_eq!(loaded.logs, manifest.logs);
+        assert_eq!(loaded.itrs, manifest.itrs);
+        assert!(!loaded.itrs.contains_key("fun"));
+
+        let history = Manifest::history(&path).unwrap();
+        assert_eq!(history.len(), 5);
+        assert_eq!(
+            history[4].edit,
+            ManifestEdit::DelLog {
+                name: "test".into()
+            }
+        );
+    }
+
+    #[test]
+    fn test_manifest_alters_logs() {
+        let path = temp_manifest_path();
+        let mut manifest = Manifest::new(&path);
+        manifest.add_log("test".into(), LogOptions::default());
+        let options = LogOptions {
+            retention_max_messages: Some(10),
+            ..LogOptions::default()
+        };
+        manifest.alter_log("test".into(), options.clone()).unwrap();
+        // Edits that change nothing aren't journaled.
+        manifest.alter_log("test".into(), options.clone()).unwrap();
+        assert_eq!(manifest.seq, 2);
+        assert_eq!(
+            manifest.alter_log("nope".into(), options.clone()),
+            Err(Error::LogDoesNotExist)
+        );
+
+        let loaded = Manifest::load(&path).unwrap();
+        assert_eq!(loaded.logs["test"].options, options);
+    }
+
+    #[test]
+    fn test_manifest_soft_deletes_logs() {
+        let path = temp_manifest_path();
+        let mut manifest = Manifest::new(&path);
+        manifest.add_log("test".into(), LogOptions::default());
+        manifest.add_log("test2".into(), LogOptions::default());
+        let _ = manifest.add_itr("test".into(), "fun".into(), "map".into(), "func".into());
+        let _ = manifest.add_itr("test2".into(), "fun2".into(), "map".into(), "func".into());
+
+        manifest.soft_del_log("test".into(), "test.1".into(), 100);
+        assert!(!manifest.logs.contains_key("test"));
+        assert!(!manifest.itrs.contains_key("fun"));
+        assert!(manifest.itrs.contains_key("fun2"));
+        let deleted = &manifest.deleted_logs["test"];
+        assert_eq!(deleted.dir, "test.1");
+        assert_eq!(deleted.purge_at, 100);
+        assert_eq!(deleted.itrs.len(), 1);
+
+        let mut loaded = Manifest::load(&path).unwrap();
+        assert_eq!(loaded.deleted_logs, manifest.deleted_logs);
+
+        loaded.undel_log("test".into());
+        assert!(loaded.deleted_logs.is_empty());
+        assert!(loaded.logs.contains_key("test"));
+        assert_eq!(loaded.itrs["fun"].log, "test");
+
+        loaded.soft_del_log("test".into(), "test.2".into(), 100);
+        loaded.purge_log("test".into());
+        let loaded = Manifest::load(&path).unwrap();
+        assert!(loaded.deleted_logs.is_empty());
+        assert!(!loaded.logs.contains_key("test"));
+    }
+
+    #[test]
+    fn test_manifest_checkpoints_journal() {
+        let path = temp_manifest_path();
+        let mut manifest = Manifest::new(&path);
+        manifest.checkpoint_every = 2;
+        for i in 0..5 {
+            manifest.add_log(format!("test{}", i), LogOptions::default());
+        }
+        let journal = manifest.journal.as_ref().unwrap();
+        assert_eq!(journal.entry_count(), 1);
+
+        // The file on its own has everything up to the last checkpoint.
+        let value: CborValue = serde_cbor::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+        let (checkpoint, _) = Manifest::migrate(value).unwrap();
+        assert_eq!(checkpoint.seq, 4);
+        assert_eq!(checkpoint.logs.len(), 4);
+
+        let loaded = Manifest::load(&path).unwrap();
+        assert_eq!(loaded.seq, 5);
+        assert_eq!(loaded.logs.len(), 5);
+        let seqs: Vec<u64> = Manifest::history(&path)
+            .unwrap()
+            .iter()
+            .map(|e| e.seq)
+            .collect();
+        assert_eq!(seqs, vec![1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn test_manifest_ignores_incomplete_flush() {
+        let path = temp_manifest_path();
+        let mut manifest = Manifest::new(&path);
+        manifest.add_log("test".into(), LogOptions::default());
+
+        let tmp_path = Manifest::tmp_path(&path);
+        std::fs::write(&tmp_path, [0xA3, 0x67]).unwrap();
+
+        let loaded = Manifest::load(&path).unwrap();
+        assert!(loaded.logs.contains_key("test"));
+        assert!(!tmp_path.exists());
+    }
+
+    #[test]
+    fn test_manifest_migrates_unversioned() {
+        #[derive(Serialize)]
+        struct ManifestV0 {
+            logs: HashMap<String, LogRegistrant>,
+            itrs: HashMap<String, Itr>,
+        }
+
+        let path = temp_manifest_path();
+        let mut logs = HashMap::new();
+        logs.insert(
+            "test".to_string(),
+            LogRegistrant {
+                name: "test".into(),
+                created_at: 1,
+                options: LogOptions::default(),
+            },
+        );
+        let v0 = ManifestV0 {
+            logs,
+            itrs: HashMap::new(),
+        };
+        std::fs::write(&path, serde_cbor::to_vec(&v0).unwrap()).unwrap();
+
+        let loaded = Manifest::load(&path).unwrap();
+        assert_eq!(loaded.version, Manifest::VERSION);
+        assert_eq!(loaded.logs, v0.logs);
+
+        // It was rewritten at the current version.
+        let value: CborValue = serde_cbor::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+        assert_eq!(Manifest::migrate(value).unwrap().1, Manifest::VERSION);
+    }
+
+    #[test]
+    fn test_manifest_rejects_newer_version() {
+        let path = temp_manifest_path();
+        Manifest::new(&path);
+
+        let mut value: CborValue = serde_cbor::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+        if let CborValue::Map(map) = &mut value {
+            map.insert(CborValue::Text("version".into()), CborValue::Integer(99));
+        }
+        std::fs::write(&path, serde_cbor::to_vec(&value).unwrap()).unwrap();
+
+        assert!(Manifest::load(&path).is_err());
     }
 
     #[test]
     fn test_manifest_add_itr() {
-        let mut manifest = Manifest::new(&*temp_manifest_path());
+        let mut manifest = Manifest::new(&temp_manifest_path());
         let _ = manifest.add_itr("test".into(), "fun".into(), "map".into(), "func".into());
         let _ = manifest.add_itr("test".into(), "fun2".into(), "map".into(), "func".into());
         let _ = manifest.add_itr("test".into(), "fun3".into(), "map".into(), "func".into());
         assert!(manifest.itrs.contains_key("fun"));
         assert!(manifest.itrs.contains_key("fun2"));
         assert!(manifest.itrs.contains_key("fun3"));
-        assert_eq!(manifest.logs.contains_key("fun1"), false);
+        assert!(!manifest.logs.contains_key("fun1"));
 
         let duplicate_error =
             manifest.add_itr("test".into(), "fun".into(), "map".into(), "func2".into());
@@ -201,12 +653,12 @@ mod tests {
 
     #[test]
     fn test_manifest_del_itr() {
-        let mut manifest = Manifest::new(&*temp_manifest_path());
+        let mut manifest = Manifest::new(&temp_manifest_path());
         // Normal
         let _ = manifest.add_itr("test".into(), "fun".into(), "map".into(), "func".into());
         assert!(manifest.itrs.contains_key("fun"));
         let _ = manifest.del_itr("test".into(), "fun".into());
-        assert_eq!(manifest.logs.contains_key("fun"), false);
+        assert!(!manifest.logs.contains_key("fun"));
 
         // Function doesnt exist log does
         let does_not_exist_error = manifest.del_itr("test".into(), "fun".into());
@@ -1,27 +1,88 @@
-mod iters;
+mod backup;
+mod engine;
+pub mod inspect;
+pub mod iters;
+mod journal;
 mod logs;
 mod manifest;
 
-use std::collections::hash_map::Entry;
-use std::collections::HashMap;
-use std::path::PathBuf;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
 use std::sync::RwLock;
 
 use crate::commands;
-use crate::commands::{Command, IteratorKind};
+use crate::commands::{Command, IteratorKind, LogOptions};
 use crate::errors::Error;
 use crate::protocol::Response;
-use logs::Log;
-use manifest::Manifest;
+use manifest::{LogRegistrant, Manifest};
+
+pub use backup::BackupInfo;
+pub use engine::SegmentEngine;
+pub use journal::{JournalEntry, ManifestEdit};
+use serde::Serialize;
+use storage::{Metadata, ProducerSequence, StorageEngine};
 
 const OK_RESP: &[u8] = &[0x62, 0x6F, 0x6B];
 
+/// The current time in seconds since the Unix Epoch.
+fn now_secs() -> u64 {
+    logs::now_millis() / 1000
+}
+
+/// The information log_show returns about a Log.
+#[derive(Debug, Serialize)]
+struct LogInfo<'a> {
+    #[serde(flatten)]
+    registrant: &'a LogRegistrant,
+    /// The options the Log runs with: its own, then the server-wide defaults, then the built-in
+    /// ones.
+    effective_options: LogOptions,
+    /// Offset of the oldest message that hasn't been deleted by retention.
+    earliest_offset: u64,
+    #[serde(flatten)]
+    summary: logs::LogSummary,
+    /// Names of the Iterators attached to the Log, in order.
+    iterators: Vec<String>,
+}
+
+/// What msg_add returns about a message from an idempotent producer.
+#[derive(Debug, Serialize)]
+struct AddReport {
+    offset: u64,
+    /// Whether the message had already been added, so wasn't added again.
+    duplicate: bool,
+}
+
+/// What msg_import returns about a batch of messages.
+#[derive(Debug, Serialize)]
+struct ImportReport {
+    added: u64,
+    rejected: Vec<RejectedMessage>,
+}
+
+#[derive(Debug, Serialize)]
+struct RejectedMessage {
+    /// Position of the message in the batch.
+    index: u64,
+    error: Error,
+}
+
 #[derive(Debug)]
 pub struct DB {
     path: PathBuf,
 
+    /// Server-wide defaults for options a Log doesn't set itself.
+    defaults: LogOptions,
+
+    /// When both locks are needed, the manifest is always locked before the logs, so that no two
+    /// requests can each hold one while waiting for the other.
     manifest: RwLock<Manifest>,
-    logs: RwLock<HashMap<String, Log>>,
+    logs: RwLock<SegmentEngine>,
+
+    /// Lua states with each Iterator's function compiled, reused across itr_next calls.
+    lua: iters::LuaPool,
 }
 
 unsafe impl Send for DB {}
@@ -29,21 +90,129 @@ unsafe impl Sync for DB {}
 
 impl DB {
     pub fn new(path: String) -> Self {
+        Self::open(path, LogOptions::default())
+    }
+
+    /// Opens the DB at `path`, using `defaults` for any option a Log doesn't set itself.
+    pub fn open(path: String, defaults: LogOptions) -> Self {
         let path = PathBuf::from(&*path);
         let mut manifest_path = path.clone();
         manifest_path.push("manifest");
 
         let manifest = if manifest_path.exists() {
-            Manifest::load(&*manifest_path).expect("could not load manifest file")
+            Manifest::load(&manifest_path).expect("could not load manifest file")
         } else {
-            Manifest::new(&*manifest_path)
+            Manifest::new(&manifest_path)
         };
 
+        if let Err(e) = Self::clean_up_log_dirs(&path, &manifest) {
+            error!("could not clean up directories of deleted logs: {}", e);
+        }
+        let logs = Self::recover_logs(&path, &manifest, &defaults);
+
         DB {
             path,
+            defaults,
             manifest: RwLock::new(manifest),
-            logs: RwLock::new(HashMap::new()),
+            logs: RwLock::new(logs),
+            lua: iters::LuaPool::default(),
+        }
+    }
+
+    /// Finishes any Log deletions that were interrupted by a crash, so that a Log's directory
+    /// exists exactly when the Manifest says it should.
+    fn clean_up_log_dirs(path: &Path, manifest: &Manifest) -> std::io::Result<()> {
+        let (logs_dir, trash_dir) = (path.join("logs"), path.join("trash"));
+
+        // Soft deleted in the Manifest, but the data wasn't moved to the trash yet.
+        for (name, deleted) in manifest.deleted_logs.iter() {
+            let (dir, trashed) = (logs_dir.join(name), trash_dir.join(&deleted.dir));
+            if dir.exists() && !trashed.exists() && !manifest.logs.contains_key(name) {
+                info!("moving data of deleted log {:?} to the trash", name);
+                std::fs::create_dir_all(&trash_dir)?;
+                std::fs::rename(dir, trashed)?;
+            }
+        }
+
+        // Usually hard deleted in the Manifest without the data being removed yet, but it could
+        // also be a Log the Manifest lost track of. Nothing is removed on open, so the data is
+        // moved to the trash, where it can be recovered by hand if it is still needed.
+        if logs_dir.exists() {
+            for entry in std::fs::read_dir(&logs_dir)? {
+                let entry = entry?;
+                let name = entry.file_name().to_string_lossy().into_owned();
+                if !manifest.logs.contains_key(&name) {
+                    let dir = SegmentEngine::trash_dir_name(path, &name);
+                    warn!(
+                        "moving data of unknown log {:?} to {:?} in the trash",
+                        name, dir
+                    );
+                    std::fs::create_dir_all(&trash_dir)?;
+                    std::fs::rename(entry.path(), trash_dir.join(dir))?;
+                }
+            }
+        }
+
+        // Moved to the trash, but not removed after being purged or hard deleted. It is left for
+        // an operator to remove for the same reason.
+        if trash_dir.exists() {
+            for entry in std::fs::read_dir(&trash_dir)? {
+                let entry = entry?;
+                let referenced = matches!(entry.file_name().to_str(),
+                    Some(dir) if manifest.deleted_logs.values().any(|d| d.dir == dir));
+                if !referenced {
+                    warn!(
+                        "{:?} in the trash is not a deleted log, it can be removed once it is no \
+                         longer needed",
+                        entry.file_name()
+                    );
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Reopens every Log registered in the Manifest from its Segments on disk.
+    fn recover_logs(path: &Path, manifest: &Manifest, defaults: &LogOptions) -> SegmentEngine {
+        let mut logs = SegmentEngine::new(path.to_path_buf(), defaults.clone());
+        let (mut recovered, mut total_msgs, mut total_segments, mut repaired) = (0, 0, 0, 0);
+
+        for (name, registrant) in manifest.logs.iter() {
+            let log = match logs.open_log(name, registrant.options.or(defaults)) {
+                Ok(log) => log,
+                Err(e) => {
+                    // Leave the Log registered so it is tried again on the next start, but don't
+                    // take the rest of the DB down with it.
+                    error!("could not recover log {:?}: {}", name, e);
+                    continue;
+                }
+            };
+            info!(
+                "recovered log {:?}: {} messages in {} segments",
+                name,
+                log.len(),
+                log.segment_count()
+            );
+
+            if log.repair_report().is_some() {
+                repaired += 1;
+            }
+            recovered += 1;
+            total_msgs += log.len();
+            total_segments += log.segment_count();
         }
+
+        info!(
+            "recovered {} of {} logs and {} iterators ({} messages in {} segments, {} repaired)",
+            recovered,
+            manifest.logs.len(),
+            manifest.itrs.len(),
+            total_msgs,
+            total_segments,
+            repaired
+        );
+        logs
     }
 
     pub fn exec(&self, cmd: Command) -> Response {
@@ -51,13 +220,57 @@ impl DB {
 
         match cmd {
             LogShow(commands::LogShow { log_name }) => self.log_show(log_name),
-            LogAdd(commands::LogAdd { log_name }) => self.log_add(log_name),
-            LogDelete(commands::LogDelete { log_name }) => self.log_delete(log_name),
+            LogAdd(commands::LogAdd { log_name, options }) => self.log_add(log_name, options),
+            LogDelete(commands::LogDelete {
+                log_name,
+                grace_period,
+            }) => self.log_delete(log_name, grace_period),
+            LogUndelete(commands::LogUndelete { log_name }) => self.log_undelete(log_name),
+            LogAlter(commands::LogAlter {
+                log_name,
+                options,
+                reset,
+            }) => self.log_alter(log_name, options, reset),
             LogList => self.log_list(),
             IteratorList(commands::IteratorList { log_name }) => self.itr_list(log_name),
-            MessageAdd(commands::MessageAdd { log_name, message }) => match message {
-                serde_cbor::Value::Bytes(m) => self.msg_add(log_name, m),
-                _ => Error::MsgFieldNotOfTypeBinary.into(),
+            MessageAdd(commands::MessageAdd {
+                log_name,
+                message,
+                key,
+                headers,
+                producer_id,
+                sequence,
+            }) => {
+                let producer = match (producer_id, sequence) {
+                    (Some(id), Some(sequence)) => Some(ProducerSequence { id, sequence }),
+                    (None, None) => None,
+                    _ => return Error::MsgSequenceMissing.into(),
+                };
+                let meta = Metadata {
+                    key,
+                    headers,
+                    producer,
+                };
+                match message {
+                    serde_cbor::Value::Bytes(m) => self.msg_add(log_name, m, meta),
+                    _ => Error::MsgFieldNotOfTypeBinary.into(),
+                }
+            }
+            MessageImport(commands::MessageImport { log_name, messages }) => {
+                self.msg_import(log_name, messages)
+            }
+            MessageRead(commands::MessageRead {
+                log_name,
+                message_id,
+                count,
+                start_time,
+                end_time,
+            }) => match self.msg_read(log_name, message_id, count, start_time, end_time) {
+                Ok((mut msgs, next_offset)) => {
+                    msgs.push(serde_cbor::to_vec(&next_offset).unwrap());
+                    Response::Data(msgs)
+                }
+                Err(e) => e.into(),
             },
             IteratorAdd(commands::IteratorAdd {
                 log_name,
@@ -69,11 +282,29 @@ impl DB {
                 iterator_name,
                 message_id,
                 count,
-            }) => self.itr_next(iterator_name, message_id, count),
+                start_time,
+                end_time,
+                with_next_offset: false,
+            }) => self.itr_next(iterator_name, message_id, count, start_time, end_time),
+            IteratorNext(commands::IteratorNext {
+                iterator_name,
+                message_id,
+                count,
+                start_time,
+                end_time,
+                with_next_offset: true,
+            }) => match self.itr_page(iterator_name, message_id, count, start_time, end_time) {
+                Ok((mut msgs, next_offset)) => {
+                    msgs.push(serde_cbor::to_vec(&next_offset).unwrap());
+                    Response::Data(msgs)
+                }
+                Err(e) => e.into(),
+            },
             IteratorDelete(commands::IteratorDelete {
                 log_name,
                 iterator_name,
             }) => self.itr_del(log_name, iterator_name),
+            Backup(commands::Backup { path }) => self.db_backup(path),
         }
     }
 
@@ -97,55 +328,499 @@ impl DB {
             .manifest
             .read()
             .expect("unwrapped poisoned manifest lock");
-        let info = serde_cbor::to_vec(&m.logs[&name]).expect("could not serialize log registrant");
+        let logs = self.logs.read().expect("unwrapped poisoned logs lock");
+
+        let registrant = match m.logs.get(&name) {
+            Some(registrant) => registrant,
+            None => return Error::LogDoesNotExist.into(),
+        };
+        let log = match logs.get(&name) {
+            Some(log) => log,
+            None => return Error::ErrReadingSegment.into(),
+        };
+        let summary = match log.summary() {
+            Ok(summary) => summary,
+            Err(e) => return e.into(),
+        };
+        let mut iterators: Vec<String> = m
+            .itrs
+            .values()
+            .filter(|itr| itr.log == name)
+            .map(|itr| itr.name.clone())
+            .collect();
+        iterators.sort();
+
+        let info = LogInfo {
+            registrant,
+            effective_options: registrant
+                .options
+                .or(&self.defaults)
+                .or(&logs::builtin_options()),
+            earliest_offset: log.earliest_offset() as u64,
+            summary,
+            iterators,
+        };
+        let info = serde_cbor::to_vec(&info).expect("could not serialize log info");
         Response::Data(vec![info])
     }
 
     /// Adds a new log to the DB
-    fn log_add(&self, name: String) -> Response {
+    fn log_add(&self, name: String, options: LogOptions) -> Response {
         let mut m = self
             .manifest
             .write()
             .expect("unwrapped poisoned manifest lock");
 
-        m.add_log(name.clone());
+        m.add_log(name.clone(), options);
 
-        self.logs
-            .write()
-            .expect("unwrapped poisoned logs lock")
-            .entry(name.clone())
-            .or_insert_with(|| Log::new(self.path.clone(), &*name));
+        // If the Log already existed, it keeps the options it was created with.
+        let options = m.logs[&name].options.or(&self.defaults);
+        let mut logs = self.logs.write().expect("unwrapped poisoned logs lock");
+        if let Err(e) = logs.open_log(&name, options) {
+            error!("could not open log {:?}: {}", name, e);
+            return Error::ErrReadingSegment.into();
+        }
 
         Response::Info(OK_RESP.into())
     }
 
-    /// Deletes a log from the DB
-    fn log_delete(&self, name: String) -> Response {
+    /// Changes the options of a log. Options that aren't given keep their value, and those named in
+    /// `reset` fall back to the server-wide defaults again. A log's ingestion mode can't be
+    /// changed, since its messages would no longer be read the way they were written.
+    fn log_alter(&self, name: String, options: LogOptions, reset: Vec<String>) -> Response {
+        let mut m = self
+            .manifest
+            .write()
+            .expect("unwrapped poisoned manifest lock");
         let mut logs = self.logs.write().expect("unwrapped poisoned logs lock");
 
-        if let Entry::Occupied(l) = logs.entry(name.clone()) {
-            l.remove_entry();
-            self.manifest
-                .write()
-                .expect("unwrapped poisoned manifest lock")
-                .del_log(name);
+        let current = match m.logs.get(&name) {
+            Some(registrant) => &registrant.options,
+            None => return Error::LogDoesNotExist.into(),
         };
+        let altered = match options.or(current).reset(&reset) {
+            Ok(altered) => altered,
+            Err(e) => return e.into(),
+        };
+        let ingestion = |o: &LogOptions| o.or(&self.defaults).ingestion.unwrap_or_default();
+        if ingestion(&altered) != ingestion(current) {
+            return Error::LogOptionNotAlterable.into();
+        }
+
+        if let Err(e) = m.alter_log(name.clone(), altered.clone()) {
+            return e.into();
+        }
+        if let Some(log) = logs.get_mut(&name) {
+            log.set_options(altered.or(&self.defaults));
+        }
         Response::Info(OK_RESP.into())
     }
 
-    /// Adds a new message to a log
-    fn msg_add(&self, log: String, msg: Vec<u8>) -> Response {
+    /// Deletes a log from the DB. If it has a grace period, its data is moved to the trash and it
+    /// can be undeleted until the grace period is over. Otherwise its data is removed right away.
+    fn log_delete(&self, name: String, grace_period: Option<u64>) -> Response {
+        let mut m = self
+            .manifest
+            .write()
+            .expect("unwrapped poisoned manifest lock");
         let mut logs = self.logs.write().expect("unwrapped poisoned logs lock");
-        let l = logs.get_mut(&log);
 
-        if l.is_none() {
-            return Error::LogDoesNotExist.into();
+        // Reads hold the logs lock, so none are in flight once the Log is out of the map.
+        let grace_period = match (logs.get(&name), m.logs.get(&name)) {
+            (Some(log), _) => grace_period.or(log.options().delete_grace_period),
+            // A Log that couldn't be opened is deleted all the same, or it would come back on
+            // every start with no way to get rid of it.
+            (None, Some(registrant)) => {
+                grace_period.or(registrant.options.or(&self.defaults).delete_grace_period)
+            }
+            (None, None) => return Error::LogDoesNotExist.into(),
+        };
+
+        for itr in m.itrs.values().filter(|itr| itr.log == name) {
+            self.lua.invalidate(&itr.name);
+        }
+
+        // The Manifest is updated before the directory is touched, so if we crash in between the
+        // move is finished on the next start rather than the Log coming back empty.
+        let res = match grace_period {
+            Some(secs) if secs > 0 => {
+                // Only the most recently deleted version of a Log is kept.
+                if let Some(previous) = m.deleted_logs.get(&name) {
+                    let trashed = previous.dir.clone();
+                    m.purge_log(name.clone());
+                    if let Err(e) = logs.purge_trashed(&trashed) {
+                        error!("could not remove data of deleted log {:?}: {}", name, e);
+                    }
+                }
+
+                let dir = SegmentEngine::trash_dir_name(&self.path, &name);
+                m.soft_del_log(name.clone(), dir.clone(), now_secs() + secs);
+                logs.trash_log(&name, &dir)
+            }
+            _ => {
+                m.del_log(name.clone());
+                let dir = SegmentEngine::trash_dir_name(&self.path, &name);
+                logs.trash_log(&name, &dir)
+                    .and_then(|_| logs.purge_trashed(&dir))
+            }
+        };
+
+        match res {
+            Ok(()) => Response::Info(OK_RESP.into()),
+            Err(e) => {
+                error!("could not remove data of deleted log {:?}: {}", name, e);
+                Error::ErrWritingSegment.into()
+            }
+        }
+    }
+
+    /// Restores a soft deleted log along with its iterators.
+    fn log_undelete(&self, name: String) -> Response {
+        let mut m = self
+            .manifest
+            .write()
+            .expect("unwrapped poisoned manifest lock");
+        let mut logs = self.logs.write().expect("unwrapped poisoned logs lock");
+
+        if logs.contains(&name) || m.logs.contains_key(&name) {
+            return Error::LogExistsWithSameName.into();
+        }
+        let trashed = match m.deleted_logs.get(&name) {
+            Some(deleted) => self.path.join("trash").join(&deleted.dir),
+            None => return Error::LogDoesNotExist.into(),
+        };
+
+        // If we crash after the move, the Log is moved back to the trash on the next start.
+        let logs_dir = self.path.join("logs");
+        let res = std::fs::create_dir_all(&logs_dir)
+            .and_then(|_| std::fs::rename(&trashed, logs_dir.join(&name)));
+        if let Err(e) = res {
+            error!("could not restore data of deleted log {:?}: {}", name, e);
+            return Error::ErrWritingSegment.into();
         }
+        m.undel_log(name.clone());
 
-        match l.unwrap().add_msg(msg) {
+        let options = m.logs[&name].options.or(&self.defaults);
+        match logs.open_log(&name, options) {
             Ok(_) => Response::Info(OK_RESP.into()),
-            Err(e) => e.into(),
+            Err(e) => {
+                error!("could not open undeleted log {:?}: {}", name, e);
+                Error::ErrReadingSegment.into()
+            }
+        }
+    }
+
+    /// Removes the data of soft deleted Logs whose grace period is over.
+    pub fn purge_deleted_logs(&self) {
+        let mut m = self
+            .manifest
+            .write()
+            .expect("unwrapped poisoned manifest lock");
+
+        let now = now_secs();
+        let expired: Vec<(String, String)> = m
+            .deleted_logs
+            .iter()
+            .filter(|(_, deleted)| deleted.purge_at <= now)
+            .map(|(name, deleted)| (name.clone(), deleted.dir.clone()))
+            .collect();
+
+        for (name, dir) in expired {
+            m.purge_log(name.clone());
+            match std::fs::remove_dir_all(self.path.join("trash").join(dir)) {
+                Ok(()) => info!("purged deleted log {:?}", name),
+                Err(e) => error!("could not remove data of deleted log {:?}: {}", name, e),
+            }
+        }
+    }
+
+    /// Every change ever made to the Logs and Iterators registered in the DB, oldest first.
+    pub fn manifest_history(&self) -> std::io::Result<Vec<JournalEntry>> {
+        Manifest::history(&self.path.join("manifest"))
+    }
+
+    /// Backs up the DB to `path` on the server, responding with each Log's high-water mark.
+    fn db_backup(&self, path: String) -> Response {
+        match self.backup(PathBuf::from(&path)) {
+            Ok(info) => {
+                let marks =
+                    serde_cbor::to_vec(&info.logs).expect("could not serialize backup info");
+                Response::Data(vec![marks])
+            }
+            Err(e) => {
+                error!("could not back up db to {:?}: {}", path, e);
+                Error::ErrWritingBackup.into()
+            }
+        }
+    }
+
+    /// Takes a consistent backup of the DB in `dir`, which must be empty or not exist yet. Every
+    /// Log is backed up to the same point in time, its high-water mark, while messages can keep
+    /// being added once that point has been captured.
+    pub fn backup(&self, dir: PathBuf) -> std::io::Result<BackupInfo> {
+        if dir.exists() && std::fs::read_dir(&dir)?.next().is_some() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                format!("backup directory {:?} is not empty", dir),
+            ));
+        }
+        std::fs::create_dir_all(&dir)?;
+
+        // Only what the backup is made of is captured while the DB is locked. The files are opened
+        // so that retention or compaction can't remove them before they are copied, and copying
+        // them is left until writes can carry on.
+        let m = self
+            .manifest
+            .read()
+            .expect("unwrapped poisoned manifest lock");
+        let logs = self.logs.read().expect("unwrapped poisoned logs lock");
+
+        let manifest = m.snapshot();
+        let mut marks = BTreeMap::new();
+        let mut files = vec![];
+        let mut active_segments = vec![];
+        for name in m.logs.keys() {
+            let log = match logs.get(name) {
+                Some(log) => log,
+                None => {
+                    warn!(
+                        "log {:?} could not be opened, leaving it out of backup",
+                        name
+                    );
+                    continue;
+                }
+            };
+
+            let log_dir = Path::new("logs").join(name);
+            let snapshot = log.snapshot()?;
+            for path in snapshot.sealed {
+                let file_name = path.file_name().expect("segment file has no name");
+                files.push((log_dir.join(file_name), backup::PinnedFile::open(path)?));
+            }
+
+            marks.insert(name.clone(), snapshot.next_offset);
+            active_segments.push((log_dir, snapshot.active));
+        }
+
+        for deleted in m.deleted_logs.values() {
+            let trashed = Path::new("trash").join(&deleted.dir);
+            for (path, file) in backup::PinnedFile::open_tree(&self.path.join(&trashed))? {
+                files.push((trashed.join(path), file));
+            }
+        }
+
+        drop(logs);
+        drop(m);
+
+        let mut manifest_file = File::create(dir.join("manifest"))?;
+        manifest_file.write_all(&manifest)?;
+        manifest_file.sync_all()?;
+        for (path, file) in files {
+            let to = dir.join(path);
+            if let Some(parent) = to.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            file.link_or_copy(&to)?;
+        }
+        for (log_dir, active) in active_segments {
+            let log_dir = dir.join(log_dir);
+            std::fs::create_dir_all(&log_dir)?;
+            active.copy_into(log_dir)?;
+        }
+        BackupInfo::write(&dir, now_secs(), marks)
+    }
+
+    /// Restores the backup in `backup` to `db_path`, checking it for corruption first. Nothing
+    /// can be open at `db_path` while this runs.
+    pub fn restore(backup: &Path, db_path: &Path) -> std::io::Result<BackupInfo> {
+        backup::restore(backup, db_path)
+    }
+
+    /// Compresses the sealed Segments of every Log that is set to compress them. The logs are only
+    /// locked to find the Segments and to swap each one in once it is compressed, so writes carry
+    /// on in the meantime.
+    pub fn compress_segments(&self) {
+        let mut pending = vec![];
+        {
+            let logs = self.logs.read().expect("unwrapped poisoned logs lock");
+            for (name, log) in logs.iter() {
+                match log.start_compression() {
+                    Ok(p) => pending.extend(p.into_iter().map(|p| (name.clone(), p))),
+                    Err(e) => error!("could not compress segments of log {:?}: {}", name, e),
+                }
+            }
+        }
+
+        for (name, p) in pending {
+            if let Err(e) = p.write() {
+                // The Segment is still readable uncompressed, so it is tried again next time.
+                warn!("could not compress segment of log {:?}: {}", name, e);
+                continue;
+            }
+            let mut logs = self.logs.write().expect("unwrapped poisoned logs lock");
+            let res = match logs.get_mut(&name) {
+                Some(log) => log.finish_compression(p),
+                None => p.discard(),
+            };
+            if let Err(e) = res {
+                warn!("could not compress segment of log {:?}: {}", name, e);
+            }
+        }
+    }
+
+    /// Deletes the oldest Segments of every Log that are past the Log's retention limits, and
+    /// compacts the Logs whose cleanup policy is to compact. Compaction reads each Log without
+    /// holding the logs lock, which is only taken again to swap in the compacted Segments.
+    pub fn enforce_retention(&self) {
+        let mut pending = vec![];
+        {
+            let mut logs = self.logs.write().expect("unwrapped poisoned logs lock");
+            for (name, log) in logs.iter_mut() {
+                match log.apply_retention() {
+                    Ok(0) => (),
+                    Ok(n) => info!(
+                        "retention deleted {} segments from log {:?}, earliest offset is now {}",
+                        n,
+                        name,
+                        log.earliest_offset()
+                    ),
+                    Err(e) => error!("could not apply retention to log {:?}: {}", name, e),
+                }
+                match log.start_compaction() {
+                    Ok(Some(p)) => pending.push((name.clone(), p)),
+                    Ok(None) => (),
+                    Err(e) => error!("could not compact log {:?}: {}", name, e),
+                }
+            }
+        }
+
+        for (name, mut p) in pending {
+            if let Err(e) = p.scan() {
+                error!("could not compact log {:?}: {}", name, e);
+                continue;
+            }
+            let mut logs = self.logs.write().expect("unwrapped poisoned logs lock");
+            let res = match logs.get_mut(&name) {
+                Some(log) => log.finish_compaction(p),
+                None => continue,
+            };
+            match res {
+                Ok(0) => (),
+                Ok(n) => info!("compaction removed {} messages from log {:?}", n, name),
+                Err(e) => error!("could not compact log {:?}: {}", name, e),
+            }
+        }
+    }
+
+    /// Adds a new message to a log. A message from an idempotent producer is acknowledged with
+    /// its offset, and isn't added again if it already was.
+    fn msg_add(&self, log: String, msg: Vec<u8>, meta: Metadata) -> Response {
+        let idempotent = meta.producer.is_some();
+        let mut logs = self.logs.write().expect("unwrapped poisoned logs lock");
+        let pending = match logs.append_pending(&log, msg, meta) {
+            Ok(pending) => pending,
+            Err(e) => return e.into(),
+        };
+
+        // Other messages can be added to the Log while this one waits to be synced.
+        drop(logs);
+        let report = AddReport {
+            offset: pending.offset() as u64,
+            duplicate: pending.is_duplicate(),
+        };
+        match pending.wait() {
+            Ok(()) if idempotent => {
+                let bytes = serde_cbor::to_vec(&report).expect("could not serialize add report");
+                Response::Data(vec![bytes])
+            }
+            Ok(()) => Response::Info(OK_RESP.into()),
+            Err(e) => {
+                error!("could not sync msg to log {:?}: {}", log, e);
+                Error::ErrWritingSegment.into()
+            }
+        }
+    }
+
+    /// Adds a batch of messages to a log, keeping the ingest times they were given. Messages that
+    /// can't be added are reported back rather than failing the rest of the batch.
+    fn msg_import(&self, log: String, messages: Vec<commands::ImportedMessage>) -> Response {
+        let mut logs = self.logs.write().expect("unwrapped poisoned logs lock");
+        if !logs.contains(&log) {
+            return Error::LogDoesNotExist.into();
+        }
+
+        let mut report = ImportReport {
+            added: 0,
+            rejected: vec![],
+        };
+        let mut last = None;
+        for (i, msg) in messages.into_iter().enumerate() {
+            let time = msg.time.unwrap_or_else(logs::now_millis);
+            let res = match msg.message {
+                serde_cbor::Value::Bytes(m) => {
+                    logs.append_pending_at(&log, m, Metadata::default(), time)
+                }
+                _ => Err(Error::MsgFieldNotOfTypeBinary),
+            };
+            match res {
+                Ok(pending) => {
+                    report.added += 1;
+                    last = Some(pending);
+                }
+                Err(error) => report.rejected.push(RejectedMessage {
+                    index: i as u64,
+                    error,
+                }),
+            }
+        }
+
+        // Messages are synced in the order they were written, so once the last one is synced the
+        // whole batch is.
+        drop(logs);
+        if let Some(Err(e)) = last.map(|pending| pending.wait()) {
+            error!("could not sync imported msgs to log {:?}: {}", log, e);
+            return Error::ErrWritingSegment.into();
         }
+
+        let bytes = serde_cbor::to_vec(&report).expect("could not serialize import report");
+        Response::Data(vec![bytes])
+    }
+
+    /// Reads up to `count` messages of a log as they were added, starting at `msg_id`. Returns
+    /// them along with the offset to resume from, as itr_page does.
+    fn msg_read(
+        &self,
+        log: String,
+        msg_id: usize,
+        count: usize,
+        start_time: Option<u64>,
+        end_time: Option<u64>,
+    ) -> Result<(Vec<Vec<u8>>, u64), Error> {
+        let logs = self.logs.read().expect("unwrapped poisoned logs lock");
+        let ingestion = logs
+            .get(&log)
+            .ok_or(Error::LogDoesNotExist)?
+            .options()
+            .ingestion
+            .unwrap_or_default();
+
+        let msg_id = msg_id as u64;
+        let start = match start_time {
+            Some(t) => logs.offset_at_time(&log, t)?.max(msg_id),
+            None => msg_id,
+        };
+        let mut msgs = logs.read(&log, start, count)?;
+        if let Some(t) = end_time {
+            let end = logs.offset_at_time(&log, t)?;
+            msgs.retain(|m| m.offset < end);
+        }
+
+        let next_offset = msgs.last().map_or(start, |m| m.offset + 1);
+        let msgs = msgs
+            .into_iter()
+            .map(|m| iters::encode_body(ingestion, m.body))
+            .collect();
+        Ok((msgs, next_offset))
     }
 
     /// List all itrs attached to a log
@@ -177,8 +852,11 @@ impl DB {
             .write()
             .expect("unwrapped poisoned manifest lock");
 
-        match m.add_itr(log, name, kind, func) {
-            Ok(_) => Response::Info(OK_RESP.into()),
+        match m.add_itr(log, name.clone(), kind, func) {
+            Ok(_) => {
+                self.lua.invalidate(&name);
+                Response::Info(OK_RESP.into())
+            }
             Err(e) => e.into(),
         }
     }
@@ -189,32 +867,79 @@ impl DB {
             .write()
             .expect("unwrapped poisoned manifest lock");
 
-        match m.del_itr(log, name) {
-            Ok(_) => Response::Info(OK_RESP.into()),
+        match m.del_itr(log, name.clone()) {
+            Ok(_) => {
+                self.lua.invalidate(&name);
+                Response::Info(OK_RESP.into())
+            }
             Err(e) => e.into(),
         }
     }
 
-    fn itr_next(&self, name: String, msg_id: usize, count: usize) -> Response {
-        let manifest = self
+    /// Runs an iterator over up to `count` messages, starting at `msg_id` or the first message
+    /// ingested at `start_time`, whichever is later, and stopping before `end_time`.
+    fn itr_next(
+        &self,
+        name: String,
+        msg_id: usize,
+        count: usize,
+        start_time: Option<u64>,
+        end_time: Option<u64>,
+    ) -> Response {
+        match self.itr_page(name, msg_id, count, start_time, end_time) {
+            Ok((d, _)) => Response::Data(d),
+            Err(e) => e.into(),
+        }
+    }
+
+    /// Like itr_next, but also returns the offset to resume from to get the messages that follow.
+    fn itr_page(
+        &self,
+        name: String,
+        msg_id: usize,
+        count: usize,
+        start_time: Option<u64>,
+        end_time: Option<u64>,
+    ) -> Result<(Vec<Vec<u8>>, u64), Error> {
+        // A Filter Iterator can read many messages for each one it returns, so no lock is held
+        // while its function runs, only while each batch of messages is read.
+        let itr = self
             .manifest
             .read()
-            .expect("unwrapped poisoned manifest lock");
-        let itr = match manifest.itrs.get(&name) {
-            Some(itr) => itr,
-            None => return Error::ItrDoesNotExist.into(),
-        };
+            .expect("unwrapped poisoned manifest lock")
+            .itrs
+            .get(&name)
+            .cloned()
+            .ok_or(Error::ItrDoesNotExist)?;
 
-        let logs = self.logs.read().expect("unwrapped poisoned logs lock");
-        let log = match logs.get(&itr.log) {
-            Some(log) => log,
-            None => return Error::LogDoesNotExist.into(),
+        let (ingestion, start, end) = {
+            let logs = self.logs.read().expect("unwrapped poisoned logs lock");
+            let msg_id = msg_id as u64;
+            let ingestion = logs
+                .get(&itr.log)
+                .ok_or(Error::LogDoesNotExist)?
+                .options()
+                .ingestion
+                .unwrap_or_default();
+
+            let start = match start_time {
+                Some(t) => logs.offset_at_time(&itr.log, t)?.max(msg_id),
+                None => msg_id,
+            };
+            let end = match end_time {
+                Some(t) => Some(logs.offset_at_time(&itr.log, t)?),
+                None => None,
+            };
+            (ingestion, start, end)
         };
 
-        match itr.next(log, msg_id, count) {
-            Ok(d) => Response::Data(d),
-            Err(e) => e.into(),
-        }
+        let read = |offset, count| {
+            let logs = self.logs.read().expect("unwrapped poisoned logs lock");
+            Ok(logs.read(&itr.log, offset, count)?)
+        };
+        self.lua.with(&itr, |compiled| {
+            itr.next_with(compiled, read, ingestion, start, end, count)
+        })
     }
 }
 
@@ -227,13 +952,13 @@ mod tests {
     #[test]
     fn test_db_log_list() {
         let db = DB::new(temp_db_path());
-        db.log_add("metric".into());
-        db.log_add("test".into());
+        db.log_add("metric".into(), LogOptions::default());
+        db.log_add("test".into(), LogOptions::default());
 
         let resp = db.log_list();
         match resp {
             Response::Data(bytes) => {
-                let out: Vec<String> = serde_cbor::from_slice(&*(bytes[0])).unwrap();
+                let out: Vec<String> = serde_cbor::from_slice(&bytes[0]).unwrap();
                 let l1 = "test".to_owned();
                 let l2 = "metric".to_owned();
 
@@ -247,17 +972,37 @@ mod tests {
     #[test]
     fn test_db_log_show() {
         let db = DB::new(temp_db_path());
-        db.log_add("test".into());
+        db.log_add("test".into(), LogOptions::default());
+        db.itr_add("test".into(), "b".into(), "map".into(), "return msg".into());
+        db.itr_add("test".into(), "a".into(), "map".into(), "return msg".into());
         let resp = db.log_show("test".into());
+        let size_bytes = db.logs.read().unwrap().stats("test").unwrap().size_bytes;
 
-        let log = serde_cbor::to_vec(&manifest::LogRegistrant {
-            name: "test".into(),
-            created_at: SystemTime::now()
-                .duration_since(SystemTime::UNIX_EPOCH)
-                .expect("could not get system time")
-                .as_secs() as usize,
+        let log = serde_cbor::to_vec(&LogInfo {
+            registrant: &manifest::LogRegistrant {
+                name: "test".into(),
+                created_at: SystemTime::now()
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .expect("could not get system time")
+                    .as_secs() as usize,
+                options: LogOptions::default(),
+            },
+            effective_options: logs::builtin_options(),
+            earliest_offset: 0,
+            summary: logs::LogSummary {
+                message_count: 0,
+                first_offset: None,
+                last_offset: None,
+                next_offset: 0,
+                size_bytes,
+                segment_count: 1,
+                earliest_timestamp: None,
+                latest_timestamp: None,
+                append_rate: 0.0,
+            },
+            iterators: vec!["a".into(), "b".into()],
         })
-        .expect("could not marshal comparison LogRegistrant");
+        .expect("could not marshal comparison LogInfo");
 
         match resp {
             Response::Data(bytes) => assert_eq!(*bytes[0], *log),
@@ -266,50 +1011,586 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_db_log_alter() {
+        let defaults = LogOptions {
+            max_segment_age: Some(0),
+            ..LogOptions::default()
+        };
+        let path = temp_db_path();
+        let db = DB::open(path.clone(), defaults.clone());
+        let options = LogOptions {
+            description: Some("page views".into()),
+            labels: vec![("owner".to_string(), "web".to_string())]
+                .into_iter()
+                .collect(),
+            ..LogOptions::default()
+        };
+        db.log_add("test".into(), options.clone());
+
+        let alter = |options, reset: &[&str]| {
+            db.exec(Command::LogAlter(commands::LogAlter {
+                log_name: "test".into(),
+                options,
+                reset: reset.iter().map(|r| r.to_string()).collect(),
+            }))
+        };
+        let hour = LogOptions {
+            max_segment_age: Some(3600),
+            ..LogOptions::default()
+        };
+        assert_eq!(alter(hour, &[]), Response::Info(OK_RESP.into()));
+        let segment_count = || {
+            db.msg_add("test".into(), vec![0x01], Metadata::default());
+            db.msg_add("test".into(), vec![0x01], Metadata::default());
+            db.logs.read().unwrap().get("test").unwrap().segment_count()
+        };
+        assert_eq!(segment_count(), 1);
+
+        // Back to the server-wide default of rolling every message.
+        assert_eq!(
+            alter(LogOptions::default(), &["max_segment_age"]),
+            Response::Info(OK_RESP.into())
+        );
+        assert_eq!(segment_count(), 3);
+
+        let raw = LogOptions {
+            ingestion: Some(commands::IngestionMode::Raw),
+            ..LogOptions::default()
+        };
+        assert_eq!(
+            alter(raw, &[]),
+            Response::Error(Error::LogOptionNotAlterable)
+        );
+        assert_eq!(
+            alter(LogOptions::default(), &["nope"]),
+            Response::Error(Error::LogOptionUnknown)
+        );
+        drop(db);
+
+        // The description and labels were kept through every change.
+        let db = DB::open(path, defaults);
+        let m = db.manifest.read().unwrap();
+        assert_eq!(m.logs["test"].options, options);
+    }
+
+    #[test]
+    fn test_db_log_show_log_dne() {
+        let db = DB::new(temp_db_path());
+        match db.log_show("test".into()) {
+            Response::Error(e) => assert_eq!(e, Error::LogDoesNotExist),
+            _ => panic!("expected log show to return an error"),
+        }
+    }
+
+    #[test]
+    fn test_db_enforce_retention() {
+        let db = DB::new(temp_db_path());
+        db.log_add(
+            "test".into(),
+            LogOptions {
+                max_segment_size: Some(5 + 12),
+                retention_max_messages: Some(1),
+                ..LogOptions::default()
+            },
+        );
+        db.itr_add("test".into(), "i".into(), "map".into(), "return msg".into());
+        for i in 0..3 {
+            db.msg_add("test".into(), vec![0x19, 0x03, i], Metadata::default());
+        }
+
+        db.enforce_retention();
+
+        match db.itr_next("i".into(), 0, 1, None, None) {
+            Response::Error(e) => assert_eq!(e, Error::MsgOffsetRetired),
+            _ => panic!("expected itr_next to return an error"),
+        };
+        match db.itr_next("i".into(), 2, 1, None, None) {
+            Response::Data(msgs) => assert_eq!(msgs, vec![vec![0x19, 0x03, 2]]),
+            _ => panic!("expected itr_next to return data"),
+        };
+    }
+
     #[test]
     fn test_db_log_add() {
         let db = DB::new(temp_db_path());
 
-        match db.log_add("test".into()) {
+        match db.log_add("test".into(), LogOptions::default()) {
             Response::Info(i) => assert_eq!(i, OK_RESP),
             _ => panic!("expected info to be returned"),
         };
 
-        match db.log_add("test".into()) {
+        match db.log_add("test".into(), LogOptions::default()) {
             Response::Info(i) => assert_eq!(i, OK_RESP),
             _ => panic!("expected info to be returned"),
         };
     }
 
+    #[test]
+    fn test_db_log_add_falls_back_to_server_defaults() {
+        let defaults = LogOptions {
+            max_segment_age: Some(0),
+            ..LogOptions::default()
+        };
+        let db = DB::open(temp_db_path(), defaults);
+        db.log_add("default".into(), LogOptions::default());
+        db.log_add(
+            "override".into(),
+            LogOptions {
+                max_segment_age: Some(3600),
+                ..LogOptions::default()
+            },
+        );
+
+        for _ in 0..3 {
+            db.msg_add("default".into(), vec![0x01], Metadata::default());
+            db.msg_add("override".into(), vec![0x01], Metadata::default());
+        }
+
+        let logs = db.logs.read().unwrap();
+        assert_eq!(logs.get("default").unwrap().segment_count(), 3);
+        assert_eq!(logs.get("override").unwrap().segment_count(), 1);
+    }
+
     #[test]
     fn test_db_msg_add() {
         let db = DB::new(temp_db_path());
-        db.log_add("test".into());
+        db.log_add("test".into(), LogOptions::default());
 
         let msg = vec![0x19, 0x03, 0xE8];
-        match db.msg_add("test".into(), msg.clone()) {
+        match db.msg_add("test".into(), msg.clone(), Metadata::default()) {
             Response::Info(i) => assert_eq!(i, OK_RESP),
             _ => panic!("expected info to be returned"),
         };
 
         let logs = db.logs.read().unwrap();
-        assert_eq!(logs.len(), 1);
-        assert_eq!(logs["test"][0], msg);
+        assert_eq!(logs.read("test", 0, 2).unwrap().len(), 1);
+        assert_eq!(logs.read("test", 0, 1).unwrap()[0].body, msg);
+    }
+
+    #[test]
+    fn test_db_msg_add_from_producer() {
+        let db = DB::new(temp_db_path());
+        db.log_add("test".into(), LogOptions::default());
+
+        let add = |message: u8, producer_id: Option<&str>, sequence| {
+            db.exec(Command::MessageAdd(commands::MessageAdd {
+                log_name: "test".into(),
+                message: serde_cbor::Value::Bytes(vec![message]),
+                key: None,
+                headers: BTreeMap::new(),
+                producer_id: producer_id.map(String::from),
+                sequence,
+            }))
+        };
+        let report = |offset, duplicate| {
+            Response::Data(vec![
+                serde_cbor::to_vec(&AddReport { offset, duplicate }).unwrap()
+            ])
+        };
+
+        assert_eq!(add(0x01, None, None), Response::Info(OK_RESP.into()));
+        assert_eq!(add(0x02, Some("p"), Some(1)), report(1, false));
+        // A retry is acknowledged with the original offset.
+        assert_eq!(add(0x02, Some("p"), Some(1)), report(1, true));
+        assert_eq!(add(0x03, Some("p"), Some(2)), report(2, false));
+        assert_eq!(
+            add(0x03, Some("p"), None),
+            Response::Error(Error::MsgSequenceMissing)
+        );
+        assert_eq!(
+            add(0x02, Some("p"), Some(1)),
+            Response::Error(Error::MsgSequenceTooOld)
+        );
+
+        let logs = db.logs.read().unwrap();
+        let bodies: Vec<Vec<u8>> = logs
+            .read("test", 0, 10)
+            .unwrap()
+            .into_iter()
+            .map(|m| m.body)
+            .collect();
+        assert_eq!(bodies, vec![vec![0x01], vec![0x02], vec![0x03]]);
+    }
+
+    #[test]
+    fn test_db_recovers_logs_on_open() {
+        let path = temp_db_path();
+        let db = DB::new(path.clone());
+        db.log_add("test".into(), LogOptions::default());
+        db.itr_add("test".into(), "i".into(), "map".into(), "return msg".into());
+        db.msg_add("test".into(), vec![0x19, 0x03, 0xE8], Metadata::default());
+        db.msg_add("test".into(), vec![0x61, 0x61], Metadata::default());
+        drop(db);
+
+        let db = DB::new(path);
+        match db.msg_add("test".into(), vec![0x01], Metadata::default()) {
+            Response::Info(i) => assert_eq!(i, OK_RESP),
+            _ => panic!("expected info to be returned"),
+        };
+
+        match db.itr_next("i".into(), 0, 3, None, None) {
+            Response::Data(msgs) => {
+                assert_eq!(msgs[0], vec![0x19, 0x03, 0xE8]);
+                assert_eq!(msgs[1], vec![0x61, 0x61]);
+                assert_eq!(msgs[2], vec![0x01]);
+            }
+            _ => panic!("expected itr_next to return data"),
+        };
+    }
+
+    #[test]
+    fn test_db_itr_next_stops_at_end_of_log() {
+        let db = DB::new(temp_db_path());
+        db.log_add("test".into(), LogOptions::default());
+        db.itr_add("test".into(), "i".into(), "map".into(), "return msg".into());
+        db.msg_add("test".into(), vec![0x01], Metadata::default());
+        db.msg_add("test".into(), vec![0x02], Metadata::default());
+
+        match db.itr_next("i".into(), 1, 10, None, None) {
+            Response::Data(msgs) => assert_eq!(msgs, vec![vec![0x02]]),
+            _ => panic!("expected itr_next to return data"),
+        };
+
+        match db.itr_next("i".into(), 5, 10, None, None) {
+            Response::Data(msgs) => assert!(msgs.is_empty()),
+            _ => panic!("expected itr_next to return data"),
+        };
+    }
+
+    #[test]
+    fn test_db_itr_sees_metadata() {
+        let db = DB::new(temp_db_path());
+        db.log_add("test".into(), LogOptions::default());
+        let func = "return string.format('%d %s %s %s', meta.offset, tostring(meta.key), \
+                    tostring(meta.headers.source), tostring(meta.ts > 0))";
+        db.itr_add("test".into(), "i".into(), "map".into(), func.into());
+
+        db.msg_add("test".into(), vec![0x01], Metadata::default());
+        let meta = Metadata {
+            key: Some("user-1".into()),
+            headers: vec![("source".to_string(), "web".to_string())]
+                .into_iter()
+                .collect(),
+            ..Metadata::default()
+        };
+        db.msg_add("test".into(), vec![0x02], meta);
+
+        let expected: Vec<Vec<u8>> = vec!["0 nil nil true", "1 user-1 web true"]
+            .into_iter()
+            .map(|s| serde_cbor::to_vec(&s).unwrap())
+            .collect();
+        match db.itr_next("i".into(), 0, 10, None, None) {
+            Response::Data(msgs) => assert_eq!(msgs, expected),
+            _ => panic!("expected itr_next to return data"),
+        };
+    }
+
+    #[test]
+    fn test_db_itr_sees_raw_msgs_as_bytes() {
+        let db = DB::new(temp_db_path());
+        let options = LogOptions {
+            ingestion: Some(commands::IngestionMode::Raw),
+            ..LogOptions::default()
+        };
+        db.log_add("test".into(), options);
+        let func = "return string.format('%s %d %d', type(msg), #msg, string.byte(msg, 2))";
+        db.itr_add("test".into(), "i".into(), "map".into(), func.into());
+
+        // Not valid CBOR.
+        match db.msg_add("test".into(), vec![0x1a, 0xff, 0x00], Metadata::default()) {
+            Response::Info(i) => assert_eq!(i, OK_RESP),
+            _ => panic!("expected raw msg to be added"),
+        };
+        match db.itr_next("i".into(), 0, 10, None, None) {
+            Response::Data(msgs) => {
+                assert_eq!(msgs, vec![serde_cbor::to_vec(&"string 3 255").unwrap()])
+            }
+            _ => panic!("expected itr_next to return data"),
+        };
+    }
+
+    #[test]
+    fn test_db_itr_next_by_time() {
+        let db = DB::new(temp_db_path());
+        db.log_add("test".into(), LogOptions::default());
+        db.itr_add("test".into(), "i".into(), "map".into(), "return msg".into());
+        let pause = || std::thread::sleep(std::time::Duration::from_millis(5));
+
+        db.msg_add("test".into(), vec![0x01], Metadata::default());
+        pause();
+        let start = logs::now_millis();
+        pause();
+        db.msg_add("test".into(), vec![0x02], Metadata::default());
+        db.msg_add("test".into(), vec![0x03], Metadata::default());
+        pause();
+        let end = logs::now_millis();
+        pause();
+        db.msg_add("test".into(), vec![0x04], Metadata::default());
+
+        match db.itr_next("i".into(), 0, 10, Some(start), Some(end)) {
+            Response::Data(msgs) => assert_eq!(msgs, vec![vec![0x02], vec![0x03]]),
+            _ => panic!("expected itr_next to return data"),
+        };
+
+        // Paging through a time range by moving message_id forward.
+        match db.itr_next("i".into(), 2, 10, Some(start), Some(end)) {
+            Response::Data(msgs) => assert_eq!(msgs, vec![vec![0x03]]),
+            _ => panic!("expected itr_next to return data"),
+        };
+
+        match db.itr_next("i".into(), 0, 10, Some(end), None) {
+            Response::Data(msgs) => assert_eq!(msgs, vec![vec![0x04]]),
+            _ => panic!("expected itr_next to return data"),
+        };
+    }
+
+    #[test]
+    fn test_db_itr_next_with_next_offset() {
+        let db = DB::new(temp_db_path());
+        db.log_add("test".into(), LogOptions::default());
+        db.itr_add("test".into(), "i".into(), "map".into(), "return msg".into());
+        for i in 0..3 {
+            db.msg_add("test".into(), vec![0x19, 0x03, i], Metadata::default());
+        }
+
+        let next = |message_id, start_time| {
+            db.exec(Command::IteratorNext(commands::IteratorNext {
+                iterator_name: "i".into(),
+                message_id,
+                count: 2,
+                start_time,
+                end_time: None,
+                with_next_offset: true,
+            }))
+        };
+        let split = |resp| match resp {
+            Response::Data(mut msgs) => {
+                let next_offset: u64 = serde_cbor::from_slice(&msgs.pop().unwrap()).unwrap();
+                (msgs, next_offset)
+            }
+            _ => panic!("expected itr_next to return data"),
+        };
+
+        let (msgs, next_offset) = split(next(0, None));
+        assert_eq!(msgs, vec![vec![0x19, 0x03, 0], vec![0x19, 0x03, 1]]);
+        assert_eq!(next_offset, 2);
+
+        let (msgs, next_offset) = split(next(2, None));
+        assert_eq!(msgs, vec![vec![0x19, 0x03, 2]]);
+        assert_eq!(next_offset, 3);
+
+        // The end of the Log has been reached, so there is nothing more to resume from.
+        let (msgs, next_offset) = split(next(3, Some(0)));
+        assert!(msgs.is_empty());
+        assert_eq!(next_offset, 3);
+    }
+
+    #[test]
+    fn test_db_filter_itr_pages_through_matches() {
+        let db = DB::new(temp_db_path());
+        db.log_add("test".into(), LogOptions::default());
+        db.itr_add(
+            "test".into(),
+            "i".into(),
+            "filter".into(),
+            "msg % 3 == 0".into(),
+        );
+        for i in 0..10 {
+            db.msg_add("test".into(), vec![i], Metadata::default());
+        }
+
+        let page = |message_id| match db.exec(Command::IteratorNext(commands::IteratorNext {
+            iterator_name: "i".into(),
+            message_id,
+            count: 2,
+            start_time: None,
+            end_time: None,
+            with_next_offset: true,
+        })) {
+            Response::Data(mut msgs) => {
+                let next_offset: u64 = serde_cbor::from_slice(&msgs.pop().unwrap()).unwrap();
+                (msgs, next_offset)
+            }
+            _ => panic!("expected itr_next to return data"),
+        };
+
+        // Matching messages are returned as they were added, rather than what the function
+        // returned for them.
+        assert_eq!(page(0), (vec![vec![0], vec![3]], 4));
+        assert_eq!(page(4), (vec![vec![6], vec![9]], 10));
+        assert_eq!(page(10), (vec![], 10));
+
+        // Reaching the end of the Log before finding enough matches still moves past the
+        // messages that didn't match.
+        db.itr_del("test".into(), "i".into());
+        db.itr_add(
+            "test".into(),
+            "i".into(),
+            "filter".into(),
+            "msg == 8".into(),
+        );
+        assert_eq!(page(0), (vec![vec![8]], 10));
+
+        // Sparse matches are read up to a limit per request, which is picked up from after.
+        for i in 10..250u64 {
+            let msg = serde_cbor::to_vec(&i).unwrap();
+            db.msg_add("test".into(), msg, Metadata::default());
+        }
+        db.itr_del("test".into(), "i".into());
+        db.itr_add(
+            "test".into(),
+            "i".into(),
+            "filter".into(),
+            "msg == 240".into(),
+        );
+        let match_240 = serde_cbor::to_vec(&240u64).unwrap();
+        assert_eq!(page(10), (vec![], 210));
+        assert_eq!(page(210), (vec![match_240], 250));
+    }
+
+    #[test]
+    fn test_db_itr_next_across_compacted_gaps() {
+        let db = DB::new(temp_db_path());
+        db.log_add(
+            "test".into(),
+            LogOptions {
+                max_segment_size: Some(1),
+                cleanup_policy: Some(commands::CleanupPolicy::Compact),
+                ..LogOptions::default()
+            },
+        );
+        db.itr_add(
+            "test".into(),
+            "i".into(),
+            "map".into(),
+            "return meta.offset".into(),
+        );
+        for i in 0..6 {
+            let meta = Metadata {
+                key: Some(format!("k{}", i % 2)),
+                ..Metadata::default()
+            };
+            db.msg_add("test".into(), vec![0x19, 0x03, i], meta);
+        }
+        db.enforce_retention();
+
+        // Only offsets 4 and 5 are left, and 5 is still in the active Segment.
+        let offsets = |msgs: Vec<Vec<u8>>| -> Vec<u64> {
+            msgs.iter()
+                .map(|m| serde_cbor::from_slice(m).unwrap())
+                .collect()
+        };
+        let (msgs, next_offset) = db.itr_page("i".into(), 0, 1, None, None).unwrap();
+        assert_eq!((offsets(msgs), next_offset), (vec![4], 5));
+        let (msgs, next_offset) = db.itr_page("i".into(), 5, 10, None, None).unwrap();
+        assert_eq!((offsets(msgs), next_offset), (vec![5], 6));
     }
 
     #[test]
     fn test_db_msg_add_log_dne() {
         let db = DB::new(temp_db_path());
-        match db.msg_add("test".into(), b"hello".to_vec()) {
+        match db.msg_add("test".into(), b"hello".to_vec(), Metadata::default()) {
             Response::Error(_e) => (),
             _ => panic!("expected response to be an error"),
         }
     }
 
+    #[test]
+    fn test_db_msg_import() {
+        let db = DB::new(temp_db_path());
+        db.log_add("test".into(), LogOptions::default());
+        db.itr_add("test".into(), "i".into(), "map".into(), "return msg".into());
+
+        let imported = |message, time| commands::ImportedMessage { message, time };
+        let batch = vec![
+            imported(serde_cbor::Value::Bytes(vec![0x01]), Some(1_000)),
+            imported(serde_cbor::Value::Bytes(vec![0x1a, 0x01]), Some(1_500)),
+            imported(serde_cbor::Value::Integer(2), Some(2_000)),
+            // Older than the message before it, so it is given that one's time.
+            imported(serde_cbor::Value::Bytes(vec![0x02]), Some(500)),
+            imported(serde_cbor::Value::Bytes(vec![0x03]), Some(3_000)),
+        ];
+
+        let report: serde_cbor::Value = match db.msg_import("test".into(), batch) {
+            Response::Data(data) => serde_cbor::from_slice(&data[0]).unwrap(),
+            _ => panic!("expected msg_import to return data"),
+        };
+        let expected: serde_cbor::Value = serde_cbor::from_slice(
+            &serde_cbor::to_vec(&ImportReport {
+                added: 3,
+                rejected: vec![
+                    RejectedMessage {
+                        index: 1,
+                        error: Error::MsgNotValidCbor,
+                    },
+                    RejectedMessage {
+                        index: 2,
+                        error: Error::MsgFieldNotOfTypeBinary,
+                    },
+                ],
+            })
+            .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(report, expected);
+
+        match db.itr_next("i".into(), 0, 10, Some(1_000), Some(3_000)) {
+            Response::Data(msgs) => assert_eq!(msgs, vec![vec![0x01], vec![0x02]]),
+            _ => panic!("expected itr_next to return data"),
+        };
+        match db.itr_next("i".into(), 0, 10, Some(2_000), None) {
+            Response::Data(msgs) => assert_eq!(msgs, vec![vec![0x03]]),
+            _ => panic!("expected itr_next to return data"),
+        };
+
+        match db.msg_import("nope".into(), vec![]) {
+            Response::Error(e) => assert_eq!(e, Error::LogDoesNotExist),
+            _ => panic!("expected response to be an error"),
+        }
+    }
+
+    #[test]
+    fn test_db_msg_read_returns_msgs_as_added() {
+        let db = DB::new(temp_db_path());
+        db.log_add("test".into(), LogOptions::default());
+        // A half precision 1.0 and a map with an integer key and a byte string value, neither of
+        // which survive a trip through Lua unchanged.
+        let msgs = [
+            vec![0xf9, 0x3c, 0x00],
+            vec![0xa1, 0x01, 0x41, 0x00],
+            vec![0x03],
+        ];
+        for msg in msgs.iter() {
+            db.msg_add("test".into(), msg.clone(), Metadata::default());
+        }
+
+        let read = |log: &str, message_id, count| match db.exec(Command::MessageRead(
+            commands::MessageRead {
+                log_name: log.into(),
+                message_id,
+                count,
+                start_time: None,
+                end_time: None,
+            },
+        )) {
+            Response::Data(mut data) => {
+                let next_offset: u64 = serde_cbor::from_slice(&data.pop().unwrap()).unwrap();
+                Ok((data, next_offset))
+            }
+            Response::Error(e) => Err(e),
+            _ => panic!("expected msg_read to return data"),
+        };
+        assert_eq!(read("test", 0, 2), Ok((msgs[..2].to_vec(), 2)));
+        assert_eq!(read("test", 2, 2), Ok((msgs[2..].to_vec(), 3)));
+        assert_eq!(read("test", 3, 2), Ok((vec![], 3)));
+        assert_eq!(read("nope", 0, 2), Err(Error::LogDoesNotExist));
+    }
+
     #[test]
     fn test_db_log_del() {
         let db = DB::new(temp_db_path());
-        db.log_add("test".into());
+        db.log_add("test".into(), LogOptions::default());
         db.itr_add(
             "test".into(),
             "fun".into(),
@@ -318,17 +1599,229 @@ mod tests {
         );
         assert_eq!(db.manifest.read().unwrap().logs.len(), 1);
 
-        match db.log_delete("test".into()) {
+        match db.log_delete("test".into(), None) {
             Response::Info(i) => assert_eq!(&*i, OK_RESP),
             _ => panic!("expected response to be info"),
         };
         assert_eq!(db.manifest.read().unwrap().logs.len(), 0);
     }
 
+    #[test]
+    fn test_db_log_del_removes_data() {
+        let path = temp_db_path();
+        let db = DB::new(path.clone());
+        db.log_add("test".into(), LogOptions::default());
+        db.msg_add("test".into(), vec![0x01], Metadata::default());
+        assert!(db.path.join("logs/test").exists());
+
+        db.log_delete("test".into(), None);
+        assert!(!db.path.join("logs/test").exists());
+        assert_eq!(std::fs::read_dir(db.path.join("trash")).unwrap().count(), 0);
+
+        // Re-adding the Log starts it over.
+        db.log_add("test".into(), LogOptions::default());
+        assert_eq!(
+            db.logs.read().unwrap().stats("test").unwrap().next_offset,
+            0
+        );
+        drop(db);
+
+        // Data the Manifest doesn't know about is moved out of the way on open, but never removed
+        // in case it was the Manifest that lost track of it.
+        std::fs::create_dir_all(format!("{}/logs/gone", path)).unwrap();
+        std::fs::write(format!("{}/logs/gone/data", path), b"kept").unwrap();
+        std::fs::create_dir_all(format!("{}/trash/gone.1", path)).unwrap();
+        let db = DB::new(path);
+        assert!(!db.path.join("logs/gone").exists());
+        assert!(db.path.join("trash/gone.1").exists());
+        assert!(db.path.join("logs/test").exists());
+        let moved: Vec<PathBuf> = std::fs::read_dir(db.path.join("trash"))
+            .unwrap()
+            .map(|e| e.unwrap().path())
+            .filter(|p| p.file_name().unwrap() != "gone.1")
+            .collect();
+        assert_eq!(moved.len(), 1);
+        assert_eq!(std::fs::read(moved[0].join("data")).unwrap(), b"kept");
+    }
+
+    #[test]
+    fn test_db_log_del_removes_log_that_could_not_be_opened() {
+        let path = temp_db_path();
+        let db = DB::new(path.clone());
+        db.log_add("test".into(), LogOptions::default());
+        db.msg_add("test".into(), vec![0x01], Metadata::default());
+        drop(db);
+
+        for entry in std::fs::read_dir(format!("{}/logs/test", path)).unwrap() {
+            let entry = entry.unwrap().path();
+            if entry.extension() == Some("dat".as_ref()) {
+                let mut data = std::fs::read(&entry).unwrap();
+                data[0..4].copy_from_slice(&[0; 4]);
+                std::fs::write(&entry, data).unwrap();
+            }
+        }
+        let db = DB::new(path.clone());
+        assert!(!db.logs.read().unwrap().contains("test"));
+
+        match db.log_delete("test".into(), None) {
+            Response::Info(i) => assert_eq!(&*i, OK_RESP),
+            _ => panic!("expected response to be info"),
+        };
+        assert!(!db.manifest.read().unwrap().logs.contains_key("test"));
+        assert!(!db.path.join("logs/test").exists());
+        assert_eq!(std::fs::read_dir(db.path.join("trash")).unwrap().count(), 0);
+        match db.log_delete("test".into(), None) {
+            Response::Error(e) => assert_eq!(e, Error::LogDoesNotExist),
+            _ => panic!("expected an error"),
+        }
+        drop(db);
+
+        let db = DB::new(path);
+        assert!(db.manifest.read().unwrap().logs.is_empty());
+    }
+
+    #[test]
+    fn test_db_log_soft_del_and_undel() {
+        let path = temp_db_path();
+        let db = DB::new(path.clone());
+        db.log_add("test".into(), LogOptions::default());
+        db.itr_add("test".into(), "i".into(), "map".into(), "return msg".into());
+        db.msg_add("test".into(), vec![0x01], Metadata::default());
+
+        db.log_delete("test".into(), Some(60));
+        assert!(!db.path.join("logs/test").exists());
+        match db.itr_next("i".into(), 0, 1, None, None) {
+            Response::Error(e) => assert_eq!(e, Error::ItrDoesNotExist),
+            _ => panic!("expected an error"),
+        }
+
+        // The grace period isn't over, so purging leaves it alone.
+        db.purge_deleted_logs();
+        drop(db);
+        let db = DB::new(path.clone());
+
+        db.log_add("test".into(), LogOptions::default());
+        match db.log_undelete("test".into()) {
+            Response::Error(e) => assert_eq!(e, Error::LogExistsWithSameName),
+            _ => panic!("expected an error"),
+        }
+        db.log_delete("test".into(), None);
+        match db.log_undelete("nope".into()) {
+            Response::Error(e) => assert_eq!(e, Error::LogDoesNotExist),
+            _ => panic!("expected an error"),
+        }
+
+        match db.log_undelete("test".into()) {
+            Response::Info(i) => assert_eq!(i, OK_RESP),
+            _ => panic!("expected response to be info"),
+        };
+        match db.itr_next("i".into(), 0, 1, None, None) {
+            Response::Data(msgs) => assert_eq!(msgs, vec![vec![0x01]]),
+            _ => panic!("expected itr_next to return data"),
+        };
+        assert!(db.manifest.read().unwrap().deleted_logs.is_empty());
+    }
+
+    #[test]
+    fn test_db_purges_deleted_logs_after_grace_period() {
+        let defaults = LogOptions {
+            delete_grace_period: Some(60),
+            ..LogOptions::default()
+        };
+        let db = DB::open(temp_db_path(), defaults);
+        db.log_add("test".into(), LogOptions::default());
+        db.log_delete("test".into(), None);
+        assert_eq!(std::fs::read_dir(db.path.join("trash")).unwrap().count(), 1);
+
+        db.manifest
+            .write()
+            .unwrap()
+            .deleted_logs
+            .get_mut("test")
+            .unwrap()
+            .purge_at = 0;
+        db.purge_deleted_logs();
+        assert!(db.manifest.read().unwrap().deleted_logs.is_empty());
+        assert_eq!(std::fs::read_dir(db.path.join("trash")).unwrap().count(), 0);
+        match db.log_undelete("test".into()) {
+            Response::Error(e) => assert_eq!(e, Error::LogDoesNotExist),
+            _ => panic!("expected an error"),
+        }
+    }
+
+    #[test]
+    fn test_db_backup_and_restore() {
+        let db = DB::open(
+            temp_db_path(),
+            LogOptions {
+                max_segment_size: Some(5 + 2 * 12),
+                ..LogOptions::default()
+            },
+        );
+        db.log_add("test".into(), LogOptions::default());
+        db.itr_add("test".into(), "i".into(), "map".into(), "return msg".into());
+        for i in 0..3 {
+            db.msg_add("test".into(), vec![0x19, 0x03, i], Metadata::default());
+        }
+
+        let backup = PathBuf::from(temp_db_path()).join("backup");
+        let info = db.backup(backup.clone()).unwrap();
+        assert_eq!(info.logs["test"], 3);
+        assert!(db.backup(backup.clone()).is_err());
+
+        // Messages added after the backup started aren't part of it.
+        db.msg_add("test".into(), vec![0x19, 0x03, 3], Metadata::default());
+
+        let restored = PathBuf::from(temp_db_path());
+        assert!(DB::restore(&backup, &db.path).is_err());
+        DB::restore(&backup, &restored).unwrap();
+
+        let db = DB::new(restored.to_str().unwrap().into());
+        match db.itr_next("i".into(), 0, 10, None, None) {
+            Response::Data(msgs) => assert_eq!(
+                msgs,
+                vec![
+                    vec![0x19, 0x03, 0],
+                    vec![0x19, 0x03, 1],
+                    vec![0x19, 0x03, 2]
+                ]
+            ),
+            _ => panic!("expected itr_next to return data"),
+        };
+        match db.msg_add("test".into(), vec![0x01], Metadata::default()) {
+            Response::Info(i) => assert_eq!(i, OK_RESP),
+            _ => panic!("expected info to be returned"),
+        };
+    }
+
+    #[test]
+    fn test_db_restore_rejects_corrupt_backup() {
+        let db = DB::new(temp_db_path());
+        db.log_add("test".into(), LogOptions::default());
+        db.msg_add("test".into(), vec![0x19, 0x03, 0xE8], Metadata::default());
+
+        let backup = PathBuf::from(temp_db_path());
+        db.backup(backup.clone()).unwrap();
+
+        let segment = std::fs::read_dir(backup.join("logs/test"))
+            .unwrap()
+            .map(|e| e.unwrap().path())
+            .find(|p| p.extension().unwrap() == "dat")
+            .unwrap();
+        let mut bytes = std::fs::read(&segment).unwrap();
+        *bytes.last_mut().unwrap() ^= 0xFF;
+        std::fs::write(&segment, bytes).unwrap();
+
+        let restored = PathBuf::from(temp_db_path()).join("db");
+        let err = DB::restore(&backup, &restored).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert!(!restored.exists());
+    }
+
     #[test]
     fn test_db_itr_list() {
         let db = DB::new(temp_db_path());
-        db.log_add("log".into());
+        db.log_add("log".into(), LogOptions::default());
         db.itr_add("log".into(), "i1".into(), "map".into(), "return msg".into());
         db.itr_add(
             "log2".into(),
@@ -338,7 +1831,7 @@ mod tests {
         );
         match db.itr_list(Some("log".into())) {
             Response::Data(bytes) => {
-                let out: String = serde_cbor::from_slice(&*(bytes[0])).unwrap();
+                let out: String = serde_cbor::from_slice(&bytes[0]).unwrap();
                 assert_eq!(out, "i1".to_owned());
             }
             _ => panic!("expected itr_list to return data"),
@@ -346,8 +1839,8 @@ mod tests {
 
         match db.itr_list(None) {
             Response::Data(bytes) => {
-                let first: String = serde_cbor::from_slice(&*(bytes[0])).unwrap();
-                let secnd: String = serde_cbor::from_slice(&*(bytes[1])).unwrap();
+                let first: String = serde_cbor::from_slice(&bytes[0]).unwrap();
+                let secnd: String = serde_cbor::from_slice(&bytes[1]).unwrap();
                 assert!((first == "i1" && secnd == "i2") || (secnd == "i1" && first == "i2"));
             }
             _ => panic!("expected itr_list to return data"),
@@ -374,4 +1867,40 @@ mod tests {
         };
         assert_eq!(db.manifest.read().unwrap().itrs.len(), 0);
     }
+
+    #[test]
+    fn test_db_itr_lua_states_are_reused_until_replaced() {
+        let db = DB::new(temp_db_path());
+        db.log_add("test".into(), LogOptions::default());
+        db.msg_add("test".into(), vec![0x01], Metadata::default());
+        db.msg_add("test".into(), vec![0x02], Metadata::default());
+        // Globals last from one message to the next, but not past the request.
+        let counting = "calls = (calls or 0) + 1; return calls";
+        db.itr_add("test".into(), "i".into(), "map".into(), counting.into());
+
+        let next = |db: &DB| match db.itr_next("i".into(), 0, 10, None, None) {
+            Response::Data(msgs) => msgs,
+            _ => panic!("expected itr_next to return data"),
+        };
+        let cbor = |vs: &[u64]| -> Vec<Vec<u8>> {
+            vs.iter().map(|v| serde_cbor::to_vec(v).unwrap()).collect()
+        };
+        assert_eq!(next(&db), cbor(&[1, 2]));
+        assert_eq!(next(&db), cbor(&[1, 2]));
+        assert_eq!(db.lua.idle("i"), 1);
+
+        db.itr_del("test".into(), "i".into());
+        assert_eq!(db.lua.idle("i"), 0);
+
+        // Nor do they replace the state's own globals, such as the standard library.
+        let clearing = "local n = string.len('ab'); string = nil; return n + msg";
+        db.itr_add("test".into(), "i".into(), "map".into(), clearing.into());
+        assert_eq!(next(&db), cbor(&[3, 4]));
+        assert_eq!(next(&db), cbor(&[3, 4]));
+        db.itr_del("test".into(), "i".into());
+
+        // A single expression works as well as a block.
+        db.itr_add("test".into(), "i".into(), "map".into(), "msg + 1".into());
+        assert_eq!(next(&db), cbor(&[2, 3]));
+    }
 }
@@ -27,6 +27,31 @@ pub enum Error {
     ItrTypeInvalid = 0x10,
     MsgIdNotNumber = 0x11,
     MsgFieldNotOfTypeBinary = 0x12,
+
+    // Storage Errors
+    ErrWritingSegment = 0x13,
+    ErrReadingSegment = 0x14,
+    MsgOffsetRetired = 0x15,
+
+    // DB Errors
+    LogExistsWithSameName = 0x16,
+    ErrWritingBackup = 0x17,
+
+    // Idempotent Producer Errors
+    MsgSequenceMissing = 0x18,
+    MsgSequenceTooOld = 0x19,
+
+    // Message Policy Errors
+    MsgTooLarge = 0x1A,
+    MsgSchemaTypeMismatch = 0x1B,
+    MsgSchemaRequiredMissing = 0x1C,
+    MsgSchemaAdditionalProperty = 0x1D,
+    MsgSchemaLengthOutOfBounds = 0x1E,
+    MsgSchemaValueOutOfRange = 0x1F,
+
+    // Log Option Errors
+    LogOptionUnknown = 0x20,
+    LogOptionNotAlterable = 0x21,
 }
 
 impl Error {
@@ -35,6 +60,18 @@ impl Error {
     }
 }
 
+impl From<storage::Error> for Error {
+    fn from(e: storage::Error) -> Self {
+        match e {
+            storage::Error::LogAlreadyExists => Error::LogExistsWithSameName,
+            storage::Error::LogDoesNotExist => Error::LogDoesNotExist,
+            storage::Error::OffsetRetired => Error::MsgOffsetRetired,
+            storage::Error::InvalidMessage => Error::MsgNotValidCbor,
+            storage::Error::Io(_) => Error::ErrReadingSegment,
+        }
+    }
+}
+
 impl From<Error> for Bytes {
     fn from(e: Error) -> Self {
         format!("{:?}", e).into()
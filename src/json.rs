@@ -0,0 +1,93 @@
+use serde_cbor::Value as CborValue;
+use serde_json::Value as JsonValue;
+use std::convert::TryFrom;
+
+/// Encodes a CBOR value as JSON. Byte strings become hex strings, and map keys that aren't
+/// strings are encoded as JSON and then used as strings.
+pub fn to_json(value: &CborValue) -> String {
+    serde_json::to_string(&json_value(value)).expect("could not serialize json")
+}
+
+/// The string a map key is written as in JSON.
+pub fn key_string(key: &CborValue) -> String {
+    match key {
+        CborValue::Text(s) => s.clone(),
+        key => to_json(key),
+    }
+}
+
+fn json_value(value: &CborValue) -> JsonValue {
+    match value {
+        CborValue::Null => JsonValue::Null,
+        CborValue::Bool(b) => JsonValue::Bool(*b),
+        CborValue::Integer(i) => match (u64::try_from(*i), i64::try_from(*i)) {
+            (Ok(u), _) => u.into(),
+            (_, Ok(i)) => i.into(),
+            // Only CBOR's negative integers below i64::MIN get here.
+            _ => (*i as f64).into(),
+        },
+        // Non-finite floats have no JSON form, and become null.
+        CborValue::Float(f) => (*f).into(),
+        CborValue::Bytes(bytes) => {
+            JsonValue::String(bytes.iter().map(|b| format!("{:02x}", b)).collect())
+        }
+        CborValue::Text(s) => JsonValue::String(s.clone()),
+        CborValue::Array(values) => JsonValue::Array(values.iter().map(json_value).collect()),
+        CborValue::Map(map) => JsonValue::Object(
+            map.iter()
+                .map(|(k, v)| (key_string(k), json_value(v)))
+                .collect(),
+        ),
+        CborValue::Tag(_, value) => json_value(value),
+        _ => JsonValue::Null,
+    }
+}
+
+/// Decodes a single JSON value, such as a line of a JSON Lines file, into a CBOR value. Integers
+/// stay integers, and every other number becomes a float.
+pub fn from_json(s: &str) -> Result<CborValue, String> {
+    let mut deserializer = serde_json::Deserializer::from_str(s);
+    let value = serde_cbor::value::to_value(serde_transcode::Transcoder::new(&mut deserializer))
+        .map_err(|e| e.to_string())?;
+    deserializer.end().map_err(|e| e.to_string())?;
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_json_round_trip() {
+        let line = r#"{"a": [1, -2.5, true, null], "b": "x\"é😀", "c": {}}"#;
+        let value = from_json(line).unwrap();
+        assert_eq!(
+            to_json(&value),
+            r#"{"a":[1,-2.5,true,null],"b":"x\"é😀","c":{}}"#
+        );
+    }
+
+    #[test]
+    fn test_json_keeps_number_types() {
+        let value = from_json("[1, 1.0, -1, 18446744073709551615, 1e2]").unwrap();
+        assert_eq!(
+            value,
+            CborValue::Array(vec![
+                CborValue::Integer(1),
+                CborValue::Float(1.0),
+                CborValue::Integer(-1),
+                CborValue::Integer(u64::MAX as i128),
+                CborValue::Float(100.0),
+            ])
+        );
+        assert_eq!(to_json(&value), "[1,1.0,-1,18446744073709551615,100.0]");
+    }
+
+    #[test]
+    fn test_from_json_rejects_invalid() {
+        assert!(from_json("{\"a\": }").is_err());
+        assert!(from_json("[1, 2").is_err());
+        assert!(from_json("1 2").is_err());
+        assert!(from_json("").is_err());
+    }
+}
@@ -1,4 +1,3 @@
-
 #[macro_use]
 extern crate log;
 #[macro_use]
@@ -7,7 +6,9 @@ pub mod commands;
 pub mod config;
 pub mod db;
 pub mod errors;
+pub mod json;
 pub mod protocol;
+pub mod schema;
 pub mod server;
 #[cfg(test)]
-mod test_util;
\ No newline at end of file
+mod test_util;
@@ -26,6 +26,11 @@ enum RequestCodes {
     IteratorList = 0x06,
     IteratorNext = 0x07,
     IteratorDelete = 0x08,
+    LogUndelete = 0x09,
+    Backup = 0x0A,
+    MessageImport = 0x0B,
+    LogAlter = 0x0C,
+    MessageRead = 0x0D,
 }
 
 pub struct Connection {
@@ -100,12 +105,17 @@ fn read_command(bytes: BytesMut) -> Result<Command, Error> {
         IteratorList => parse_cbor!(IteratorList, data),
         IteratorNext => parse_cbor!(IteratorNext, data),
         IteratorDelete => parse_cbor!(IteratorDelete, data),
+        LogUndelete => parse_cbor!(LogUndelete, data),
+        Backup => parse_cbor!(Backup, data),
+        MessageImport => parse_cbor!(MessageImport, data),
+        LogAlter => parse_cbor!(LogAlter, data),
+        MessageRead => parse_cbor!(MessageRead, data),
     };
 
     Ok(cmd)
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Eq)]
 pub enum Response {
     Info(Vec<u8>),
     Data(Vec<Vec<u8>>),
@@ -121,11 +131,11 @@ impl From<Error> for Response {
 impl From<Response> for Bytes {
     fn from(r: Response) -> Bytes {
         match r {
-            Response::Info(data) => [&[FrameKind::Info.to_u8().unwrap(), 0x00 as u8], &*data]
+            Response::Info(data) => [&[FrameKind::Info.to_u8().unwrap(), 0x00], &*data]
                 .concat()
                 .into(),
             Response::Data(datas) => {
-                let mut byt = vec![FrameKind::Data.to_u8().unwrap(), 0x00 as u8];
+                let mut byt = vec![FrameKind::Data.to_u8().unwrap(), 0x00];
                 for mut data in datas {
                     let len = u32::to_be_bytes(data.len() as u32);
                     byt.extend_from_slice(&len);
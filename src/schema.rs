@@ -0,0 +1,241 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+use serde_cbor::Value as CborValue;
+
+use crate::errors::Error;
+
+/// A structural schema that a Log's messages must satisfy, written in a subset of JSON Schema.
+/// Every keyword is optional, and a schema without any accepts every message.
+#[derive(Clone, Default, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct Schema {
+    /// What kind of value the message must be.
+    #[serde(default, rename = "type", skip_serializing_if = "Option::is_none")]
+    pub kind: Option<SchemaType>,
+    /// Schemas for the fields of a map, by name.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub properties: BTreeMap<String, Schema>,
+    /// Fields a map must have.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub required: Vec<String>,
+    /// Whether a map can have fields that aren't in `properties`. They can unless this is false.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub additional_properties: Option<bool>,
+    /// Schema for every element of an array.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub items: Option<Box<Schema>>,
+    /// Bounds on the number of characters in a string, bytes in a byte string, elements in an
+    /// array or fields in a map.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_length: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_length: Option<u64>,
+    /// Bounds on a number, inclusive.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub minimum: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub maximum: Option<i64>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SchemaType {
+    Null,
+    Boolean,
+    Integer,
+    /// An integer or a float.
+    Number,
+    String,
+    Bytes,
+    Array,
+    Object,
+}
+
+/// Where a message broke its Log's schema, and which rule it broke.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Violation {
+    /// Path to the value that broke the rule, such as `$.user.tags[2]`.
+    pub path: String,
+    pub rule: Error,
+}
+
+impl Schema {
+    /// Checks `value` against the schema, returning the first rule it breaks.
+    pub fn validate(&self, value: &CborValue) -> Result<(), Violation> {
+        self.check("$".to_string(), value)
+    }
+
+    fn check(&self, path: String, value: &CborValue) -> Result<(), Violation> {
+        // Tags only say how to interpret the value they wrap.
+        if let CborValue::Tag(_, value) = value {
+            return self.check(path, value);
+        }
+        let violation = |rule| {
+            Err(Violation {
+                path: path.clone(),
+                rule,
+            })
+        };
+
+        if let Some(kind) = self.kind {
+            if !kind.matches(value) {
+                return violation(Error::MsgSchemaTypeMismatch);
+            }
+        }
+
+        let length = match value {
+            CborValue::Text(s) => Some(s.chars().count() as u64),
+            CborValue::Bytes(b) => Some(b.len() as u64),
+            CborValue::Array(a) => Some(a.len() as u64),
+            CborValue::Map(m) => Some(m.len() as u64),
+            _ => None,
+        };
+        if let Some(length) = length {
+            if self.min_length.is_some_and(|min| length < min)
+                || self.max_length.is_some_and(|max| length > max)
+            {
+                return violation(Error::MsgSchemaLengthOutOfBounds);
+            }
+        }
+
+        let number = match value {
+            CborValue::Integer(i) => Some(*i as f64),
+            CborValue::Float(f) => Some(*f),
+            _ => None,
+        };
+        if let Some(number) = number {
+            if self.minimum.is_some_and(|min| number < min as f64)
+                || self.maximum.is_some_and(|max| number > max as f64)
+            {
+                return violation(Error::MsgSchemaValueOutOfRange);
+            }
+        }
+
+        match value {
+            CborValue::Map(map) => {
+                let field = |name: &str| map.get(&CborValue::Text(name.to_string()));
+                for name in self.required.iter() {
+                    if field(name).is_none() {
+                        return Err(Violation {
+                            path: format!("{}.{}", path, name),
+                            rule: Error::MsgSchemaRequiredMissing,
+                        });
+                    }
+                }
+                for (name, value) in map.iter() {
+                    let name = match name {
+                        CborValue::Text(name) => name.clone(),
+                        name => crate::json::key_string(name),
+                    };
+                    match self.properties.get(&name) {
+                        Some(schema) => schema.check(format!("{}.{}", path, name), value)?,
+                        None if self.additional_properties == Some(false) => {
+                            return Err(Violation {
+                                path: format!("{}.{}", path, name),
+                                rule: Error::MsgSchemaAdditionalProperty,
+                            });
+                        }
+                        None => (),
+                    }
+                }
+            }
+            CborValue::Array(items) => {
+                if let Some(schema) = &self.items {
+                    for (i, item) in items.iter().enumerate() {
+                        schema.check(format!("{}[{}]", path, i), item)?;
+                    }
+                }
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+}
+
+impl SchemaType {
+    fn matches(self, value: &CborValue) -> bool {
+        matches!(
+            (self, value),
+            (SchemaType::Null, CborValue::Null)
+                | (SchemaType::Boolean, CborValue::Bool(_))
+                | (SchemaType::Integer, CborValue::Integer(_))
+                | (SchemaType::Number, CborValue::Integer(_))
+                | (SchemaType::Number, CborValue::Float(_))
+                | (SchemaType::String, CborValue::Text(_))
+                | (SchemaType::Bytes, CborValue::Bytes(_))
+                | (SchemaType::Array, CborValue::Array(_))
+                | (SchemaType::Object, CborValue::Map(_))
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::json::from_json;
+
+    /// Reads a schema the way it arrives in a request, as CBOR.
+    fn schema(json: &str) -> Result<Schema, serde_cbor::Error> {
+        let cbor = serde_cbor::to_vec(&from_json(json).unwrap()).unwrap();
+        serde_cbor::from_slice(&cbor)
+    }
+
+    fn check(schema: &Schema, json: &str) -> Result<(), Violation> {
+        schema.validate(&from_json(json).unwrap())
+    }
+
+    #[test]
+    fn test_schema_validates_structure() {
+        let schema = schema(
+            r#"{
+                "type": "object",
+                "required": ["id"],
+                "additionalProperties": false,
+                "properties": {
+                    "id": {"type": "integer", "minimum": 1},
+                    "tags": {"type": "array", "maxLength": 2, "items": {"type": "string"}}
+                }
+            }"#,
+        )
+        .unwrap();
+        let violation = |path: &str, rule| {
+            Err(Violation {
+                path: path.to_string(),
+                rule,
+            })
+        };
+
+        assert_eq!(check(&schema, r#"{"id": 1, "tags": ["a"]}"#), Ok(()));
+        assert_eq!(
+            check(&schema, "[]"),
+            violation("$", Error::MsgSchemaTypeMismatch)
+        );
+        assert_eq!(
+            check(&schema, r#"{"tags": []}"#),
+            violation("$.id", Error::MsgSchemaRequiredMissing)
+        );
+        assert_eq!(
+            check(&schema, r#"{"id": 0}"#),
+            violation("$.id", Error::MsgSchemaValueOutOfRange)
+        );
+        assert_eq!(
+            check(&schema, r#"{"id": 1, "name": "x"}"#),
+            violation("$.name", Error::MsgSchemaAdditionalProperty)
+        );
+        assert_eq!(
+            check(&schema, r#"{"id": 1, "tags": ["a", "b", "c"]}"#),
+            violation("$.tags", Error::MsgSchemaLengthOutOfBounds)
+        );
+        assert_eq!(
+            check(&schema, r#"{"id": 1, "tags": ["a", 2]}"#),
+            violation("$.tags[1]", Error::MsgSchemaTypeMismatch)
+        );
+    }
+
+    #[test]
+    fn test_schema_rejects_unknown_keywords() {
+        assert!(schema(r#"{"pattern": "a+"}"#).is_err());
+        assert_eq!(check(&Schema::default(), r#"{"anything": [1]}"#), Ok(()));
+    }
+}
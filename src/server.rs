@@ -1,7 +1,9 @@
 use crate::config::RemitsConfig;
-use crate::db::DB;
+use crate::db::{BackupInfo, DB};
 use crate::protocol::Connection;
+use std::path::Path;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::net::TcpListener;
 
 pub async fn handle(db: Arc<DB>, mut conn: Connection) {
@@ -17,19 +19,80 @@ pub async fn handle(db: Arc<DB>, mut conn: Connection) {
         };
         debug!("received command: {:?}", &cmd);
 
-        let resp = db.exec(cmd);
+        // Requests block on locks and disk IO, and adding a message can wait for others to fill
+        // its batch before it is synced, so they are run off the async workers.
+        let exec_db = db.clone();
+        let resp = match tokio::task::spawn_blocking(move || exec_db.exec(cmd)).await {
+            Ok(resp) => resp,
+            Err(e) => std::panic::resume_unwind(e.into_panic()),
+        };
         conn.respond(resp).await;
     }
 
     debug!("closing connection");
 }
 
+/// Periodically deletes Segments that are past their Log's retention and compacts Logs, along with
+/// deleting Logs that are past their grace period and compressing newly sealed Segments.
+async fn enforce_retention(db: Arc<DB>, every: Duration) {
+    let mut interval = tokio::time::interval(every);
+    loop {
+        interval.tick().await;
+        // Maintenance reads and writes whole Segments, so it stays off the async workers.
+        let db = db.clone();
+        let res = tokio::task::spawn_blocking(move || {
+            db.enforce_retention();
+            db.purge_deleted_logs();
+            db.compress_segments();
+        })
+        .await;
+        if let Err(e) = res {
+            std::panic::resume_unwind(e.into_panic());
+        }
+    }
+}
+
+/// Restores the backup in `backup` to `db_path`, unless there already is a DB there. The server
+/// can then be restarted with restore_from still set without its data being touched.
+fn restore_if_empty(backup: &Path, db_path: &Path) -> std::io::Result<Option<BackupInfo>> {
+    if db_path.exists() && std::fs::read_dir(db_path)?.next().is_some() {
+        return Ok(None);
+    }
+    info!("restoring db from backup {:?}", backup);
+    DB::restore(backup, db_path).map(Some)
+}
+
 pub async fn run_server(cfg: RemitsConfig) {
     info!("starting server");
     let mut listener = TcpListener::bind(cfg.addr()).await.unwrap();
     info!("listening on {}", cfg.addr());
 
-    let db = Arc::new(DB::new(cfg.db_path.unwrap()));
+    let db_path = cfg.db_path.clone().unwrap();
+    if let Some(backup) = &cfg.restore_from {
+        match restore_if_empty(Path::new(backup), Path::new(&db_path)) {
+            Ok(Some(info)) => info!("restored {} logs from backup", info.logs.len()),
+            Ok(None) => info!(
+                "{:?} already holds a db, not restoring from backup",
+                db_path
+            ),
+            Err(e) => {
+                error!("could not restore db from backup {:?}: {}", backup, e);
+                return;
+            }
+        }
+    }
+
+    let db = Arc::new(DB::open(db_path, cfg.log_defaults()));
+
+    match cfg.retention_interval {
+        Some(secs) if secs > 0 => {
+            tokio::spawn(enforce_retention(db.clone(), Duration::from_secs(secs)));
+        }
+        _ => warn!(
+            "retention_interval is unset or 0, so retention, compaction, purging deleted logs and \
+             compression are disabled"
+        ),
+    }
 
     loop {
         match listener.accept().await {
@@ -40,3 +103,46 @@ pub async fn run_server(cfg: RemitsConfig) {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::{Command, LogAdd, LogOptions, LogShow};
+    use crate::protocol::Response;
+    use crate::test_util::temp_db_path;
+
+    fn add_log(db: &DB, name: &str) {
+        db.exec(Command::LogAdd(LogAdd {
+            log_name: name.into(),
+            options: LogOptions::default(),
+        }));
+    }
+
+    fn has_log(db: &DB, name: &str) -> bool {
+        let resp = db.exec(Command::LogShow(LogShow {
+            log_name: name.into(),
+        }));
+        matches!(resp, Response::Data(_))
+    }
+
+    #[test]
+    fn test_restart_with_restore_from_keeps_db() {
+        let backup = std::path::PathBuf::from(temp_db_path()).join("backup");
+        let db = DB::new(temp_db_path());
+        add_log(&db, "backed_up");
+        db.backup(backup.clone()).unwrap();
+
+        let db_path = std::path::PathBuf::from(temp_db_path()).join("db");
+        let info = restore_if_empty(&backup, &db_path).unwrap().unwrap();
+        assert_eq!(info.logs.len(), 1);
+        let db = DB::new(db_path.to_string_lossy().into_owned());
+        add_log(&db, "added_since");
+        drop(db);
+
+        // Restarting with the option still set leaves the DB as it is.
+        assert_eq!(restore_if_empty(&backup, &db_path).unwrap(), None);
+        let db = DB::new(db_path.to_string_lossy().into_owned());
+        assert!(has_log(&db, "backed_up"));
+        assert!(has_log(&db, "added_since"));
+    }
+}
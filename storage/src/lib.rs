@@ -1,45 +1,116 @@
-use std::collections::{hash_map::Entry, HashMap};
+use std::collections::{hash_map::Entry, BTreeMap, HashMap};
 use std::time::{SystemTime, UNIX_EPOCH};
 
-/// A handle to the underlying storage layer.
-// TODO: Right now this is in-memory only until we hammer out the API/semantics.
-// There's no point writing super involved disk handling code before we know what we need.
-// As such, there shouldn't be any public properties on Storage, only methods.
-pub struct Storage {
-    logs: HashMap<String, Vec<RawMessage>>,
+/// The operations every storage layer provides for Logs. Messages in a Log are addressed by
+/// offset, starting from 0 and increasing by 1 for each message appended.
+///
+/// Implementations are free to offer more than this, but anything built against the trait works
+/// with any of them.
+pub trait StorageEngine {
+    /// Creates an empty Log.
+    fn create_log(&mut self, name: &str) -> Result<(), Error>;
+
+    /// Deletes a Log along with all of its messages.
+    fn delete_log(&mut self, name: &str) -> Result<(), Error>;
+
+    /// Appends a message to a Log, returning its offset.
+    fn append(&mut self, log: &str, body: Vec<u8>, meta: Metadata) -> Result<u64, Error>;
+
+    /// Reads up to `count` messages from a Log, starting at `offset`. Fewer are returned if the Log
+    /// ends first. Offsets can have gaps, such as where messages were removed by compaction, so
+    /// each message says which offset it is at.
+    fn read(&self, log: &str, offset: u64, count: usize) -> Result<Vec<RawMessage>, Error>;
+
+    /// Returns the offset of the first message ingested at or after `time`, in milliseconds since
+    /// the Unix Epoch. If there is none, this is the offset the next message will have.
+    fn offset_at_time(&self, log: &str, time: u64) -> Result<u64, Error>;
+
+    fn stats(&self, log: &str) -> Result<LogStats, Error>;
 }
 
-impl Storage {
-    /// Open Storage for usage. If no Storage exists at that location, it will be created.
-    pub fn open(_path: &str) -> Result<Storage, Error> {
-        Ok(Storage {
-            logs: HashMap::new(),
-        })
+/// A StorageEngine that keeps everything in memory. Nothing survives the process exiting, so it is
+/// mostly useful for tests.
+// There shouldn't be any public properties on MemoryStorage, only methods.
+#[derive(Default)]
+pub struct MemoryStorage {
+    logs: HashMap<String, MemoryLog>,
+}
+
+#[derive(Default)]
+struct MemoryLog {
+    /// Messages in the order they were appended, so each one's offset is its index.
+    msgs: Vec<RawMessage>,
+}
+
+impl MemoryStorage {
+    /// Opens an empty MemoryStorage. The path is ignored, since nothing is kept anywhere but in
+    /// memory.
+    pub fn open(_path: &str) -> Result<MemoryStorage, Error> {
+        Ok(MemoryStorage::default())
     }
 
-    pub fn create_log(&mut self, name: String) -> Result<(), Error> {
-        match self.logs.entry(name) {
+    fn log(&self, name: &str) -> Result<&MemoryLog, Error> {
+        self.logs.get(name).ok_or(Error::LogDoesNotExist)
+    }
+}
+
+impl StorageEngine for MemoryStorage {
+    fn create_log(&mut self, name: &str) -> Result<(), Error> {
+        match self.logs.entry(name.to_string()) {
             Entry::Occupied(_) => Err(Error::LogAlreadyExists),
             Entry::Vacant(e) => {
-                e.insert(vec![]);
+                e.insert(MemoryLog::default());
                 Ok(())
             }
         }
     }
 
-    pub fn add_message_to_log(&mut self, log_name: String, body: Vec<u8>) -> Result<(), Error> {
-        match self.logs.entry(log_name) {
-            Entry::Occupied(mut o) => {
-                // TODO: We can't make a syscall on every message like this. It'll be dog slow.
-                // Honestly it shouldn't even be in the storage layer, it's just convenient right
-                // now.
-                let ingest_time = ingest_time();
-                o.get_mut().push(RawMessage { ingest_time, body });
-                Ok(())
-            }
-            Entry::Vacant(_) => Err(Error::LogDoesNotExist),
+    fn delete_log(&mut self, name: &str) -> Result<(), Error> {
+        match self.logs.remove(name) {
+            Some(_) => Ok(()),
+            None => Err(Error::LogDoesNotExist),
         }
     }
+
+    fn append(&mut self, log: &str, body: Vec<u8>, meta: Metadata) -> Result<u64, Error> {
+        let log = self.logs.get_mut(log).ok_or(Error::LogDoesNotExist)?;
+
+        // Ingest times never decrease within a Log, so that it can be searched by time.
+        let ingest_time = log
+            .msgs
+            .last()
+            .map_or(0, |m| m.ingest_time)
+            .max(ingest_time());
+        let offset = log.msgs.len() as u64;
+        log.msgs.push(RawMessage {
+            offset,
+            ingest_time,
+            meta,
+            body,
+        });
+        Ok(offset)
+    }
+
+    fn read(&self, log: &str, offset: u64, count: usize) -> Result<Vec<RawMessage>, Error> {
+        let log = self.log(log)?;
+        let start = (offset as usize).min(log.msgs.len());
+        let end = start.saturating_add(count).min(log.msgs.len());
+        Ok(log.msgs[start..end].to_vec())
+    }
+
+    fn offset_at_time(&self, log: &str, time: u64) -> Result<u64, Error> {
+        let log = self.log(log)?;
+        Ok(log.msgs.partition_point(|m| m.ingest_time < time) as u64)
+    }
+
+    fn stats(&self, log: &str) -> Result<LogStats, Error> {
+        let log = self.log(log)?;
+        Ok(LogStats {
+            earliest_offset: 0,
+            next_offset: log.msgs.len() as u64,
+            size_bytes: log.msgs.iter().map(|m| m.body.len() as u64).sum(),
+        })
+    }
 }
 
 fn ingest_time() -> u64 {
@@ -52,17 +123,117 @@ fn ingest_time() -> u64 {
 }
 
 // Persisted variant of a Message.
-// Right now this is only for the in-memory version.
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct RawMessage {
+    pub offset: u64,
+
     // Ingest time in milliseconds
     pub ingest_time: u64,
 
-    // Raw JSON bytes
+    pub meta: Metadata,
+
+    // Raw message bytes
     pub body: Vec<u8>,
 }
 
-#[derive(Clone, Debug)]
+/// What a message carries besides its body, set by whoever adds it. All of it is optional.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Metadata {
+    /// Identifies what the message is about, such as the entity whose state it holds.
+    pub key: Option<String>,
+    pub headers: BTreeMap<String, String>,
+    /// The producer that added the message, so that a retried add can be recognised.
+    pub producer: Option<ProducerSequence>,
+}
+
+impl Metadata {
+    pub fn is_empty(&self) -> bool {
+        self.key.is_none() && self.headers.is_empty() && self.producer.is_none()
+    }
+}
+
+/// Identifies a message by who produced it. Each producer numbers its messages with sequence
+/// numbers that increase, so that adding the same message twice can be detected.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ProducerSequence {
+    pub id: String,
+    pub sequence: u64,
+}
+
+/// A summary of what a Log holds.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct LogStats {
+    /// Offset of the oldest message that is still stored.
+    pub earliest_offset: u64,
+    /// Offset the next message appended will have.
+    pub next_offset: u64,
+    /// Number of bytes the Log takes up in storage.
+    pub size_bytes: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub enum Error {
     LogAlreadyExists,
     LogDoesNotExist,
+    /// The message was deleted by retention.
+    OffsetRetired,
+    /// The Log refused to store the message.
+    InvalidMessage,
+    /// The storage layer couldn't be read from or written to.
+    Io(String),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_memory_storage_logs() {
+        let mut storage = MemoryStorage::open("").unwrap();
+        storage.create_log("test").unwrap();
+        assert_eq!(storage.create_log("test"), Err(Error::LogAlreadyExists));
+
+        storage.delete_log("test").unwrap();
+        assert_eq!(storage.delete_log("test"), Err(Error::LogDoesNotExist));
+        assert_eq!(
+            storage.append("test", vec![], Metadata::default()),
+            Err(Error::LogDoesNotExist)
+        );
+    }
+
+    #[test]
+    fn test_memory_storage_messages() {
+        let mut storage = MemoryStorage::open("").unwrap();
+        storage.create_log("test").unwrap();
+        let meta = Metadata {
+            key: Some("k".into()),
+            ..Metadata::default()
+        };
+        assert_eq!(
+            storage
+                .append("test", vec![0x01], Metadata::default())
+                .unwrap(),
+            0
+        );
+        assert_eq!(
+            storage
+                .append("test", vec![0x02, 0x03], meta.clone())
+                .unwrap(),
+            1
+        );
+
+        let msgs = storage.read("test", 1, 10).unwrap();
+        assert_eq!(msgs.len(), 1);
+        assert_eq!((msgs[0].offset, &msgs[0].meta), (1, &meta));
+        assert_eq!(msgs[0].body, vec![0x02, 0x03]);
+        assert!(storage.read("test", 5, 10).unwrap().is_empty());
+
+        let first = storage.read("test", 0, 1).unwrap()[0].ingest_time;
+        assert_eq!(storage.offset_at_time("test", first).unwrap(), 0);
+        assert_eq!(storage.offset_at_time("test", u64::MAX).unwrap(), 2);
+
+        let stats = storage.stats("test").unwrap();
+        assert_eq!(stats.next_offset, 2);
+        assert_eq!(stats.size_bytes, 3);
+    }
 }
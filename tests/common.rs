@@ -10,6 +10,20 @@ pub async fn start_server() {
         port: Some("4243".into()),
         log_level: Some("trace".into()),
         db_path: Some(file_path.into()),
+        max_segment_size: None,
+        max_segment_age: None,
+        compression: None,
+        durability: None,
+        retention_max_age: None,
+        retention_max_size: None,
+        retention_max_messages: None,
+        delete_grace_period: None,
+        cleanup_policy: None,
+        tombstone_grace_period: None,
+        max_message_size: None,
+        ingestion: None,
+        retention_interval: None,
+        restore_from: None,
     };
 
     remitslib::server::run_server(cfg).await;
@@ -1,8 +1,7 @@
 use bytes::Bytes;
 use futures::SinkExt;
 use serde::{Deserialize, Serialize};
-use std::panic;
-use std::{time};
+use std::time;
 use tokio::net::TcpStream;
 use tokio::stream::StreamExt;
 use tokio_util::codec::{Framed, LengthDelimitedCodec};
@@ -15,7 +14,7 @@ static OK_RESP: &[u8] = &[0x62, 0x6F, 0x6B];
 
 #[tokio::test]
 async fn integration_tests() {
-    let _ = tokio::spawn(common::start_server());
+    tokio::spawn(common::start_server());
 
     let five = time::Duration::from_secs(5);
     std::thread::sleep(five);